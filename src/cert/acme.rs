@@ -0,0 +1,528 @@
+// src/cert/acme.rs
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
+    time::Duration,
+};
+
+use anyhow::{Result, anyhow};
+use base64::{Engine, engine::general_purpose::URL_SAFE_NO_PAD};
+use rcgen::{CertificateParams, KeyPair};
+use reqwest::{Client, Response, header::HeaderMap};
+use ring::{
+    digest::{SHA256, digest},
+    rand::SystemRandom,
+    signature::{ECDSA_P256_SHA256_FIXED_SIGNING, EcdsaKeyPair, KeyPair as _},
+};
+use serde::Deserialize;
+use serde_json::{Value, json};
+
+use super::certbot::write_file_atomic;
+use super::dns::DnsProvider;
+
+pub const LETSENCRYPT_DIRECTORY_URL: &str = "https://acme-v02.api.letsencrypt.org/directory";
+pub const LETSENCRYPT_STAGING_DIRECTORY_URL: &str =
+    "https://acme-staging-v02.api.letsencrypt.org/directory";
+
+/// How often and how long to poll pending authorizations and orders
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+const POLL_ATTEMPTS: u32 = 30;
+
+/// HTTP-01 key authorizations waiting to be fetched by the ACME server,
+/// served from memory by the HTTP proxy
+#[derive(Clone, Default)]
+pub struct AcmeChallenges {
+    inner: Arc<RwLock<HashMap<String, String>>>,
+}
+
+impl AcmeChallenges {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn insert(&self, token: &str, key_authorization: &str) {
+        if let Ok(mut challenges) = self.inner.write() {
+            challenges.insert(token.to_string(), key_authorization.to_string());
+        }
+    }
+
+    pub fn remove(&self, token: &str) {
+        if let Ok(mut challenges) = self.inner.write() {
+            challenges.remove(token);
+        }
+    }
+
+    /// Get the key authorization to answer for a challenge token
+    pub fn get(&self, token: &str) -> Option<String> {
+        self.inner.read().ok()?.get(token).cloned()
+    }
+}
+
+/// ACME server settings, read from the environment
+#[derive(Debug, Clone)]
+pub struct AcmeConfig {
+    pub directory_url: String,
+    pub staging_directory_url: String,
+    /// Extra root certificate trusted for the ACME server, e.g. a local Pebble CA
+    pub ca_cert: Option<PathBuf>,
+    /// Contact address used for certificates requested without one, e.g. renewals
+    pub email: Option<String>,
+    /// Challenge used for requests that do not ask for one
+    pub default_challenge: ChallengeType,
+    /// Time given to DNS-01 records to reach every authoritative nameserver
+    pub dns_propagation_delay: Duration,
+}
+
+impl AcmeConfig {
+    pub fn from_env() -> Self {
+        Self {
+            directory_url: std::env::var("ACME_DIRECTORY_URL")
+                .unwrap_or_else(|_| LETSENCRYPT_DIRECTORY_URL.to_string()),
+            staging_directory_url: std::env::var("ACME_STAGING_DIRECTORY_URL")
+                .unwrap_or_else(|_| LETSENCRYPT_STAGING_DIRECTORY_URL.to_string()),
+            ca_cert: std::env::var("ACME_CA_CERT").ok().map(PathBuf::from),
+            email: std::env::var("ACME_EMAIL").ok().filter(|e| !e.is_empty()),
+            default_challenge: match std::env::var("ACME_CHALLENGE").as_deref() {
+                Ok("dns-01") => ChallengeType::Dns01,
+                _ => ChallengeType::Http01,
+            },
+            dns_propagation_delay: Duration::from_secs(
+                std::env::var("DNS_PROPAGATION_DELAY")
+                    .ok()
+                    .and_then(|v| v.parse::<u64>().ok())
+                    .unwrap_or(10),
+            ),
+        }
+    }
+
+    /// Build the HTTP client used to talk to the ACME server
+    pub fn http_client(&self) -> Result<Client> {
+        let mut builder = Client::builder().timeout(Duration::from_secs(30));
+
+        if let Some(path) = &self.ca_cert {
+            let pem = fs::read(path)?;
+            builder = builder.add_root_certificate(reqwest::Certificate::from_pem(&pem)?);
+        }
+
+        Ok(builder.build()?)
+    }
+}
+
+/// Challenge types the client can answer
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+pub enum ChallengeType {
+    #[serde(rename = "http-01")]
+    Http01,
+    #[serde(rename = "dns-01")]
+    Dns01,
+}
+
+impl ChallengeType {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ChallengeType::Http01 => "http-01",
+            ChallengeType::Dns01 => "dns-01",
+        }
+    }
+}
+
+/// How the authorizations of an order are answered
+pub struct ChallengeSolvers<'a> {
+    /// Challenge used for non-wildcard names, wildcards always use DNS-01
+    pub preferred: ChallengeType,
+    pub http: &'a AcmeChallenges,
+    pub dns: Option<&'a dyn DnsProvider>,
+    pub dns_propagation_delay: Duration,
+}
+
+/// A certificate chain and its private key, both PEM encoded
+pub struct IssuedCertificate {
+    pub cert_pem: String,
+    pub key_pem: String,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Directory {
+    new_nonce: String,
+    new_account: String,
+    new_order: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct Order {
+    status: String,
+    authorizations: Vec<String>,
+    finalize: String,
+    certificate: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Authorization {
+    status: String,
+    identifier: Identifier,
+    #[serde(default)]
+    wildcard: bool,
+    #[serde(default)]
+    challenges: Vec<Challenge>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Identifier {
+    value: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct Challenge {
+    #[serde(rename = "type")]
+    kind: String,
+    url: String,
+    token: String,
+}
+
+/// Minimal ACME v2 (RFC 8555) client using HTTP-01 and DNS-01 challenges
+pub struct AcmeClient {
+    http: Client,
+    directory: Directory,
+    key: EcdsaKeyPair,
+    rng: SystemRandom,
+    account_url: Option<String>,
+    nonce: Option<String>,
+}
+
+impl AcmeClient {
+    /// Connect to an ACME directory, loading or creating the account key at `account_key_path`
+    pub async fn new(http: Client, directory_url: &str, account_key_path: &Path) -> Result<Self> {
+        let directory: Directory = http
+            .get(directory_url)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+
+        let rng = SystemRandom::new();
+        let key = load_or_create_account_key(account_key_path, &rng)?;
+
+        Ok(Self {
+            http,
+            directory,
+            key,
+            rng,
+            account_url: None,
+            nonce: None,
+        })
+    }
+
+    /// Register the account, or look up the existing one for this key
+    pub async fn register_account(&mut self, email: &str) -> Result<()> {
+        // The contact address is optional for ACME accounts
+        let payload = if email.is_empty() {
+            json!({ "termsOfServiceAgreed": true })
+        } else {
+            json!({
+                "termsOfServiceAgreed": true,
+                "contact": [format!("mailto:{}", email)],
+            })
+        };
+
+        let url = self.directory.new_account.clone();
+        let response = self.post(&url, Some(&payload)).await?;
+        let account_url = location(response.headers())
+            .ok_or_else(|| anyhow!("ACME server did not return an account URL"))?;
+
+        println!("Using ACME account: {}", account_url);
+        self.account_url = Some(account_url);
+        Ok(())
+    }
+
+    /// Run a full order for `domains`, answering challenges through `solvers`
+    pub async fn order_certificate(
+        &mut self,
+        domains: &[String],
+        solvers: &ChallengeSolvers<'_>,
+    ) -> Result<IssuedCertificate> {
+        let identifiers: Vec<Value> = domains
+            .iter()
+            .map(|d| json!({ "type": "dns", "value": d }))
+            .collect();
+
+        let url = self.directory.new_order.clone();
+        let response = self
+            .post(&url, Some(&json!({ "identifiers": identifiers })))
+            .await?;
+        let order_url = location(response.headers())
+            .ok_or_else(|| anyhow!("ACME server did not return an order URL"))?;
+        let order: Order = response.json().await?;
+
+        for authz_url in &order.authorizations {
+            self.complete_authorization(authz_url, solvers).await?;
+        }
+
+        let order = self.poll_order(&order_url, &["ready"]).await?;
+
+        // Generate the certificate key and ask the CA to sign it
+        let cert_key = KeyPair::generate()?;
+        let csr = CertificateParams::new(domains.to_vec())?.serialize_request(&cert_key)?;
+
+        let payload = json!({ "csr": URL_SAFE_NO_PAD.encode(csr.der()) });
+        self.post(&order.finalize, Some(&payload)).await?;
+        let order = self.poll_order(&order_url, &["valid"]).await?;
+
+        let cert_url = order
+            .certificate
+            .ok_or_else(|| anyhow!("ACME order is valid but has no certificate URL"))?;
+        let cert_pem = self.post(&cert_url, None).await?.text().await?;
+
+        Ok(IssuedCertificate {
+            cert_pem,
+            key_pem: cert_key.serialize_pem(),
+        })
+    }
+
+    async fn complete_authorization(
+        &mut self,
+        authz_url: &str,
+        solvers: &ChallengeSolvers<'_>,
+    ) -> Result<()> {
+        let authz: Authorization = self.post(authz_url, None).await?.json().await?;
+        if authz.status == "valid" {
+            return Ok(());
+        }
+
+        // Wildcard names can only be validated through DNS
+        let kind = if authz.wildcard {
+            ChallengeType::Dns01
+        } else {
+            solvers.preferred
+        };
+
+        let challenge = authz
+            .challenges
+            .iter()
+            .find(|c| c.kind == kind.as_str())
+            .ok_or_else(|| {
+                anyhow!(
+                    "No {} challenge offered for {}",
+                    kind.as_str(),
+                    authz.identifier.value
+                )
+            })?;
+
+        let key_authorization = format!("{}.{}", challenge.token, self.thumbprint());
+        println!(
+            "Answering {} challenge for {} with token {}",
+            kind.as_str(),
+            authz.identifier.value,
+            challenge.token
+        );
+
+        match kind {
+            ChallengeType::Http01 => {
+                solvers.http.insert(&challenge.token, &key_authorization);
+                let result = self.validate_challenge(authz_url, &challenge.url).await;
+                solvers.http.remove(&challenge.token);
+                result
+            }
+            ChallengeType::Dns01 => {
+                let dns = solvers.dns.ok_or_else(|| {
+                    anyhow!(
+                        "DNS-01 is required for {} but no DNS provider is configured",
+                        authz.identifier.value
+                    )
+                })?;
+
+                // TXT value is the base64url SHA-256 digest of the key authorization
+                let fqdn = format!("_acme-challenge.{}", authz.identifier.value);
+                let value =
+                    URL_SAFE_NO_PAD.encode(digest(&SHA256, key_authorization.as_bytes()).as_ref());
+
+                dns.present(&fqdn, &value).await?;
+                tokio::time::sleep(solvers.dns_propagation_delay).await;
+
+                let result = self.validate_challenge(authz_url, &challenge.url).await;
+                if let Err(e) = dns.cleanup(&fqdn, &value).await {
+                    println!("Failed to remove DNS record {}: {}", fqdn, e);
+                }
+                result
+            }
+        }
+    }
+
+    async fn validate_challenge(&mut self, authz_url: &str, challenge_url: &str) -> Result<()> {
+        // Tell the server the challenge is ready to be checked
+        self.post(challenge_url, Some(&json!({}))).await?;
+
+        for _ in 0..POLL_ATTEMPTS {
+            tokio::time::sleep(POLL_INTERVAL).await;
+
+            let authz: Value = self.post(authz_url, None).await?.json().await?;
+            match authz["status"].as_str() {
+                Some("valid") => return Ok(()),
+                Some("pending") | Some("processing") => continue,
+                status => {
+                    return Err(anyhow!(
+                        "Authorization failed with status {:?}: {}",
+                        status,
+                        authz["challenges"]
+                    ));
+                }
+            }
+        }
+
+        Err(anyhow!("Timed out waiting for authorization {}", authz_url))
+    }
+
+    async fn poll_order(&mut self, order_url: &str, wanted: &[&str]) -> Result<Order> {
+        for _ in 0..POLL_ATTEMPTS {
+            let order: Order = self.post(order_url, None).await?.json().await?;
+
+            if wanted.contains(&order.status.as_str()) {
+                return Ok(order);
+            }
+            if order.status == "invalid" {
+                return Err(anyhow!("ACME order {} became invalid", order_url));
+            }
+
+            tokio::time::sleep(POLL_INTERVAL).await;
+        }
+
+        Err(anyhow!("Timed out waiting for order {}", order_url))
+    }
+
+    /// Send a signed request, `None` payload makes it a POST-as-GET
+    async fn post(&mut self, url: &str, payload: Option<&Value>) -> Result<Response> {
+        // A stale nonce is rejected with badNonce, retry once with the fresh one
+        for attempt in 0..2 {
+            let body = self.sign(url, payload).await?;
+            let response = self
+                .http
+                .post(url)
+                .header("content-type", "application/jose+json")
+                .body(body)
+                .send()
+                .await?;
+
+            self.nonce = response
+                .headers()
+                .get("replay-nonce")
+                .and_then(|v| v.to_str().ok())
+                .map(|v| v.to_string());
+
+            if response.status().is_success() {
+                return Ok(response);
+            }
+
+            let status = response.status();
+            let problem: Value = response.json().await.unwrap_or(Value::Null);
+            if attempt == 0 && problem["type"] == "urn:ietf:params:acme:error:badNonce" {
+                continue;
+            }
+
+            return Err(anyhow!(
+                "ACME request to {} failed ({}): {}",
+                url,
+                status,
+                problem
+            ));
+        }
+
+        Err(anyhow!("ACME request to {} failed: bad nonce", url))
+    }
+
+    async fn sign(&mut self, url: &str, payload: Option<&Value>) -> Result<String> {
+        let nonce = match self.nonce.take() {
+            Some(nonce) => nonce,
+            None => self.new_nonce().await?,
+        };
+
+        let mut protected = json!({
+            "alg": "ES256",
+            "nonce": nonce,
+            "url": url,
+        });
+        match &self.account_url {
+            Some(kid) => protected["kid"] = json!(kid),
+            None => protected["jwk"] = self.jwk(),
+        }
+
+        let protected = URL_SAFE_NO_PAD.encode(serde_json::to_vec(&protected)?);
+        let payload = match payload {
+            Some(payload) => URL_SAFE_NO_PAD.encode(serde_json::to_vec(payload)?),
+            None => String::new(),
+        };
+
+        let signing_input = format!("{}.{}", protected, payload);
+        let signature = self
+            .key
+            .sign(&self.rng, signing_input.as_bytes())
+            .map_err(|_| anyhow!("Failed to sign ACME request"))?;
+
+        Ok(serde_json::to_string(&json!({
+            "protected": protected,
+            "payload": payload,
+            "signature": URL_SAFE_NO_PAD.encode(signature.as_ref()),
+        }))?)
+    }
+
+    async fn new_nonce(&self) -> Result<String> {
+        let response = self.http.head(&self.directory.new_nonce).send().await?;
+        response
+            .headers()
+            .get("replay-nonce")
+            .and_then(|v| v.to_str().ok())
+            .map(|v| v.to_string())
+            .ok_or_else(|| anyhow!("ACME server did not return a nonce"))
+    }
+
+    fn jwk(&self) -> Value {
+        // Uncompressed P-256 point: 0x04 || x || y
+        let point = self.key.public_key().as_ref();
+        json!({
+            "crv": "P-256",
+            "kty": "EC",
+            "x": URL_SAFE_NO_PAD.encode(&point[1..33]),
+            "y": URL_SAFE_NO_PAD.encode(&point[33..65]),
+        })
+    }
+
+    /// RFC 7638 thumbprint of the account key, members in lexicographic order
+    fn thumbprint(&self) -> String {
+        let jwk = self.jwk();
+        let canonical = format!(
+            r#"{{"crv":"P-256","kty":"EC","x":"{}","y":"{}"}}"#,
+            jwk["x"].as_str().unwrap_or_default(),
+            jwk["y"].as_str().unwrap_or_default()
+        );
+        URL_SAFE_NO_PAD.encode(digest(&SHA256, canonical.as_bytes()))
+    }
+}
+
+fn location(headers: &HeaderMap) -> Option<String> {
+    headers
+        .get("location")
+        .and_then(|v| v.to_str().ok())
+        .map(|v| v.to_string())
+}
+
+fn load_or_create_account_key(path: &Path, rng: &SystemRandom) -> Result<EcdsaKeyPair> {
+    let pkcs8 = match fs::read(path) {
+        Ok(pkcs8) => pkcs8,
+        Err(_) => {
+            println!("Creating new ACME account key: {}", path.display());
+            let pkcs8 = EcdsaKeyPair::generate_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, rng)
+                .map_err(|_| anyhow!("Failed to generate ACME account key"))?;
+
+            if let Some(parent) = path.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            write_file_atomic(path, pkcs8.as_ref(), 0o600)?;
+            pkcs8.as_ref().to_vec()
+        }
+    };
+
+    EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, &pkcs8, rng)
+        .map_err(|_| anyhow!("Invalid ACME account key: {}", path.display()))
+}
@@ -1,4 +1,9 @@
-use std::path::Path;
+use std::{
+    fs::{self, OpenOptions},
+    io::Write,
+    os::unix::fs::OpenOptionsExt,
+    path::Path,
+};
 
 /// Struct to represent domain certificate information
 #[derive(Debug, Clone)]
@@ -8,27 +13,58 @@ pub struct DomainCert {
     pub key_path: String,
 }
 
+/// Certbot-compatible configuration directory used by the certificate issuer
+pub const CERTBOT_DIR: &str = "certbot/letsencrypt";
+
 /// Constant for the certbot directory
-const CERTBOT_LIVE_DIR: &str = "certbot/letsencrypt/live";
+pub const CERTBOT_LIVE_DIR: &str = "certbot/letsencrypt/live";
+
+/// Directory the certificate issuer copies issued certificates to
+pub const ISSUER_OUTPUT_DIR: &str = "certs";
+
+/// Write a file through a temporary file renamed over it, so readers see the old
+/// or the new content but never a partial file. Keys are written with mode 0600
+pub fn write_file_atomic(path: &Path, data: &[u8], mode: u32) -> std::io::Result<()> {
+    let mut temp_path = path.as_os_str().to_owned();
+    temp_path.push(".tmp");
+
+    // A leftover temporary file would keep its old mode
+    let _ = fs::remove_file(&temp_path);
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(mode)
+        .open(&temp_path)?;
+    file.write_all(data)?;
+    file.sync_all()?;
+    fs::rename(&temp_path, path)
+}
 
-/// Function to check for certbot certificates for given domains
-pub fn find_certbot_certs(domains: &[String]) -> Vec<DomainCert> {
+/// Function to list every `<domain>/fullchain.pem` + `privkey.pem` pair in a directory
+pub fn scan_cert_dir(dir: &Path) -> Vec<DomainCert> {
     let mut certs = Vec::new();
 
-    for domain in domains {
-        let domain_dir = Path::new(CERTBOT_LIVE_DIR).join(domain);
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(_) => return certs,
+    };
+
+    for entry in entries.flatten() {
+        let domain_dir = entry.path();
+        if !domain_dir.is_dir() {
+            continue;
+        }
+
+        let domain = entry.file_name().to_string_lossy().to_string();
         let fullchain_path = domain_dir.join("fullchain.pem");
         let privkey_path = domain_dir.join("privkey.pem");
 
         if fullchain_path.exists() && privkey_path.exists() {
-            println!("Found certificates for domain: {}", domain);
             certs.push(DomainCert {
-                domain: domain.clone(),
+                domain,
                 cert_path: fullchain_path.to_string_lossy().to_string(),
                 key_path: privkey_path.to_string_lossy().to_string(),
             });
-        } else {
-            println!("No certificates found for domain: {}", domain);
         }
     }
 
@@ -0,0 +1,415 @@
+// src/cert/dns.rs
+use std::{
+    net::{IpAddr, SocketAddr, ToSocketAddrs},
+    path::PathBuf,
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{Result, anyhow};
+use async_trait::async_trait;
+use base64::{Engine, engine::general_purpose::STANDARD};
+use ring::{
+    hmac,
+    rand::{SecureRandom, SystemRandom},
+};
+use tokio::{net::UdpSocket, process::Command};
+
+/// How long to wait for a DNS server to answer
+const DNS_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// TTL of the challenge TXT records, they only live for the duration of an order
+const CHALLENGE_TTL: u32 = 60;
+
+// DNS wire format constants (RFC 1035, RFC 2136, RFC 8945)
+const OPCODE_UPDATE: u16 = 5 << 11;
+const TYPE_SOA: u16 = 6;
+const TYPE_TXT: u16 = 16;
+const TYPE_TSIG: u16 = 250;
+const CLASS_IN: u16 = 1;
+const CLASS_NONE: u16 = 254;
+const CLASS_ANY: u16 = 255;
+const TSIG_FUDGE: u16 = 300;
+
+/// Publishes the TXT records answering DNS-01 challenges
+#[async_trait]
+pub trait DnsProvider: Send + Sync {
+    /// Add a TXT record with `value` at `fqdn`
+    async fn present(&self, fqdn: &str, value: &str) -> Result<()>;
+
+    /// Remove the TXT record added by `present`
+    async fn cleanup(&self, fqdn: &str, value: &str) -> Result<()>;
+}
+
+/// Build the DNS provider selected by `DNS_PROVIDER`, if any
+pub fn dns_provider_from_env() -> Result<Option<Arc<dyn DnsProvider>>> {
+    let provider = std::env::var("DNS_PROVIDER").unwrap_or_default();
+
+    match provider.to_lowercase().as_str() {
+        "" => Ok(None),
+        "rfc2136" => Ok(Some(Arc::new(Rfc2136Provider::from_env()?))),
+        "exec" => {
+            let command = std::env::var("DNS_EXEC_PATH")
+                .map_err(|_| anyhow!("DNS_EXEC_PATH is required for the exec DNS provider"))?;
+            Ok(Some(Arc::new(ExecProvider::new(PathBuf::from(command)))))
+        }
+        other => Err(anyhow!("Unknown DNS provider: {}", other)),
+    }
+}
+
+/// Shared secret used to sign dynamic updates
+pub struct TsigKey {
+    pub name: String,
+    pub algorithm: &'static str,
+    key: hmac::Key,
+}
+
+impl TsigKey {
+    /// Create a key from its name, algorithm (e.g. `hmac-sha256`) and base64 secret
+    pub fn new(name: &str, algorithm: &str, secret: &str) -> Result<Self> {
+        let (algorithm, hmac_algorithm) = match algorithm.trim_end_matches('.') {
+            "hmac-sha256" => ("hmac-sha256", hmac::HMAC_SHA256),
+            "hmac-sha384" => ("hmac-sha384", hmac::HMAC_SHA384),
+            "hmac-sha512" => ("hmac-sha512", hmac::HMAC_SHA512),
+            "hmac-sha1" => ("hmac-sha1", hmac::HMAC_SHA1_FOR_LEGACY_USE_ONLY),
+            other => return Err(anyhow!("Unsupported TSIG algorithm: {}", other)),
+        };
+        let secret = STANDARD
+            .decode(secret.trim())
+            .map_err(|e| anyhow!("Invalid TSIG secret: {}", e))?;
+
+        Ok(Self {
+            name: name.to_lowercase(),
+            algorithm,
+            key: hmac::Key::new(hmac_algorithm, &secret),
+        })
+    }
+
+    // Append a TSIG record to a complete message (RFC 8945, section 4.3)
+    fn sign(&self, message: &mut Vec<u8>) -> Result<()> {
+        let time_signed = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+
+        let mut key_name = Vec::new();
+        write_name(&mut key_name, &self.name)?;
+        let mut algorithm_name = Vec::new();
+        write_name(&mut algorithm_name, self.algorithm)?;
+
+        let mut timers = Vec::new();
+        timers.extend_from_slice(&time_signed.to_be_bytes()[2..]);
+        timers.extend_from_slice(&TSIG_FUDGE.to_be_bytes());
+
+        // The MAC covers the message followed by the TSIG variables
+        let mut signed = message.clone();
+        signed.extend_from_slice(&key_name);
+        signed.extend_from_slice(&CLASS_ANY.to_be_bytes());
+        signed.extend_from_slice(&0u32.to_be_bytes());
+        signed.extend_from_slice(&algorithm_name);
+        signed.extend_from_slice(&timers);
+        signed.extend_from_slice(&0u16.to_be_bytes()); // error
+        signed.extend_from_slice(&0u16.to_be_bytes()); // other len
+        let mac = hmac::sign(&self.key, &signed);
+
+        let mut rdata = algorithm_name;
+        rdata.extend_from_slice(&timers);
+        rdata.extend_from_slice(&(mac.as_ref().len() as u16).to_be_bytes());
+        rdata.extend_from_slice(mac.as_ref());
+        rdata.extend_from_slice(&message[0..2]); // original id
+        rdata.extend_from_slice(&0u16.to_be_bytes()); // error
+        rdata.extend_from_slice(&0u16.to_be_bytes()); // other len
+
+        message.extend_from_slice(&key_name);
+        message.extend_from_slice(&TYPE_TSIG.to_be_bytes());
+        message.extend_from_slice(&CLASS_ANY.to_be_bytes());
+        message.extend_from_slice(&0u32.to_be_bytes());
+        message.extend_from_slice(&(rdata.len() as u16).to_be_bytes());
+        message.extend_from_slice(&rdata);
+
+        // One more record in the additional section
+        let additional = u16::from_be_bytes([message[10], message[11]]) + 1;
+        message[10..12].copy_from_slice(&additional.to_be_bytes());
+
+        Ok(())
+    }
+}
+
+/// Publishes records through RFC 2136 dynamic updates, optionally signed with TSIG
+pub struct Rfc2136Provider {
+    pub nameserver: String,
+    /// Zone to update, discovered through SOA queries when not set
+    pub zone: Option<String>,
+    pub tsig: Option<TsigKey>,
+    rng: SystemRandom,
+}
+
+impl Rfc2136Provider {
+    pub fn new(nameserver: &str, zone: Option<String>, tsig: Option<TsigKey>) -> Self {
+        Self {
+            nameserver: nameserver.to_string(),
+            zone,
+            tsig,
+            rng: SystemRandom::new(),
+        }
+    }
+
+    pub fn from_env() -> Result<Self> {
+        let nameserver = std::env::var("RFC2136_NAMESERVER")
+            .map_err(|_| anyhow!("RFC2136_NAMESERVER is required for the rfc2136 DNS provider"))?;
+        let zone = std::env::var("RFC2136_ZONE").ok().filter(|z| !z.is_empty());
+
+        let tsig = match (
+            std::env::var("RFC2136_TSIG_KEY"),
+            std::env::var("RFC2136_TSIG_SECRET"),
+        ) {
+            (Ok(name), Ok(secret)) => {
+                let algorithm = std::env::var("RFC2136_TSIG_ALGORITHM")
+                    .unwrap_or_else(|_| "hmac-sha256".to_string());
+                Some(TsigKey::new(&name, &algorithm, &secret)?)
+            }
+            _ => None,
+        };
+
+        Ok(Self::new(&nameserver, zone, tsig))
+    }
+
+    fn server_addr(&self) -> Result<SocketAddr> {
+        // Port 53 unless the nameserver is given as host:port
+        if let Ok(ip) = self.nameserver.parse::<IpAddr>() {
+            return Ok(SocketAddr::new(ip, 53));
+        }
+        let addr = if self.nameserver.contains(':') {
+            self.nameserver.clone()
+        } else {
+            format!("{}:53", self.nameserver)
+        };
+
+        addr.to_socket_addrs()?
+            .next()
+            .ok_or_else(|| anyhow!("Could not resolve nameserver {}", self.nameserver))
+    }
+
+    fn message_id(&self) -> Result<[u8; 2]> {
+        let mut id = [0u8; 2];
+        self.rng
+            .fill(&mut id)
+            .map_err(|_| anyhow!("Failed to generate DNS message id"))?;
+        Ok(id)
+    }
+
+    async fn exchange(&self, message: &[u8]) -> Result<Vec<u8>> {
+        let server = self.server_addr()?;
+        let bind_addr = if server.is_ipv4() {
+            "0.0.0.0:0"
+        } else {
+            "[::]:0"
+        };
+        let socket = UdpSocket::bind(bind_addr).await?;
+        socket.connect(server).await?;
+        socket.send(message).await?;
+
+        let mut buf = vec![0u8; 4096];
+        loop {
+            let len = tokio::time::timeout(DNS_TIMEOUT, socket.recv(&mut buf))
+                .await
+                .map_err(|_| anyhow!("Timed out waiting for nameserver {}", server))??;
+
+            // Ignore stray answers to other queries
+            if len >= 12 && buf[0..2] == message[0..2] {
+                buf.truncate(len);
+                return Ok(buf);
+            }
+        }
+    }
+
+    // Find the zone containing `fqdn` by asking for the SOA of each parent name
+    async fn find_zone(&self, fqdn: &str) -> Result<String> {
+        if let Some(zone) = &self.zone {
+            return Ok(zone.clone());
+        }
+
+        let mut name = fqdn.trim_end_matches('.');
+        while let Some((_, parent)) = name.split_once('.') {
+            name = parent;
+
+            let mut query = Vec::new();
+            query.extend_from_slice(&self.message_id()?);
+            query.extend_from_slice(&0u16.to_be_bytes()); // standard query, no recursion
+            query.extend_from_slice(&[0, 1, 0, 0, 0, 0, 0, 0]);
+            write_name(&mut query, name)?;
+            query.extend_from_slice(&TYPE_SOA.to_be_bytes());
+            query.extend_from_slice(&CLASS_IN.to_be_bytes());
+
+            let response = self.exchange(&query).await?;
+            if response_code(&response) == 0 && first_answer_type(&response) == Some(TYPE_SOA) {
+                return Ok(name.to_string());
+            }
+        }
+
+        Err(anyhow!("Could not find the DNS zone of {}", fqdn))
+    }
+
+    async fn update(&self, fqdn: &str, value: &str, add: bool) -> Result<()> {
+        let zone = self.find_zone(fqdn).await?;
+
+        let mut message = Vec::new();
+        message.extend_from_slice(&self.message_id()?);
+        message.extend_from_slice(&OPCODE_UPDATE.to_be_bytes());
+        // One zone, no prerequisites, one update, no additional records
+        message.extend_from_slice(&[0, 1, 0, 0, 0, 1, 0, 0]);
+
+        write_name(&mut message, &zone)?;
+        message.extend_from_slice(&TYPE_SOA.to_be_bytes());
+        message.extend_from_slice(&CLASS_IN.to_be_bytes());
+
+        // Class NONE with TTL 0 deletes exactly this record (RFC 2136, section 2.5.4)
+        let (class, ttl) = if add {
+            (CLASS_IN, CHALLENGE_TTL)
+        } else {
+            (CLASS_NONE, 0)
+        };
+        if value.len() > 255 {
+            return Err(anyhow!("TXT value is too long"));
+        }
+        write_name(&mut message, fqdn)?;
+        message.extend_from_slice(&TYPE_TXT.to_be_bytes());
+        message.extend_from_slice(&class.to_be_bytes());
+        message.extend_from_slice(&ttl.to_be_bytes());
+        message.extend_from_slice(&(value.len() as u16 + 1).to_be_bytes());
+        message.push(value.len() as u8);
+        message.extend_from_slice(value.as_bytes());
+
+        if let Some(tsig) = &self.tsig {
+            tsig.sign(&mut message)?;
+        }
+
+        let response = self.exchange(&message).await?;
+        match response_code(&response) {
+            0 => Ok(()),
+            code => Err(anyhow!(
+                "DNS update of {} in zone {} failed: {}",
+                fqdn,
+                zone,
+                rcode_name(code)
+            )),
+        }
+    }
+}
+
+#[async_trait]
+impl DnsProvider for Rfc2136Provider {
+    async fn present(&self, fqdn: &str, value: &str) -> Result<()> {
+        self.update(fqdn, value, true).await
+    }
+
+    async fn cleanup(&self, fqdn: &str, value: &str) -> Result<()> {
+        self.update(fqdn, value, false).await
+    }
+}
+
+/// Delegates record changes to an external program, called as
+/// `<command> present|cleanup <fqdn> <value>`
+pub struct ExecProvider {
+    pub command: PathBuf,
+}
+
+impl ExecProvider {
+    pub fn new(command: PathBuf) -> Self {
+        Self { command }
+    }
+
+    async fn run(&self, action: &str, fqdn: &str, value: &str) -> Result<()> {
+        let output = Command::new(&self.command)
+            .arg(action)
+            .arg(fqdn)
+            .arg(value)
+            .output()
+            .await?;
+
+        if !output.status.success() {
+            return Err(anyhow!(
+                "{} {} failed ({}): {}",
+                self.command.display(),
+                action,
+                output.status,
+                String::from_utf8_lossy(&output.stderr).trim()
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl DnsProvider for ExecProvider {
+    async fn present(&self, fqdn: &str, value: &str) -> Result<()> {
+        self.run("present", fqdn, value).await
+    }
+
+    async fn cleanup(&self, fqdn: &str, value: &str) -> Result<()> {
+        self.run("cleanup", fqdn, value).await
+    }
+}
+
+// Uncompressed, lowercase wire format of a domain name
+fn write_name(buf: &mut Vec<u8>, name: &str) -> Result<()> {
+    let name = name.trim_end_matches('.');
+    if !name.is_empty() {
+        for label in name.split('.') {
+            if label.is_empty() || label.len() > 63 {
+                return Err(anyhow!("Invalid DNS name: {}", name));
+            }
+            buf.push(label.len() as u8);
+            buf.extend(label.bytes().map(|b| b.to_ascii_lowercase()));
+        }
+    }
+    buf.push(0);
+    Ok(())
+}
+
+// Position after a possibly compressed name starting at `pos`
+fn skip_name(message: &[u8], mut pos: usize) -> Option<usize> {
+    loop {
+        let len = *message.get(pos)? as usize;
+        match len {
+            0 => return Some(pos + 1),
+            l if l & 0xc0 == 0xc0 => return Some(pos + 2),
+            l => pos += l + 1,
+        }
+    }
+}
+
+fn response_code(message: &[u8]) -> u8 {
+    message.get(3).map(|b| b & 0x0f).unwrap_or(u8::MAX)
+}
+
+fn first_answer_type(message: &[u8]) -> Option<u16> {
+    let questions = u16::from_be_bytes([*message.get(4)?, *message.get(5)?]);
+    let answers = u16::from_be_bytes([*message.get(6)?, *message.get(7)?]);
+    if answers == 0 {
+        return None;
+    }
+
+    let mut pos = 12;
+    for _ in 0..questions {
+        pos = skip_name(message, pos)? + 4;
+    }
+    pos = skip_name(message, pos)?;
+    Some(u16::from_be_bytes([
+        *message.get(pos)?,
+        *message.get(pos + 1)?,
+    ]))
+}
+
+fn rcode_name(code: u8) -> String {
+    match code {
+        1 => "FORMERR".to_string(),
+        2 => "SERVFAIL".to_string(),
+        3 => "NXDOMAIN".to_string(),
+        4 => "NOTIMP".to_string(),
+        5 => "REFUSED".to_string(),
+        8 => "NXRRSET".to_string(),
+        9 => "NOTAUTH".to_string(),
+        10 => "NOTZONE".to_string(),
+        u8::MAX => "truncated response".to_string(),
+        code => format!("RCODE {}", code),
+    }
+}
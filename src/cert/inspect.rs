@@ -0,0 +1,187 @@
+// src/cert/inspect.rs
+use std::{
+    fs::{self, File},
+    io::BufReader,
+    net::IpAddr,
+    path::Path,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{Result, anyhow};
+use serde::Serialize;
+use time::{OffsetDateTime, format_description::well_known::Rfc3339};
+use x509_parser::{
+    certificate::X509Certificate, extensions::GeneralName, pem::Pem, public_key::PublicKey,
+    time::ASN1Time,
+};
+
+/// Details of a certificate chain on disk, as reported by the manager API
+#[derive(Debug, Clone, Serialize)]
+pub struct CertificateInfo {
+    pub subject: String,
+    pub issuer: String,
+    pub serial: String,
+    pub not_before: String,
+    pub not_after: String,
+    pub sans: Vec<String>,
+    pub key_type: String,
+    pub chain_length: usize,
+    /// Every certificate is within its validity period and signed by the next one
+    pub chain_valid: bool,
+    /// Whether the private key belongs to the leaf certificate, if a key was given
+    pub key_matches: Option<bool>,
+    /// Human readable reasons for an invalid chain or key
+    pub problems: Vec<String>,
+    #[serde(skip)]
+    pub expiry: SystemTime,
+}
+
+/// Format a point in time as an RFC 3339 UTC timestamp
+pub fn format_rfc3339(time: SystemTime) -> String {
+    OffsetDateTime::from(time)
+        .format(&Rfc3339)
+        .unwrap_or_default()
+}
+
+fn asn1_to_system_time(time: ASN1Time) -> SystemTime {
+    let timestamp = time.timestamp();
+    if timestamp >= 0 {
+        UNIX_EPOCH + Duration::from_secs(timestamp as u64)
+    } else {
+        UNIX_EPOCH - Duration::from_secs(timestamp.unsigned_abs())
+    }
+}
+
+fn key_type(cert: &X509Certificate) -> String {
+    match cert.public_key().parsed() {
+        Ok(PublicKey::RSA(rsa)) => format!("RSA-{}", rsa.key_size()),
+        Ok(PublicKey::EC(ec)) => format!("ECDSA-P{}", ec.key_size()),
+        Ok(PublicKey::DSA(_)) => "DSA".to_string(),
+        _ => match cert
+            .public_key()
+            .algorithm
+            .algorithm
+            .to_id_string()
+            .as_str()
+        {
+            "1.3.101.112" => "Ed25519".to_string(),
+            "1.3.101.113" => "Ed448".to_string(),
+            oid => oid.to_string(),
+        },
+    }
+}
+
+// Public key of a PEM private key, as DER encoded SubjectPublicKeyInfo
+fn private_key_spki(key_path: &Path) -> Result<Vec<u8>> {
+    let mut reader = BufReader::new(File::open(key_path)?);
+    let key = rustls_pemfile::private_key(&mut reader)?
+        .ok_or_else(|| anyhow!("No private key found in {}", key_path.display()))?;
+
+    let signing_key = rustls::crypto::aws_lc_rs::default_provider()
+        .key_provider
+        .load_private_key(key)?;
+    let spki = signing_key
+        .public_key()
+        .ok_or_else(|| anyhow!("Unsupported private key type"))?;
+
+    Ok(spki.as_ref().to_vec())
+}
+
+/// Parse a PEM certificate chain, check that it is consistent and, when
+/// `key_path` is given, that the private key matches the leaf certificate
+pub fn inspect_certificate(cert_path: &Path, key_path: Option<&Path>) -> Result<CertificateInfo> {
+    let data = fs::read(cert_path)?;
+    let pems = Pem::iter_from_buffer(&data).collect::<Result<Vec<_>, _>>()?;
+    let chain = pems
+        .iter()
+        .map(|pem| pem.parse_x509())
+        .collect::<Result<Vec<_>, _>>()?;
+    let leaf = chain
+        .first()
+        .ok_or_else(|| anyhow!("No certificate found in {}", cert_path.display()))?;
+
+    let mut problems = Vec::new();
+
+    for (index, cert) in chain.iter().enumerate() {
+        if !cert.validity().is_valid() {
+            problems.push(format!(
+                "Certificate {} ({}) is not valid at this time",
+                index,
+                cert.subject()
+            ));
+        }
+
+        // The last certificate is checked against itself only when self-signed,
+        // its issuer is expected to be a trusted root that is not part of the chain
+        let issuer = match chain.get(index + 1) {
+            Some(next) => Some(next),
+            None if cert.subject() == cert.issuer() => Some(cert),
+            None if index == 0 => {
+                problems.push("Chain does not include the issuer certificate".to_string());
+                None
+            }
+            None => None,
+        };
+
+        if let Some(issuer) = issuer
+            && cert.verify_signature(Some(issuer.public_key())).is_err()
+        {
+            problems.push(format!(
+                "Certificate {} ({}) is not signed by {}",
+                index,
+                cert.subject(),
+                issuer.subject()
+            ));
+        }
+    }
+    let chain_valid = problems.is_empty();
+
+    let key_matches = key_path.map(|path| match private_key_spki(path) {
+        Ok(spki) if spki == leaf.public_key().raw => true,
+        Ok(_) => {
+            problems.push("Private key does not match the certificate".to_string());
+            false
+        }
+        Err(e) => {
+            problems.push(format!("Could not read private key: {}", e));
+            false
+        }
+    });
+
+    let sans = match leaf.subject_alternative_name() {
+        Ok(Some(extension)) => extension
+            .value
+            .general_names
+            .iter()
+            .filter_map(|name| match name {
+                GeneralName::DNSName(dns) => Some(dns.to_string()),
+                GeneralName::IPAddress([a, b, c, d]) => {
+                    Some(IpAddr::from([*a, *b, *c, *d]).to_string())
+                }
+                GeneralName::IPAddress(ip) => <[u8; 16]>::try_from(*ip)
+                    .ok()
+                    .map(|ip| IpAddr::from(ip).to_string()),
+                _ => None,
+            })
+            .collect(),
+        _ => Vec::new(),
+    };
+
+    let not_before = asn1_to_system_time(leaf.validity().not_before);
+    let expiry = asn1_to_system_time(leaf.validity().not_after);
+
+    Ok(CertificateInfo {
+        subject: leaf.subject().to_string(),
+        issuer: leaf.issuer().to_string(),
+        serial: leaf.raw_serial_as_string(),
+        not_before: format_rfc3339(not_before),
+        not_after: format_rfc3339(expiry),
+        sans,
+        key_type: key_type(leaf),
+        chain_length: chain.len(),
+        chain_valid,
+        key_matches,
+        problems,
+        expiry,
+    })
+}
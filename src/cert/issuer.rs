@@ -1,12 +1,31 @@
 // src/cert/issuer.rs
 use std::fs;
 use std::net::ToSocketAddrs;
-use std::path::{Path, PathBuf};
-use std::process::Command;
+use std::path::PathBuf;
+use std::sync::Arc;
 use std::time::{Duration, SystemTime};
 
 use anyhow::{Result, anyhow};
 use serde::{Deserialize, Serialize};
+use tokio::sync::OnceCell;
+
+use super::acme::{AcmeChallenges, AcmeClient, AcmeConfig, ChallengeSolvers, ChallengeType};
+use super::certbot::{scan_cert_dir, write_file_atomic};
+use super::dns::{DnsProvider, dns_provider_from_env};
+use super::inspect::{CertificateInfo, inspect_certificate};
+
+// Directory name of a certificate, `*` is not welcome in paths
+fn lineage_name(domain: &str) -> String {
+    domain.replace('*', "_")
+}
+
+// Domain of a certificate directory, reverting `lineage_name`
+fn lineage_domain(lineage: &str) -> String {
+    match lineage.strip_prefix("_.") {
+        Some(parent) => format!("*.{}", parent),
+        None => lineage.to_string(),
+    }
+}
 
 // Certificate request data structure
 #[derive(Debug, Deserialize)]
@@ -16,6 +35,24 @@ pub struct CertificateRequest {
     // Optional fields
     pub staging: Option<bool>,
     pub force_renew: Option<bool>,
+    /// `http-01` or `dns-01`, wildcard domains always use `dns-01`
+    pub challenge: Option<ChallengeType>,
+    /// Additional names the certificate should cover, wildcards included
+    pub sans: Option<Vec<String>>,
+}
+
+impl CertificateRequest {
+    /// Every name of the certificate, starting with `domain`
+    pub fn names(&self) -> Vec<String> {
+        let mut names = vec![self.domain.to_lowercase()];
+        for name in self.sans.iter().flatten() {
+            let name = name.trim().to_lowercase();
+            if !name.is_empty() && !names.contains(&name) {
+                names.push(name);
+            }
+        }
+        names
+    }
 }
 
 // Certificate status response
@@ -27,50 +64,97 @@ pub struct CertificateStatus {
     pub key_path: Option<String>,
     pub expiry: Option<String>,
     pub error: Option<String>,
+    pub certificate: Option<CertificateInfo>,
 }
 
 // Structure to manage the certificate issuing process
 pub struct CertificateIssuer {
     pub certbot_dir: PathBuf,
     pub output_dir: PathBuf,
-    pub public_ip: String,
+    pub acme: AcmeConfig,
+    pub challenges: AcmeChallenges,
+    pub dns: Option<Arc<dyn DnsProvider>>,
+    public_ip: OnceCell<String>,
 }
 
 impl CertificateIssuer {
-    pub fn new(certbot_dir: &str, output_dir: &str) -> Result<Self> {
+    pub fn new(certbot_dir: &str, output_dir: &str, challenges: AcmeChallenges) -> Result<Self> {
         // Ensure directories exist
         fs::create_dir_all(certbot_dir)?;
         fs::create_dir_all(output_dir)?;
 
-        // Try to detect public IP
-        let public_ip = match Self::get_public_ip() {
-            Ok(ip) => ip,
-            Err(_) => String::from("0.0.0.0"), // Default fallback
+        // DNS-01 stays unavailable when the provider is misconfigured
+        let dns = match dns_provider_from_env() {
+            Ok(dns) => dns,
+            Err(e) => {
+                println!("Error configuring DNS provider: {}", e);
+                None
+            }
         };
 
         Ok(Self {
             certbot_dir: PathBuf::from(certbot_dir),
             output_dir: PathBuf::from(output_dir),
-            public_ip,
+            acme: AcmeConfig::from_env(),
+            challenges,
+            dns,
+            public_ip: OnceCell::new(),
         })
     }
 
+    // Public IP address, detected once on first use
+    async fn public_ip(&self) -> &str {
+        self.public_ip
+            .get_or_init(|| async {
+                match Self::get_public_ip().await {
+                    Ok(ip) => ip,
+                    Err(e) => {
+                        println!("Failed to detect public IP: {}", e);
+                        String::from("0.0.0.0") // Default fallback
+                    }
+                }
+            })
+            .await
+    }
+
     // Get public IP address
-    fn get_public_ip() -> Result<String> {
-        let output = Command::new("curl").arg("https://api.ipify.org").output()?;
+    async fn get_public_ip() -> Result<String> {
+        let ip = reqwest::Client::builder()
+            .timeout(Duration::from_secs(10))
+            .build()?
+            .get("https://api.ipify.org")
+            .send()
+            .await?
+            .error_for_status()?
+            .text()
+            .await?;
+
+        Ok(ip.trim().to_string())
+    }
 
-        if output.status.success() {
-            let ip = String::from_utf8(output.stdout)?;
-            Ok(ip.trim().to_string())
+    // Challenge used to validate a request
+    fn challenge_type(&self, request: &CertificateRequest) -> ChallengeType {
+        if request.domain.starts_with("*.") {
+            ChallengeType::Dns01
         } else {
-            Err(anyhow!("Failed to get public IP"))
+            request.challenge.unwrap_or(self.acme.default_challenge)
         }
     }
 
     // Process a certificate request
     pub async fn process_request(&self, request: CertificateRequest) -> CertificateStatus {
-        // 1. Validate domain points to our server
-        let validation_result = self.validate_domain(&request.domain).await;
+        let names = request.names();
+
+        // 1. Validate domains point to our server, DNS-01 works for names that do not
+        let mut validation_result = Ok(());
+        if self.challenge_type(&request) == ChallengeType::Http01 {
+            for name in names.iter().filter(|name| !name.starts_with("*.")) {
+                validation_result = self.validate_domain(name).await;
+                if validation_result.is_err() {
+                    break;
+                }
+            }
+        }
         if let Err(e) = validation_result {
             return CertificateStatus {
                 domain: request.domain,
@@ -79,15 +163,22 @@ impl CertificateIssuer {
                 key_path: None,
                 expiry: None,
                 error: Some(format!("Domain validation failed: {}", e)),
+                certificate: None,
             };
         }
 
-        // 2. Check if certificate already exists and is valid
+        // 2. Check if certificate already exists, is valid and covers every name
         let force_renew = request.force_renew.unwrap_or(false);
-        if !force_renew {
-            if let Some(status) = self.check_certificate(&request.domain) {
-                return status;
-            }
+        if !force_renew
+            && let Some(status) = self.check_certificate(&request.domain)
+            && status.status == "valid"
+            && status.certificate.as_ref().is_some_and(|info| {
+                names
+                    .iter()
+                    .all(|name| info.sans.iter().any(|san| san.eq_ignore_ascii_case(name)))
+            })
+        {
+            return status;
         }
 
         // 3. Issue certificate
@@ -100,6 +191,7 @@ impl CertificateIssuer {
                 key_path: None,
                 expiry: None,
                 error: Some(format!("Certificate issuance failed: {}", e)),
+                certificate: None,
             },
         }
     }
@@ -110,13 +202,14 @@ impl CertificateIssuer {
 
         // 1. DNS resolution check
         let addresses = format!("{}:443", domain).to_socket_addrs()?;
+        let public_ip = self.public_ip().await.to_string();
 
         // For testing purposes, consider any local IP as valid
         // You can remove or modify this for production
-        let valid_ips = vec![
+        let valid_ips = [
             "127.0.0.1".to_string(),
             "localhost".to_string(),
-            self.public_ip.clone(),
+            public_ip.clone(),
         ];
 
         let mut found_matching_ip = false;
@@ -136,7 +229,7 @@ impl CertificateIssuer {
             return Err(anyhow!(
                 "Domain {} does not resolve to a valid IP (local: 127.0.0.1 or public: {})",
                 domain,
-                self.public_ip
+                public_ip
             ));
         }
 
@@ -146,189 +239,143 @@ impl CertificateIssuer {
         Ok(())
     }
 
+    // Certificate and key of a domain, issued certificates take precedence
+    fn certificate_paths(&self, domain: &str) -> Option<(PathBuf, PathBuf)> {
+        let lineage = lineage_name(domain);
+        [
+            self.certbot_dir.join("live").join(&lineage),
+            self.output_dir.join(&lineage),
+        ]
+        .into_iter()
+        .map(|dir| (dir.join("fullchain.pem"), dir.join("privkey.pem")))
+        .find(|(cert_path, key_path)| cert_path.exists() && key_path.exists())
+    }
+
     // Check if a valid certificate already exists - make this public
     pub fn check_certificate(&self, domain: &str) -> Option<CertificateStatus> {
-        let live_dir = self.certbot_dir.join("live").join(domain);
-        let cert_path = live_dir.join("fullchain.pem");
-        let key_path = live_dir.join("privkey.pem");
+        let (cert_path, key_path) = self.certificate_paths(domain)?;
 
-        if cert_path.exists() && key_path.exists() {
-            // Check certificate expiry
-            match self.get_cert_expiry(&cert_path) {
-                Ok(expiry) => {
+        let (status, expiry, error, certificate) =
+            match inspect_certificate(&cert_path, Some(&key_path)) {
+                Ok(info) => {
                     let now = SystemTime::now();
                     let thirty_days = Duration::from_secs(30 * 24 * 60 * 60);
 
-                    // If certificate expires in more than 30 days, it's valid
-                    if expiry > now + thirty_days {
-                        return Some(CertificateStatus {
-                            domain: domain.to_string(),
-                            status: "valid".to_string(),
-                            cert_path: Some(cert_path.to_string_lossy().to_string()),
-                            key_path: Some(key_path.to_string_lossy().to_string()),
-                            expiry: Some(format!("{:?}", expiry)),
-                            error: None,
-                        });
-                    }
-
-                    // Certificate exists but expires soon
-                    return Some(CertificateStatus {
-                        domain: domain.to_string(),
-                        status: "expiring_soon".to_string(),
-                        cert_path: Some(cert_path.to_string_lossy().to_string()),
-                        key_path: Some(key_path.to_string_lossy().to_string()),
-                        expiry: Some(format!("{:?}", expiry)),
-                        error: None,
-                    });
-                }
-                Err(_) => {
-                    // Certificate exists but can't read expiry
-                    return Some(CertificateStatus {
-                        domain: domain.to_string(),
-                        status: "unknown_expiry".to_string(),
-                        cert_path: Some(cert_path.to_string_lossy().to_string()),
-                        key_path: Some(key_path.to_string_lossy().to_string()),
-                        expiry: None,
-                        error: Some("Could not determine certificate expiry".to_string()),
-                    });
+                    let status = if info.key_matches == Some(false) || !info.chain_valid {
+                        "invalid"
+                    } else if info.expiry <= now {
+                        "expired"
+                    } else if info.expiry <= now + thirty_days {
+                        "expiring_soon"
+                    } else {
+                        "valid"
+                    };
+                    let error = (!info.problems.is_empty()).then(|| info.problems.join("; "));
+
+                    (status, Some(info.not_after.clone()), error, Some(info))
                 }
-            }
-        }
+                // Certificate exists but can't be parsed
+                Err(e) => (
+                    "invalid",
+                    None,
+                    Some(format!("Could not parse certificate: {}", e)),
+                    None,
+                ),
+            };
 
-        None
+        Some(CertificateStatus {
+            domain: domain.to_string(),
+            status: status.to_string(),
+            cert_path: Some(cert_path.to_string_lossy().to_string()),
+            key_path: Some(key_path.to_string_lossy().to_string()),
+            expiry,
+            error,
+            certificate,
+        })
     }
 
-    // Issue a certificate using certbot
+    // Issue a certificate through the ACME server
     async fn issue_certificate(&self, request: &CertificateRequest) -> Result<CertificateStatus> {
         let domain = &request.domain;
         let email = &request.email;
         let staging = request.staging.unwrap_or(false);
+        let names = request.names();
 
-        println!("Issuing certificate for: {}", domain);
-
-        // For local testing, create dummy certificate files
-        let dummy_testing = true; // Set to false for production
-
-        if dummy_testing {
-            println!("Creating dummy certificate files for testing");
-
-            // Create directories
-            let live_dir = self.certbot_dir.join("live").join(domain);
-            fs::create_dir_all(&live_dir)?;
-
-            let cert_path = live_dir.join("fullchain.pem");
-            let key_path = live_dir.join("privkey.pem");
-
-            // Create dummy certificate files
-            fs::write(&cert_path, "DUMMY CERTIFICATE FOR TESTING\n")?;
-            fs::write(&key_path, "DUMMY PRIVATE KEY FOR TESTING\n")?;
-
-            // Also copy to output directory
-            fs::create_dir_all(self.output_dir.join(domain))?;
-            fs::copy(
-                &cert_path,
-                self.output_dir.join(domain).join("fullchain.pem"),
-            )?;
-            fs::copy(&key_path, self.output_dir.join(domain).join("privkey.pem"))?;
-
-            return Ok(CertificateStatus {
-                domain: domain.to_string(),
-                status: "issued".to_string(),
-                cert_path: Some(cert_path.to_string_lossy().to_string()),
-                key_path: Some(key_path.to_string_lossy().to_string()),
-                expiry: Some("2099-12-31T23:59:59Z".to_string()),
-                error: None,
-            });
-        }
+        println!("Issuing certificate for: {}", names.join(", "));
 
-        // Build certbot command
-        let mut cmd = Command::new("certbot");
-        cmd.arg("certonly")
-            .arg("--webroot")
-            .arg("-w")
-            .arg("/var/www/html") // Webroot path - adjust to your HTTP challenge path
-            .arg("--email")
-            .arg(email)
-            .arg("--agree-tos")
-            .arg("--no-eff-email")
-            .arg("-d")
-            .arg(domain)
-            .arg("--config-dir")
-            .arg(&self.certbot_dir);
-
-        if staging {
-            cmd.arg("--staging");
-        }
+        let directory_url = if staging {
+            &self.acme.staging_directory_url
+        } else {
+            &self.acme.directory_url
+        };
 
-        // Execute certbot command
-        let output = cmd.output()?;
+        // Keep one account key per ACME server
+        let account_key_path = self
+            .certbot_dir
+            .join("accounts")
+            .join(directory_url.replace(|c: char| !c.is_ascii_alphanumeric(), "_"))
+            .join("account_key.der");
+
+        let mut client =
+            AcmeClient::new(self.acme.http_client()?, directory_url, &account_key_path).await?;
+        client.register_account(email).await?;
+        let solvers = ChallengeSolvers {
+            preferred: self.challenge_type(request),
+            http: &self.challenges,
+            dns: self.dns.as_deref(),
+            dns_propagation_delay: self.acme.dns_propagation_delay,
+        };
+        let issued = client.order_certificate(&names, &solvers).await?;
 
-        if !output.status.success() {
-            let error = String::from_utf8_lossy(&output.stderr);
-            println!("Certbot error: {}", error);
-            return Err(anyhow!("Certbot failed: {}", error));
-        }
+        // Store the certificate where certbot would have put it
+        let lineage = lineage_name(domain);
+        let live_dir = self.certbot_dir.join("live").join(&lineage);
+        fs::create_dir_all(&live_dir)?;
 
-        // Check if certificate was created
-        let live_dir = self.certbot_dir.join("live").join(domain);
+        // The key goes first: the watcher refuses a certificate with a key
+        // that does not match, and picks the pair up once both are in place
         let cert_path = live_dir.join("fullchain.pem");
         let key_path = live_dir.join("privkey.pem");
-
-        if !cert_path.exists() || !key_path.exists() {
-            return Err(anyhow!("Certificate files were not created"));
+        let output_dir = self.output_dir.join(&lineage);
+        fs::create_dir_all(&output_dir)?;
+        for dir in [&live_dir, &output_dir] {
+            write_file_atomic(&dir.join("privkey.pem"), issued.key_pem.as_bytes(), 0o600)?;
+            write_file_atomic(
+                &dir.join("fullchain.pem"),
+                issued.cert_pem.as_bytes(),
+                0o644,
+            )?;
         }
 
-        // Copy certificates to output directory
-        fs::create_dir_all(self.output_dir.join(domain))?;
-        fs::copy(
-            &cert_path,
-            self.output_dir.join(domain).join("fullchain.pem"),
-        )?;
-        fs::copy(&key_path, self.output_dir.join(domain).join("privkey.pem"))?;
-
-        // Get expiry information
-        let expiry = match self.get_cert_expiry(&cert_path) {
-            Ok(expiry) => Some(format!("{:?}", expiry)),
-            Err(_) => None,
-        };
+        let certificate = inspect_certificate(&cert_path, Some(&key_path)).ok();
 
         Ok(CertificateStatus {
             domain: domain.to_string(),
             status: "issued".to_string(),
             cert_path: Some(cert_path.to_string_lossy().to_string()),
             key_path: Some(key_path.to_string_lossy().to_string()),
-            expiry,
+            expiry: certificate.as_ref().map(|info| info.not_after.clone()),
             error: None,
+            certificate,
         })
     }
 
-    // Get certificate expiry date
-    fn get_cert_expiry(&self, cert_path: &Path) -> Result<SystemTime> {
-        // Execute openssl to get certificate expiry
-        let output = Command::new("openssl")
-            .arg("x509")
-            .arg("-in")
-            .arg(cert_path)
-            .arg("-noout")
-            .arg("-enddate")
-            .output()?;
-
-        if !output.status.success() {
-            return Err(anyhow!("Failed to get certificate expiry"));
-        }
-
-        let expiry_output = String::from_utf8_lossy(&output.stdout);
-
-        // Parse the expiry date from output (format: notAfter=May 15 23:59:59 2024 GMT)
-        let date_part = expiry_output
-            .strip_prefix("notAfter=")
-            .ok_or_else(|| anyhow!("Unexpected output format"))?
-            .trim();
+    /// Expiry of the certificate currently on disk for `domain`, if there is one
+    pub fn certificate_expiry(&self, domain: &str) -> Option<SystemTime> {
+        self.certificate_info(domain).map(|info| info.expiry)
+    }
 
-        // This is a simplified example - in production, use a proper date parser
-        // For this example, we'll return current time + 90 days
-        let expiry = SystemTime::now() + Duration::from_secs(90 * 24 * 60 * 60);
+    /// Details of the certificate currently on disk for `domain`, if there is one
+    pub fn certificate_info(&self, domain: &str) -> Option<CertificateInfo> {
+        let (cert_path, _) = self.certificate_paths(domain)?;
+        inspect_certificate(&cert_path, None).ok()
+    }
 
-        Ok(expiry)
+    /// Domains of every certificate issued into the certbot directory
+    pub fn issued_domains(&self) -> Vec<String> {
+        scan_cert_dir(&self.certbot_dir.join("live"))
+            .into_iter()
+            .map(|cert| lineage_domain(&cert.domain))
+            .collect()
     }
 }
@@ -1,2 +1,7 @@
+pub mod acme;
 pub mod certbot;
+pub mod dns;
+pub mod inspect;
 pub mod issuer;
+pub mod resolver;
+pub mod store;
@@ -0,0 +1,236 @@
+// src/cert/resolver.rs
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs::File,
+    io::BufReader,
+    sync::{Arc, RwLock},
+};
+
+use anyhow::{Result, anyhow};
+use rustls::{
+    RootCertStore, ServerConfig,
+    crypto::{CryptoProvider, aws_lc_rs},
+    server::{ClientHello, ResolvesServerCert, WebPkiClientVerifier},
+    sign::CertifiedKey,
+    version,
+};
+use x509_parser::{extensions::GeneralName, prelude::FromDer};
+
+/// Served certificates, by directory name and by every name they cover
+#[derive(Debug, Default)]
+struct CertIndex {
+    by_lineage: BTreeMap<String, Arc<CertifiedKey>>,
+    by_name: HashMap<String, Arc<CertifiedKey>>,
+}
+
+impl CertIndex {
+    fn new(certs: BTreeMap<String, Arc<CertifiedKey>>) -> Self {
+        let mut by_name = HashMap::new();
+
+        for (lineage, key) in &certs {
+            for name in certificate_names(key).unwrap_or_else(|| vec![lineage.clone()]) {
+                // A certificate stored under the name itself wins over one that merely
+                // lists it, otherwise the alphabetically first lineage is used
+                if lineage == &name || !by_name.contains_key(&name) {
+                    by_name.insert(name, key.clone());
+                }
+            }
+        }
+
+        Self {
+            by_lineage: certs,
+            by_name,
+        }
+    }
+
+    // Exact names win over wildcards, which cover a single label only
+    fn find(&self, name: &str) -> Option<&Arc<CertifiedKey>> {
+        let name = name.trim_end_matches('.').to_lowercase();
+        self.by_name.get(&name).or_else(|| {
+            let (_, parent) = name.split_once('.')?;
+            self.by_name.get(&format!("*.{}", parent))
+        })
+    }
+}
+
+// DNS names of the leaf certificate, falling back to the subject common name
+fn certificate_names(key: &CertifiedKey) -> Option<Vec<String>> {
+    let leaf = key.cert.first()?;
+    let (_, cert) = x509_parser::certificate::X509Certificate::from_der(leaf.as_ref()).ok()?;
+
+    let mut names: Vec<String> = match cert.subject_alternative_name() {
+        Ok(Some(extension)) => extension
+            .value
+            .general_names
+            .iter()
+            .filter_map(|name| match name {
+                GeneralName::DNSName(dns) => Some(dns.to_lowercase()),
+                _ => None,
+            })
+            .collect(),
+        _ => Vec::new(),
+    };
+    if names.is_empty() {
+        names = cert
+            .subject()
+            .iter_common_name()
+            .filter_map(|cn| cn.as_str().ok())
+            .map(|cn| cn.to_lowercase())
+            .collect();
+    }
+
+    (!names.is_empty()).then_some(names)
+}
+
+/// Picks the certificate to present for a TLS handshake based on the SNI server name
+#[derive(Debug)]
+pub struct SniCertResolver {
+    provider: Arc<CryptoProvider>,
+    certs: RwLock<CertIndex>,
+    default_domain: RwLock<Option<String>>,
+}
+
+impl SniCertResolver {
+    /// Create an empty resolver
+    pub fn new() -> Self {
+        Self {
+            provider: Arc::new(aws_lc_rs::default_provider()),
+            certs: RwLock::new(CertIndex::default()),
+            default_domain: RwLock::new(None),
+        }
+    }
+
+    /// Get the certificate loaded from a domain's certificate directory
+    pub fn get(&self, domain: &str) -> Option<Arc<CertifiedKey>> {
+        self.certs
+            .read()
+            .ok()?
+            .by_lineage
+            .get(&domain.to_lowercase())
+            .cloned()
+    }
+
+    /// Whether any certificate lists `name` among its SANs, directly or through a wildcard
+    pub fn covers(&self, name: &str) -> bool {
+        self.certs
+            .read()
+            .map(|certs| certs.find(name).is_some())
+            .unwrap_or(false)
+    }
+
+    /// Whether the certificate served for `name` is in its validity period
+    pub fn has_valid(&self, name: &str) -> bool {
+        let Ok(certs) = self.certs.read() else {
+            return false;
+        };
+        certs
+            .find(name)
+            .and_then(|key| key.cert.first())
+            .and_then(|leaf| {
+                x509_parser::certificate::X509Certificate::from_der(leaf.as_ref()).ok()
+            })
+            .is_some_and(|(_, cert)| cert.validity().is_valid())
+    }
+
+    /// Atomically replace every served certificate, keyed by certificate directory.
+    /// New handshakes pick up the new set while established connections keep the
+    /// certificate they negotiated
+    pub fn replace_all(&self, certs: BTreeMap<String, Arc<CertifiedKey>>) {
+        let index = CertIndex::new(certs);
+        match self.certs.write() {
+            Ok(mut current) => *current = index,
+            Err(e) => println!("Error locking certificate store: {:?}", e),
+        }
+    }
+
+    /// Set the domain whose certificate is presented when SNI is missing or unknown,
+    /// otherwise the alphabetically first certificate is used
+    pub fn set_default(&self, domain: &str) {
+        if let Ok(mut default_domain) = self.default_domain.write() {
+            *default_domain = Some(domain.to_lowercase());
+        }
+    }
+
+    /// Whether at least one certificate is available
+    pub fn is_empty(&self) -> bool {
+        self.certs
+            .read()
+            .map(|certs| certs.by_lineage.is_empty())
+            .unwrap_or(true)
+    }
+
+    /// Build a rustls server config that resolves certificates through this resolver
+    pub fn server_config(self: &Arc<Self>) -> Result<ServerConfig> {
+        let config = ServerConfig::builder_with_provider(self.provider.clone())
+            .with_protocol_versions(&[&version::TLS12, &version::TLS13])?
+            .with_no_client_auth()
+            .with_cert_resolver(self.clone());
+
+        Ok(config)
+    }
+
+    /// Like [`SniCertResolver::server_config`], also asking clients for a certificate
+    /// issued by `roots`; clients without one are still accepted
+    pub fn server_config_with_client_auth(
+        self: &Arc<Self>,
+        roots: RootCertStore,
+    ) -> Result<ServerConfig> {
+        let verifier =
+            WebPkiClientVerifier::builder_with_provider(Arc::new(roots), self.provider.clone())
+                .allow_unauthenticated()
+                .build()?;
+        let config = ServerConfig::builder_with_provider(self.provider.clone())
+            .with_protocol_versions(&[&version::TLS12, &version::TLS13])?
+            .with_client_cert_verifier(verifier)
+            .with_cert_resolver(self.clone());
+
+        Ok(config)
+    }
+
+    /// Parse a PEM certificate chain and private key into a signing-ready key
+    pub fn load_certified_key(&self, cert_path: &str, key_path: &str) -> Result<Arc<CertifiedKey>> {
+        let mut cert_reader = BufReader::new(File::open(cert_path)?);
+        let chain = rustls_pemfile::certs(&mut cert_reader).collect::<Result<Vec<_>, _>>()?;
+        if chain.is_empty() {
+            return Err(anyhow!("No certificates found in {}", cert_path));
+        }
+
+        let mut key_reader = BufReader::new(File::open(key_path)?);
+        let key = rustls_pemfile::private_key(&mut key_reader)?
+            .ok_or_else(|| anyhow!("No private key found in {}", key_path))?;
+
+        let certified_key = CertifiedKey::from_der(chain, key, &self.provider)?;
+        Ok(Arc::new(certified_key))
+    }
+
+    fn lookup(&self, server_name: Option<&str>) -> Option<Arc<CertifiedKey>> {
+        let certs = self.certs.read().ok()?;
+
+        if let Some(key) = server_name.and_then(|name| certs.find(name)) {
+            return Some(key.clone());
+        }
+
+        let default_domain = self.default_domain.read().ok()?;
+        default_domain
+            .as_ref()
+            .and_then(|domain| certs.find(domain))
+            .or_else(|| certs.by_lineage.values().next())
+            .cloned()
+    }
+}
+
+impl ResolvesServerCert for SniCertResolver {
+    fn resolve(&self, client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        let server_name = client_hello.server_name();
+        let key = self.lookup(server_name);
+
+        if key.is_none() {
+            println!(
+                "No TLS certificate available for server name: {}",
+                server_name.unwrap_or("<none>")
+            );
+        }
+
+        key
+    }
+}
@@ -0,0 +1,142 @@
+// src/cert/store.rs
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::SystemTime,
+};
+
+use rustls::sign::CertifiedKey;
+
+use super::certbot::{DomainCert, scan_cert_dir};
+use super::resolver::SniCertResolver;
+
+/// Modification stamp of a certificate/key pair, used to detect renewed files
+#[derive(Debug, Clone, PartialEq)]
+struct CertFiles {
+    cert_path: String,
+    cert_stamp: Option<(SystemTime, u64)>,
+    key_stamp: Option<(SystemTime, u64)>,
+}
+
+impl CertFiles {
+    fn from_domain_cert(cert: &DomainCert) -> Self {
+        Self {
+            cert_path: cert.cert_path.clone(),
+            cert_stamp: file_stamp(Path::new(&cert.cert_path)),
+            key_stamp: file_stamp(Path::new(&cert.key_path)),
+        }
+    }
+}
+
+// Metadata follows symlinks, so certbot's live/ links report their archive target
+fn file_stamp(path: &Path) -> Option<(SystemTime, u64)> {
+    let metadata = fs::metadata(path).ok()?;
+    Some((metadata.modified().ok()?, metadata.len()))
+}
+
+/// Keeps the SNI resolver in sync with the certificates found on disk
+pub struct CertStore {
+    resolver: Arc<SniCertResolver>,
+    dirs: Vec<PathBuf>,
+    loaded: Mutex<HashMap<String, CertFiles>>,
+}
+
+impl CertStore {
+    /// Create a store serving certificates from `dirs`, earlier directories win
+    /// when several contain the same domain
+    pub fn new(resolver: Arc<SniCertResolver>, dirs: Vec<PathBuf>) -> Self {
+        Self {
+            resolver,
+            dirs,
+            loaded: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Whether a served certificate covers `domain`, directly or through a wildcard
+    pub fn covers(&self, domain: &str) -> bool {
+        self.resolver.covers(domain)
+    }
+
+    /// Whether a served certificate covering `domain` is currently valid
+    pub fn has_valid(&self, domain: &str) -> bool {
+        self.resolver.has_valid(domain)
+    }
+
+    /// Rescan the certificate directories and swap added, renewed and removed
+    /// certificates into the resolver. Returns whether anything changed.
+    pub fn reload(&self) -> bool {
+        let mut loaded = match self.loaded.lock() {
+            Ok(loaded) => loaded,
+            Err(e) => {
+                println!("Error locking certificate store: {:?}", e);
+                return false;
+            }
+        };
+
+        let mut found: BTreeMap<String, DomainCert> = BTreeMap::new();
+        for dir in &self.dirs {
+            for cert in scan_cert_dir(dir) {
+                found.entry(cert.domain.to_lowercase()).or_insert(cert);
+            }
+        }
+
+        let mut next_certs: BTreeMap<String, Arc<CertifiedKey>> = BTreeMap::new();
+        let mut next_loaded = HashMap::new();
+        let mut changed = false;
+
+        for (domain, cert) in &found {
+            let files = CertFiles::from_domain_cert(cert);
+            let current = self.resolver.get(domain);
+
+            // Unchanged files keep the already parsed key
+            if let (Some(previous), Some(key)) = (loaded.get(domain), current.as_ref())
+                && *previous == files
+            {
+                next_certs.insert(domain.clone(), key.clone());
+                next_loaded.insert(domain.clone(), files);
+                continue;
+            }
+
+            match self
+                .resolver
+                .load_certified_key(&cert.cert_path, &cert.key_path)
+            {
+                Ok(key) => {
+                    let action = if current.is_some() {
+                        "Reloaded"
+                    } else {
+                        "Loaded"
+                    };
+                    println!("{} TLS certificate for {}", action, domain);
+                    next_certs.insert(domain.clone(), key);
+                    next_loaded.insert(domain.clone(), files);
+                    changed = true;
+                }
+                Err(e) => {
+                    // Files may be mid-write, keep serving the last good certificate
+                    println!("Error loading certificate for {}: {}", domain, e);
+                    if let (Some(previous), Some(key)) = (loaded.get(domain), current) {
+                        next_certs.insert(domain.clone(), key);
+                        next_loaded.insert(domain.clone(), previous.clone());
+                    }
+                }
+            }
+        }
+
+        for domain in loaded.keys() {
+            if !next_loaded.contains_key(domain) {
+                println!("Removed TLS certificate for {}", domain);
+                changed = true;
+            }
+        }
+
+        if changed {
+            self.resolver.replace_all(next_certs);
+            *loaded = next_loaded;
+        }
+
+        changed
+    }
+}
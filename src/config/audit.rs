@@ -0,0 +1,263 @@
+// src/config/audit.rs
+use std::{
+    fmt::Display,
+    fs::{self, OpenOptions},
+    io::Write,
+    net::IpAddr,
+    os::unix::fs::OpenOptionsExt,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    time::SystemTime,
+};
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use time::{OffsetDateTime, format_description::well_known::Rfc3339};
+
+use crate::cert::inspect::format_rfc3339;
+
+const DEFAULT_AUDIT_LOG: &str = "audit.log";
+const DEFAULT_MAX_BYTES: u64 = 10 * 1024 * 1024;
+const DEFAULT_KEEP: usize = 5;
+
+/// Where a configuration change came from
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AuditOrigin {
+    /// A manager API request
+    Api,
+    /// Docker Swarm service discovery
+    SwarmDiscovery,
+    /// The certificate renewal service
+    CertRenewal,
+    /// A reload of the configuration file after it changed on disk
+    FileReload,
+}
+
+/// One change to routes or certificates, a line of JSON in the audit log
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AuditEntry {
+    pub timestamp: String,
+    pub origin: AuditOrigin,
+    /// Token, client certificate or local client that made an API change
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub actor: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub source_ip: Option<String>,
+    /// What changed, e.g. `route.create`, `route.update`, `route.delete` or `certificate.issue`
+    pub action: String,
+    /// Host or domain of the change
+    pub target: String,
+    #[serde(default)]
+    pub old: Option<Value>,
+    #[serde(default)]
+    pub new: Option<Value>,
+}
+
+impl AuditEntry {
+    pub fn new(origin: AuditOrigin, action: &str, target: &str) -> Self {
+        Self {
+            timestamp: format_rfc3339(SystemTime::now()),
+            origin,
+            actor: None,
+            source_ip: None,
+            action: action.to_string(),
+            target: target.to_string(),
+            old: None,
+            new: None,
+        }
+    }
+
+    /// Who made the change and from where
+    pub fn by(mut self, actor: impl Display, source_ip: Option<IpAddr>) -> Self {
+        self.actor = Some(actor.to_string());
+        self.source_ip = source_ip.map(|ip| ip.to_string());
+        self
+    }
+
+    /// Values before and after the change, `None` for created or removed ones
+    pub fn change<T: Serialize>(mut self, old: Option<&T>, new: Option<&T>) -> Self {
+        self.old = old.and_then(|old| serde_json::to_value(old).ok());
+        self.new = new.and_then(|new| serde_json::to_value(new).ok());
+        self
+    }
+}
+
+/// Conditions an audit entry must meet to be listed
+#[derive(Debug, Default)]
+pub struct AuditFilter {
+    pub origin: Option<AuditOrigin>,
+    pub actor: Option<String>,
+    /// Exact action, or a prefix ending in `.` such as `route.`
+    pub action: Option<String>,
+    pub target: Option<String>,
+    pub since: Option<OffsetDateTime>,
+    pub until: Option<OffsetDateTime>,
+}
+
+/// Parse an RFC 3339 timestamp, as used by the audit log
+pub fn parse_timestamp(value: &str) -> Option<OffsetDateTime> {
+    OffsetDateTime::parse(value, &Rfc3339).ok()
+}
+
+impl AuditFilter {
+    pub fn matches(&self, entry: &AuditEntry) -> bool {
+        if self.origin.is_some_and(|origin| origin != entry.origin)
+            || self
+                .actor
+                .as_ref()
+                .is_some_and(|actor| entry.actor.as_ref() != Some(actor))
+            || self
+                .target
+                .as_ref()
+                .is_some_and(|target| *target != entry.target)
+        {
+            return false;
+        }
+        if let Some(action) = &self.action {
+            let matched = match action.strip_suffix('.') {
+                Some(prefix) => entry
+                    .action
+                    .strip_prefix(prefix)
+                    .is_some_and(|rest| rest.starts_with('.')),
+                None => *action == entry.action,
+            };
+            if !matched {
+                return false;
+            }
+        }
+        if self.since.is_some() || self.until.is_some() {
+            let Some(time) = parse_timestamp(&entry.timestamp) else {
+                return false;
+            };
+            if self.since.is_some_and(|since| time < since)
+                || self.until.is_some_and(|until| time > until)
+            {
+                return false;
+            }
+        }
+        true
+    }
+}
+
+// The log file and its rotation settings
+struct AuditFile {
+    path: PathBuf,
+    max_bytes: u64,
+    /// Rotated files kept, `audit.log.1` being the newest
+    keep: usize,
+}
+
+impl AuditFile {
+    fn rotated(&self, n: usize) -> PathBuf {
+        PathBuf::from(format!("{}.{}", self.path.display(), n))
+    }
+
+    fn append(&self, line: &str) -> std::io::Result<()> {
+        let size = fs::metadata(&self.path).map(|m| m.len()).unwrap_or(0);
+        if size > 0 && size + line.len() as u64 + 1 > self.max_bytes {
+            self.rotate()?;
+        }
+
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .mode(0o640)
+            .open(&self.path)?;
+        writeln!(file, "{}", line)?;
+        file.sync_data()
+    }
+
+    fn rotate(&self) -> std::io::Result<()> {
+        if self.keep == 0 {
+            return fs::remove_file(&self.path);
+        }
+        let _ = fs::remove_file(self.rotated(self.keep));
+        for n in (1..self.keep).rev() {
+            let from = self.rotated(n);
+            if from.exists() {
+                fs::rename(from, self.rotated(n + 1))?;
+            }
+        }
+        fs::rename(&self.path, self.rotated(1))
+    }
+}
+
+/// Append-only log of route and certificate changes, rotated by size
+#[derive(Clone)]
+pub struct AuditLog {
+    file: Arc<Mutex<AuditFile>>,
+}
+
+impl AuditLog {
+    pub fn new(path: impl Into<PathBuf>, max_bytes: u64, keep: usize) -> Self {
+        Self {
+            file: Arc::new(Mutex::new(AuditFile {
+                path: path.into(),
+                max_bytes,
+                keep,
+            })),
+        }
+    }
+
+    /// Log to `AUDIT_LOG`, rotated at `AUDIT_LOG_MAX_BYTES` keeping `AUDIT_LOG_KEEP` old files
+    pub fn from_env() -> Self {
+        let path = std::env::var("AUDIT_LOG").unwrap_or_else(|_| DEFAULT_AUDIT_LOG.to_string());
+        let max_bytes = std::env::var("AUDIT_LOG_MAX_BYTES")
+            .ok()
+            .and_then(|v| v.parse::<u64>().ok())
+            .unwrap_or(DEFAULT_MAX_BYTES);
+        let keep = std::env::var("AUDIT_LOG_KEEP")
+            .ok()
+            .and_then(|v| v.parse::<usize>().ok())
+            .unwrap_or(DEFAULT_KEEP);
+        println!("Audit log: {}", path);
+        Self::new(path, max_bytes, keep)
+    }
+
+    /// Append an entry, failures are logged but never stop the change itself
+    pub fn record(&self, entry: AuditEntry) {
+        let line = match serde_json::to_string(&entry) {
+            Ok(line) => line,
+            Err(e) => {
+                println!("Error serializing audit entry: {}", e);
+                return;
+            }
+        };
+        match self.file.lock() {
+            Ok(file) => {
+                if let Err(e) = file.append(&line) {
+                    println!("Error writing audit log {}: {}", file.path.display(), e);
+                }
+            }
+            Err(e) => println!("Error locking audit log: {}", e),
+        }
+    }
+
+    /// Up to `limit` entries accepted by `matches`, newest first
+    pub fn query(&self, limit: usize, matches: impl Fn(&AuditEntry) -> bool) -> Vec<AuditEntry> {
+        let Ok(file) = self.file.lock() else {
+            return Vec::new();
+        };
+
+        let mut entries = Vec::new();
+        let paths =
+            std::iter::once(file.path.clone()).chain((1..=file.keep).map(|n| file.rotated(n)));
+        for path in paths {
+            let Ok(content) = fs::read_to_string(&path) else {
+                continue;
+            };
+            let found = content
+                .lines()
+                .rev()
+                .filter_map(|line| serde_json::from_str::<AuditEntry>(line).ok())
+                .filter(|entry| matches(entry))
+                .take(limit - entries.len());
+            entries.extend(found);
+            if entries.len() >= limit {
+                break;
+            }
+        }
+        entries
+    }
+}
@@ -1,13 +1,144 @@
 use std::{
-    collections::HashMap,
     fs,
     io::{Read, Write},
+    path::PathBuf,
+    sync::atomic::{AtomicU64, Ordering},
+    time::SystemTime,
 };
 
-use super::model::{ConfigStore, Configuration, ServerMapping};
+use serde::Serialize;
+
+use super::model::{ConfigStore, Configuration, MaintenanceConfig, ServerMapping};
+use crate::cert::inspect::format_rfc3339;
 
 const CONFIG_PATH: &str = "config.json";
 const DEFAULT_CONFIG: &str = r#"{"servers":[]}"#;
+const DEFAULT_HISTORY_DIR: &str = "config.history";
+const DEFAULT_HISTORY_DEPTH: usize = 20;
+
+/// Version of the configuration in the config file
+static VERSION: AtomicU64 = AtomicU64::new(0);
+
+/// A configuration version kept in the history
+#[derive(Debug, Clone, Serialize)]
+pub struct VersionInfo {
+    pub version: u64,
+    /// When the version was saved
+    pub timestamp: String,
+    pub routes: usize,
+}
+
+// Snapshots of saved versions live in CONFIG_HISTORY_DIR, one file per version
+fn history_dir() -> PathBuf {
+    PathBuf::from(
+        std::env::var("CONFIG_HISTORY_DIR").unwrap_or_else(|_| DEFAULT_HISTORY_DIR.to_string()),
+    )
+}
+
+// Versions kept in the history, CONFIG_HISTORY_DEPTH
+fn history_depth() -> usize {
+    std::env::var("CONFIG_HISTORY_DEPTH")
+        .ok()
+        .and_then(|v| v.parse::<usize>().ok())
+        .unwrap_or(DEFAULT_HISTORY_DEPTH)
+}
+
+fn snapshot_path(version: u64) -> PathBuf {
+    history_dir().join(format!("{}.json", version))
+}
+
+// Versions in the history, newest first
+fn history_versions() -> Vec<u64> {
+    let mut versions: Vec<u64> = fs::read_dir(history_dir())
+        .map(|entries| {
+            entries
+                .filter_map(|entry| {
+                    let name = entry.ok()?.file_name();
+                    name.to_str()?.strip_suffix(".json")?.parse().ok()
+                })
+                .collect()
+        })
+        .unwrap_or_default();
+    versions.sort_unstable_by(|a, b| b.cmp(a));
+    versions
+}
+
+// Keep a copy of a saved version and drop those beyond the history depth
+fn write_snapshot(config: &Configuration) -> Result<(), std::io::Error> {
+    let depth = history_depth();
+    if depth == 0 {
+        return Ok(());
+    }
+    fs::create_dir_all(history_dir())?;
+    let data = serde_json::to_string_pretty(config).map_err(std::io::Error::other)?;
+    fs::write(snapshot_path(config.version), data)?;
+
+    for version in history_versions().into_iter().skip(depth) {
+        fs::remove_file(snapshot_path(version))?;
+    }
+    Ok(())
+}
+
+// Whether two configurations route the same, whatever their versions
+fn same_content(a: &Configuration, b: &Configuration) -> bool {
+    let content = |config: &Configuration| {
+        let mut value = serde_json::to_value(config).ok()?;
+        value.as_object_mut()?.remove("version");
+        Some(value)
+    };
+    content(a).is_some_and(|a| Some(a) == content(b))
+}
+
+/// Version of the configuration in effect
+pub fn config_version() -> u64 {
+    VERSION.load(Ordering::SeqCst)
+}
+
+/// Saved versions still in the history, newest first
+pub fn list_versions() -> Vec<VersionInfo> {
+    history_versions()
+        .into_iter()
+        .filter_map(|version| {
+            let path = snapshot_path(version);
+            let modified = fs::metadata(&path).and_then(|m| m.modified()).ok()?;
+            let config = load_version(version)?;
+            Some(VersionInfo {
+                version,
+                timestamp: format_rfc3339(modified),
+                routes: config.servers.len(),
+            })
+        })
+        .collect()
+}
+
+/// Configuration saved as `version`, if it is still in the history
+pub fn load_version(version: u64) -> Option<Configuration> {
+    let content = fs::read_to_string(snapshot_path(version)).ok()?;
+    serde_json::from_str(&content).ok()
+}
+
+/// Give a configuration read from the file a version. One matching its snapshot
+/// keeps its number, anything else, e.g. edited by hand, becomes the next version.
+/// Versions never go back, a restored older file is saved as a new one too
+pub fn adopt_version(config: &Configuration) {
+    let latest = history_versions().first().copied().unwrap_or(0);
+    let candidate = if config.version > 0 {
+        config.version
+    } else {
+        latest
+    };
+    if candidate >= config_version()
+        && load_version(candidate).is_some_and(|saved| same_content(&saved, config))
+    {
+        VERSION.store(candidate, Ordering::SeqCst);
+        return;
+    }
+
+    VERSION.fetch_max(config.version.max(latest), Ordering::SeqCst);
+    if let Err(err) = commit(config) {
+        println!("Error saving configuration version: {}", err);
+    }
+}
 
 /// Load configuration from file
 pub fn get_config() -> ConfigStore {
@@ -26,7 +157,9 @@ pub fn get_config() -> ConfigStore {
         Err(err) => {
             println!("Config file not found ({}), creating with defaults", err);
             content = DEFAULT_CONFIG.to_string();
-            update_config(vec![]);
+            if let Err(err) = update_config(vec![]) {
+                println!("Error creating default config file: {}", err);
+            }
         }
     }
 
@@ -38,27 +171,84 @@ pub fn get_config() -> ConfigStore {
         }
     };
 
+    adopt_version(&config);
     let store = config.to_hashmap();
 
     // Log loaded mappings
-    for (from, to) in &store {
-        println!("Loaded mapping: {} -> {}", from, to);
+    for (from, mapping) in &store {
+        println!(
+            "Loaded mapping: {} -> {} ({} path rules)",
+            from,
+            mapping.to,
+            mapping.paths.len()
+        );
     }
 
     store
 }
 
+/// Read the configuration file for a reload, refusing one that cannot be used
+pub fn read_config() -> Result<Configuration, String> {
+    let content =
+        fs::read_to_string(CONFIG_PATH).map_err(|e| format!("Error reading config file: {}", e))?;
+    let config = serde_json::from_str::<Configuration>(&content)
+        .map_err(|e| format!("Error parsing config file: {}", e))?;
+    config
+        .validate()
+        .map_err(|e| format!("Invalid config file: {}", e))?;
+    Ok(config)
+}
+
+/// Modification time of the configuration file
+pub fn config_modified() -> Option<SystemTime> {
+    fs::metadata(CONFIG_PATH).and_then(|m| m.modified()).ok()
+}
+
+/// Global maintenance mode saved in the configuration file
+pub fn get_maintenance() -> Option<MaintenanceConfig> {
+    let content = fs::read_to_string(CONFIG_PATH).ok()?;
+    serde_json::from_str::<Configuration>(&content)
+        .ok()?
+        .maintenance
+}
+
 /// Update configuration file with new server mappings
 pub fn update_config(servers: Vec<ServerMapping>) -> Result<(), std::io::Error> {
-    let config = Configuration { servers };
+    // Settings besides the mappings are kept as they are
+    let config = Configuration {
+        servers,
+        maintenance: get_maintenance(),
+        ..Configuration::new()
+    };
+    save_config(&config)
+}
+
+/// Write the whole configuration file as a new version, unless nothing changed
+pub fn save_config(config: &Configuration) -> Result<(), std::io::Error> {
+    // Discovery saves on every run, which must not add versions
+    let current = fs::read_to_string(CONFIG_PATH)
+        .ok()
+        .and_then(|content| serde_json::from_str::<Configuration>(&content).ok());
+    if current.is_some_and(|current| same_content(&current, config)) {
+        return Ok(());
+    }
+    commit(config).map(|_| ())
+}
+
+// Write the configuration file as the next version and keep a snapshot of it
+fn commit(config: &Configuration) -> Result<u64, std::io::Error> {
+    let config = Configuration {
+        version: config_version() + 1,
+        ..config.clone()
+    };
     let data = match serde_json::to_string_pretty(&config) {
         Ok(data) => data,
         Err(err) => {
             println!("Error serializing config: {}", err);
-            return Err(std::io::Error::new(
-                std::io::ErrorKind::Other,
-                format!("Serialization error: {}", err),
-            ));
+            return Err(std::io::Error::other(format!(
+                "Serialization error: {}",
+                err
+            )));
         }
     };
 
@@ -76,18 +266,18 @@ pub fn update_config(servers: Vec<ServerMapping>) -> Result<(), std::io::Error>
 
     // Rename temp file to actual config file
     std::fs::rename(&temp_path, CONFIG_PATH)?;
+    VERSION.store(config.version, Ordering::SeqCst);
 
-    println!("Config updated successfully");
-    Ok(())
+    // The change is made even when its snapshot cannot be kept
+    if let Err(err) = write_snapshot(&config) {
+        println!("Error saving configuration history: {}", err);
+    }
+
+    println!("Config updated successfully (version {})", config.version);
+    Ok(config.version)
 }
 
 /// Create mappings from config store
 pub fn create_mappings_from_store(store: &ConfigStore) -> Vec<ServerMapping> {
-    store
-        .iter()
-        .map(|(k, v)| ServerMapping {
-            from: k.to_string(),
-            to: v.to_string(),
-        })
-        .collect()
+    Configuration::from_hashmap(store).servers
 }
@@ -1,2 +1,3 @@
+pub mod audit;
 pub mod file_manager;
 pub mod model;
@@ -1,47 +1,945 @@
+use regex::Regex;
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::{
+    collections::{BTreeMap, HashMap},
+    path::{Component, PathBuf},
+    sync::OnceLock,
+};
 
 /// Type alias for the configuration store used throughout the application
-pub type ConfigStore = HashMap<String, String>;
+pub type ConfigStore = HashMap<String, ServerMapping>;
+
+/// How the path of a rule is compared with the request path
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PathMatch {
+    /// The request path equals the rule path
+    Exact,
+    /// The request path starts with the rule path, on a segment boundary
+    #[default]
+    Prefix,
+    /// The request path matches the rule path as a regular expression
+    Regex,
+}
+
+/// Backend selection algorithm of a pool
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Algorithm {
+    /// Every upstream in turn, ignoring weights
+    #[default]
+    RoundRobin,
+    /// Round robin in proportion to the upstream weights
+    Weighted,
+    /// The upstream with the fewest requests in flight relative to its weight
+    LeastConnections,
+    /// A weighted random upstream
+    Random,
+    /// Consistent hashing on `hash_on`, so a client keeps reaching the same upstream
+    ConsistentHash,
+}
+
+/// Load balancing settings of a pool
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub struct LoadBalancing {
+    #[serde(default)]
+    pub algorithm: Algorithm,
+    /// Hash key for `consistent_hash`: `ip`, `header:<name>` or `cookie:<name>`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hash_on: Option<String>,
+}
+
+fn default_weight() -> usize {
+    1
+}
+
+fn is_default_weight(weight: &usize) -> bool {
+    *weight == 1
+}
+
+/// Backend in a load balanced pool
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct Upstream {
+    pub addr: String,
+    #[serde(default = "default_weight", skip_serializing_if = "is_default_weight")]
+    pub weight: usize,
+}
+
+/// How upstreams are probed
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum HealthCheckType {
+    /// A TCP connection can be established
+    Tcp,
+    /// A GET request returns the expected status and body
+    #[default]
+    Http,
+}
+
+fn default_health_path() -> String {
+    "/".to_string()
+}
+
+fn default_expected_status() -> u16 {
+    200
+}
+
+fn default_health_interval() -> u64 {
+    10
+}
+
+fn default_health_timeout() -> u64 {
+    2
+}
+
+fn default_healthy_threshold() -> usize {
+    1
+}
+
+fn default_unhealthy_threshold() -> usize {
+    3
+}
+
+/// Active health check of a pool, unhealthy upstreams are taken out of rotation
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct HealthCheckConfig {
+    #[serde(default, rename = "type")]
+    pub check_type: HealthCheckType,
+    #[serde(default = "default_health_path")]
+    pub path: String,
+    /// Host header of HTTP checks, the upstream address when unset
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub host: Option<String>,
+    #[serde(default = "default_expected_status")]
+    pub expected_status: u16,
+    /// Text the response body must contain
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expected_body: Option<String>,
+    /// Seconds between checks
+    #[serde(default = "default_health_interval")]
+    pub interval: u64,
+    /// Seconds to wait for a connection and response
+    #[serde(default = "default_health_timeout")]
+    pub timeout: u64,
+    /// Consecutive successes before an unhealthy upstream is used again
+    #[serde(default = "default_healthy_threshold")]
+    pub healthy_threshold: usize,
+    /// Consecutive failures before an upstream is taken out of rotation
+    #[serde(default = "default_unhealthy_threshold")]
+    pub unhealthy_threshold: usize,
+}
+
+impl HealthCheckConfig {
+    /// Check that the health check can be run
+    pub fn validate(&self) -> Result<(), String> {
+        if self.interval == 0 || self.timeout == 0 {
+            return Err("Health check interval and timeout must be above 0".to_string());
+        }
+        if self.healthy_threshold == 0 || self.unhealthy_threshold == 0 {
+            return Err("Health check thresholds must be above 0".to_string());
+        }
+        if !self.path.starts_with('/') {
+            return Err(format!(
+                "Health check path {} must start with '/'",
+                self.path
+            ));
+        }
+        Ok(())
+    }
+}
+
+fn default_consecutive_failures() -> usize {
+    5
+}
+
+fn default_error_rate() -> u8 {
+    50
+}
+
+fn default_min_requests() -> usize {
+    20
+}
+
+fn default_breaker_window() -> u64 {
+    30
+}
+
+fn default_cooldown() -> u64 {
+    30
+}
+
+fn default_half_open_requests() -> usize {
+    1
+}
+
+/// Circuit breaker of every upstream in a pool, tripped by failures of real requests
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct CircuitBreakerConfig {
+    /// Failures in a row that open the circuit
+    #[serde(default = "default_consecutive_failures")]
+    pub consecutive_failures: usize,
+    /// Percentage of failed requests within `window` that opens the circuit
+    #[serde(default = "default_error_rate")]
+    pub error_rate: u8,
+    /// Requests needed within `window` before `error_rate` applies
+    #[serde(default = "default_min_requests")]
+    pub min_requests: usize,
+    /// Seconds over which the error rate is measured
+    #[serde(default = "default_breaker_window")]
+    pub window: u64,
+    /// Seconds an open circuit rejects requests before letting trial requests through
+    #[serde(default = "default_cooldown")]
+    pub cooldown: u64,
+    /// Trial requests allowed at once while half open
+    #[serde(default = "default_half_open_requests")]
+    pub half_open_requests: usize,
+}
+
+impl CircuitBreakerConfig {
+    /// Check that the circuit breaker can trip and recover
+    pub fn validate(&self) -> Result<(), String> {
+        if self.consecutive_failures == 0 || self.min_requests == 0 || self.half_open_requests == 0
+        {
+            return Err("Circuit breaker thresholds must be above 0".to_string());
+        }
+        if !(1..=100).contains(&self.error_rate) {
+            return Err(
+                "Circuit breaker error_rate must be a percentage from 1 to 100".to_string(),
+            );
+        }
+        if self.window == 0 || self.cooldown == 0 {
+            return Err("Circuit breaker window and cooldown must be above 0".to_string());
+        }
+        Ok(())
+    }
+}
+
+/// Failure of an attempt that a retry policy can retry
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RetryOn {
+    /// The connection could not be made, the request never reached the upstream
+    ConnectFailure,
+    /// The upstream took too long to answer
+    Timeout,
+    /// The upstream closed or broke the connection
+    Reset,
+}
+
+fn default_max_retries() -> usize {
+    2
+}
+
+fn default_retry_on() -> Vec<RetryOn> {
+    vec![RetryOn::ConnectFailure]
+}
+
+fn default_idempotent_only() -> bool {
+    true
+}
+
+fn default_retry_budget() -> u8 {
+    20
+}
+
+/// Most retries the proxy can make for a single request
+pub const MAX_RETRIES: usize = 10;
+
+/// Which failed requests are sent again, to another upstream when the pool has one
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct RetryPolicy {
+    /// Retries after the first attempt
+    #[serde(default = "default_max_retries")]
+    pub max_retries: usize,
+    #[serde(default = "default_retry_on")]
+    pub retry_on: Vec<RetryOn>,
+    /// Upstream response statuses that are retried, e.g. 502 and 503
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub status_codes: Vec<u16>,
+    /// Only retry idempotent methods, except for connection failures
+    #[serde(default = "default_idempotent_only")]
+    pub idempotent_only: bool,
+    /// Percentage of requests that may be retried
+    #[serde(default = "default_retry_budget")]
+    pub budget: u8,
+    /// Seconds to wait for the upstream to answer each attempt
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timeout: Option<u64>,
+}
+
+impl RetryPolicy {
+    /// Check that the policy is within what the proxy can retry
+    pub fn validate(&self) -> Result<(), String> {
+        if self.max_retries > MAX_RETRIES {
+            return Err(format!("Retry max_retries must be at most {}", MAX_RETRIES));
+        }
+        if self.budget > 100 {
+            return Err("Retry budget must be a percentage from 0 to 100".to_string());
+        }
+        if self.timeout == Some(0) {
+            return Err("Retry timeout must be above 0".to_string());
+        }
+        if let Some(status) = self
+            .status_codes
+            .iter()
+            .find(|status| !(100..=599).contains(*status))
+        {
+            return Err(format!("Invalid retry status code {}", status));
+        }
+        Ok(())
+    }
+}
+
+fn default_sticky_cookie() -> String {
+    "proxy_affinity".to_string()
+}
+
+/// Session affinity of a pool, keeping a client on the upstream it first reached
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct StickyConfig {
+    /// Signed cookie naming the upstream, issued by the proxy
+    #[serde(default = "default_sticky_cookie")]
+    pub cookie: String,
+    /// Hash this `header:<name>`, `cookie:<name>` or `ip` instead of issuing a cookie
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hash_on: Option<String>,
+    /// Seconds the browser keeps the cookie, until it is closed when unset
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_age: Option<u64>,
+}
+
+impl StickyConfig {
+    /// Check that the cookie can be set
+    pub fn validate(&self) -> Result<(), String> {
+        let valid = !self.cookie.is_empty()
+            && self
+                .cookie
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '.'));
+        if !valid {
+            return Err(format!("Invalid sticky cookie name {}", self.cookie));
+        }
+        Ok(())
+    }
+}
+
+/// Pool of backends shared by mappings and path rules, used instead of `to` when not empty
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub struct PoolConfig {
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub upstreams: Vec<Upstream>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub load_balancing: Option<LoadBalancing>,
+    /// Also applies to a single `to` backend, which is then checked like a pool of one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub health_check: Option<HealthCheckConfig>,
+    /// Also applies to a single `to` backend, like `health_check`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub circuit_breaker: Option<CircuitBreakerConfig>,
+    /// Also applies to a single `to` backend, which is then retried itself
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub retry: Option<RetryPolicy>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sticky: Option<StickyConfig>,
+}
+
+impl PoolConfig {
+    /// Pool serving a route with backend `to`, `None` when requests go straight to `to`
+    pub fn effective(&self, to: &str) -> Option<PoolConfig> {
+        if !self.upstreams.is_empty() {
+            return Some(self.clone());
+        }
+
+        // A health checked, circuit broken or retried single backend is a pool of one
+        let pool_of_one =
+            self.health_check.is_some() || self.circuit_breaker.is_some() || self.retry.is_some();
+        if pool_of_one && !to.is_empty() {
+            return Some(PoolConfig {
+                upstreams: vec![Upstream {
+                    addr: to.to_string(),
+                    weight: 1,
+                }],
+                ..self.clone()
+            });
+        }
+
+        None
+    }
+
+    /// Check that the pool can be used for routing
+    pub fn validate(&self) -> Result<(), String> {
+        for upstream in &self.upstreams {
+            if upstream.addr.trim().is_empty() {
+                return Err("Upstream address must not be empty".to_string());
+            }
+            if upstream.weight == 0 {
+                return Err(format!(
+                    "Upstream {} must have a weight above 0",
+                    upstream.addr
+                ));
+            }
+        }
+
+        if let Some(health_check) = &self.health_check {
+            health_check.validate()?;
+        }
+        if let Some(circuit_breaker) = &self.circuit_breaker {
+            circuit_breaker.validate()?;
+        }
+        if let Some(retry) = &self.retry {
+            retry.validate()?;
+        }
+
+        let hash_on = self
+            .load_balancing
+            .as_ref()
+            .and_then(|lb| lb.hash_on.as_ref());
+        let sticky_hash_on = self
+            .sticky
+            .as_ref()
+            .and_then(|sticky| sticky.hash_on.as_ref());
+        for hash_on in hash_on.into_iter().chain(sticky_hash_on) {
+            validate_hash_on(hash_on)?;
+        }
+        if let Some(sticky) = &self.sticky {
+            sticky.validate()?;
+        }
+
+        Ok(())
+    }
+}
+
+fn validate_hash_on(hash_on: &str) -> Result<(), String> {
+    let valid = match hash_on.split_once(':') {
+        Some(("header" | "cookie", name)) => !name.is_empty(),
+        _ => hash_on == "ip",
+    };
+    if !valid {
+        return Err(format!(
+            "Invalid hash_on {}, expected ip, header:<name> or cookie:<name>",
+            hash_on
+        ));
+    }
+    Ok(())
+}
+
+fn default_enabled() -> bool {
+    true
+}
+
+fn default_retry_after() -> u64 {
+    3600
+}
+
+fn default_hsts_max_age() -> u64 {
+    31536000
+}
+
+/// `Strict-Transport-Security` header sent with the HTTPS responses of a route
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct HstsConfig {
+    /// Seconds browsers only use HTTPS for the host
+    #[serde(default = "default_hsts_max_age")]
+    pub max_age: u64,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub include_subdomains: bool,
+    /// Allow the host on the browsers' preload lists
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub preload: bool,
+}
+
+impl Default for HstsConfig {
+    fn default() -> Self {
+        Self {
+            max_age: default_hsts_max_age(),
+            include_subdomains: false,
+            preload: false,
+        }
+    }
+}
+
+impl HstsConfig {
+    /// Check the requirements of the preload lists
+    pub fn validate(&self) -> Result<(), String> {
+        if self.preload && (!self.include_subdomains || self.max_age < default_hsts_max_age()) {
+            return Err(format!(
+                "HSTS preload requires include_subdomains and a max_age of at least {}",
+                default_hsts_max_age()
+            ));
+        }
+        Ok(())
+    }
+
+    /// Value of the `Strict-Transport-Security` header
+    pub fn header_value(&self) -> String {
+        let mut value = format!("max-age={}", self.max_age);
+        if self.include_subdomains {
+            value.push_str("; includeSubDomains");
+        }
+        if self.preload {
+            value.push_str("; preload");
+        }
+        value
+    }
+}
+
+fn default_redirect_status() -> u16 {
+    301
+}
+
+/// Redirect answered by a route instead of proxying to a backend
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct RedirectConfig {
+    /// URL or host to redirect to, `{0}`, `{1}`.. are replaced like in `to`.
+    /// Without a scheme, the scheme of the request is kept
+    pub to: String,
+    /// 301 or 308 for permanent redirects, 302, 303 or 307 for temporary ones
+    #[serde(default = "default_redirect_status")]
+    pub status: u16,
+    /// Append the request path and query to `to`
+    #[serde(default = "default_enabled")]
+    pub preserve_path: bool,
+}
+
+impl RedirectConfig {
+    /// Check that the redirect can be answered
+    pub fn validate(&self) -> Result<(), String> {
+        if self.to.trim().is_empty() {
+            return Err("Redirect has no target".to_string());
+        }
+        if !matches!(self.status, 301 | 302 | 303 | 307 | 308) {
+            return Err(format!("Invalid redirect status {}", self.status));
+        }
+        Ok(())
+    }
+}
+
+/// Rewrite of the request path, applied before path rules are matched
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct RewriteRule {
+    /// Regular expression matched against the path
+    pub pattern: String,
+    /// New path, may use `$1` captures and add query parameters
+    pub replacement: String,
+    #[serde(skip)]
+    regex: OnceLock<Option<Regex>>,
+}
+
+impl RewriteRule {
+    /// Check that the pattern compiles
+    pub fn validate(&self) -> Result<(), String> {
+        Regex::new(&self.pattern)
+            .map(|_| ())
+            .map_err(|e| format!("Invalid rewrite pattern {}: {}", self.pattern, e))
+    }
+
+    // Compiled once on first use, an invalid pattern never matches
+    pub fn regex(&self) -> Option<&Regex> {
+        self.regex
+            .get_or_init(|| match Regex::new(&self.pattern) {
+                Ok(regex) => Some(regex),
+                Err(e) => {
+                    println!("Invalid rewrite pattern {}: {}", self.pattern, e);
+                    None
+                }
+            })
+            .as_ref()
+    }
+}
+
+/// Maintenance mode of a route or of every route, answering with the maintenance page
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct MaintenanceConfig {
+    #[serde(default = "default_enabled")]
+    pub enabled: bool,
+    /// Seconds sent in `Retry-After`
+    #[serde(default = "default_retry_after")]
+    pub retry_after: u64,
+    /// Client addresses or CIDR ranges still reaching the backend
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub allow_ips: Vec<String>,
+    /// Value of the `X-Maintenance-Bypass` header or `maintenance_bypass` cookie
+    /// that still reaches the backend
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub bypass_token: Option<String>,
+}
+
+impl Default for MaintenanceConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            retry_after: default_retry_after(),
+            allow_ips: Vec::new(),
+            bypass_token: None,
+        }
+    }
+}
+
+impl MaintenanceConfig {
+    /// Check that the allow-list can be parsed
+    pub fn validate(&self) -> Result<(), String> {
+        for entry in &self.allow_ips {
+            let (ip, prefix) = match entry.split_once('/') {
+                Some((ip, prefix)) => (ip, Some(prefix)),
+                None => (entry.as_str(), None),
+            };
+            let ip: std::net::IpAddr = ip
+                .trim()
+                .parse()
+                .map_err(|_| format!("Invalid maintenance allow_ips entry {}", entry))?;
+            let max = if ip.is_ipv4() { 32 } else { 128 };
+            if let Some(prefix) = prefix
+                && !prefix.parse::<u8>().is_ok_and(|prefix| prefix <= max)
+            {
+                return Err(format!("Invalid maintenance allow_ips entry {}", entry));
+            }
+        }
+        if self.bypass_token.as_ref().is_some_and(|t| t.is_empty()) {
+            return Err("Maintenance bypass_token must not be empty".to_string());
+        }
+        Ok(())
+    }
+}
+
+/// Page served instead of the proxy's own error response
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct ErrorPage {
+    /// File the page is read from each time it is served, within ERROR_PAGES_DIR
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub file: Option<String>,
+    /// Inline page
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub template: Option<String>,
+    /// `text/html; charset=utf-8` when unset
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub content_type: Option<String>,
+}
+
+/// Error pages by status code, e.g. `502`, or by class, `5xx` and `4xx`
+pub type ErrorPages = BTreeMap<String, ErrorPage>;
+
+const DEFAULT_ERROR_PAGES_DIR: &str = "error_pages";
+
+/// Directory error page files are served from, ERROR_PAGES_DIR
+pub fn error_pages_dir() -> PathBuf {
+    PathBuf::from(
+        std::env::var("ERROR_PAGES_DIR").unwrap_or_else(|_| DEFAULT_ERROR_PAGES_DIR.to_string()),
+    )
+}
+
+/// Path of an error page file, relative names are taken from the error pages
+/// directory. Anything that leaves the directory is refused, so routes cannot
+/// serve other files of the host such as private keys
+pub fn error_page_path(file: &str) -> Result<PathBuf, String> {
+    let dir = error_pages_dir();
+    let path = dir.join(file);
+    let escapes = path
+        .components()
+        .any(|component| component == Component::ParentDir);
+    if escapes || !path.starts_with(&dir) || path == dir {
+        return Err(format!(
+            "Error page file {} must be within {}",
+            file,
+            dir.display()
+        ));
+    }
+    Ok(path)
+}
+
+/// Read an error page file, after resolving symlinks it must still be within
+/// the error pages directory
+pub fn read_error_page(file: &str) -> std::io::Result<String> {
+    let path = error_page_path(file).map_err(std::io::Error::other)?;
+    let dir = error_pages_dir().canonicalize()?;
+    let path = path.canonicalize()?;
+    if !path.starts_with(&dir) {
+        return Err(std::io::Error::other(format!(
+            "{} is outside of {}",
+            path.display(),
+            dir.display()
+        )));
+    }
+    std::fs::read_to_string(path)
+}
+
+/// Check the status keys and that every page has exactly one source
+pub fn validate_error_pages(pages: &ErrorPages) -> Result<(), String> {
+    for (key, page) in pages {
+        let valid = match key.as_str() {
+            "4xx" | "5xx" => true,
+            code => code
+                .parse::<u16>()
+                .is_ok_and(|code| (400..=599).contains(&code)),
+        };
+        if !valid {
+            return Err(format!(
+                "Invalid error page status {}, expected a code from 400 to 599, 4xx or 5xx",
+                key
+            ));
+        }
+        if page.file.is_some() == page.template.is_some() {
+            return Err(format!(
+                "Error page {} needs either a file or a template",
+                key
+            ));
+        }
+        if let Some(file) = &page.file {
+            error_page_path(file)?;
+        }
+    }
+    Ok(())
+}
+
+/// Path based routing rule within a host
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct PathRule {
+    pub path: String,
+    #[serde(default, rename = "match")]
+    pub match_type: PathMatch,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub to: String,
+    #[serde(flatten)]
+    pub pool: PoolConfig,
+    /// Remove the matched path before forwarding
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub strip_prefix: bool,
+    /// Replace the matched path before forwarding, regex rules may use `$1` captures
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rewrite: Option<String>,
+    /// Replaces the maintenance mode of the mapping for this path
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub maintenance: Option<MaintenanceConfig>,
+    /// Added to the error pages of the mapping, replacing those for the same status
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub error_pages: ErrorPages,
+    /// Replaces the HTTPS redirect of the mapping for this path
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub force_https: Option<bool>,
+    /// Replaces the HSTS header of the mapping for this path
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hsts: Option<HstsConfig>,
+    /// Redirect instead of proxying, the path it preserves is the one that
+    /// would be forwarded
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub redirect: Option<RedirectConfig>,
+    #[serde(skip)]
+    regex: OnceLock<Option<Regex>>,
+}
+
+impl PathRule {
+    /// Check that the rule can be used for routing
+    pub fn validate(&self) -> Result<(), String> {
+        if self.to.trim().is_empty() && self.pool.upstreams.is_empty() && self.redirect.is_none() {
+            return Err(format!("Path rule {} has no backend", self.path));
+        }
+        if let Some(redirect) = &self.redirect {
+            redirect.validate()?;
+        }
+        self.pool.validate()?;
+        if let Some(maintenance) = &self.maintenance {
+            maintenance.validate()?;
+        }
+        validate_error_pages(&self.error_pages)?;
+        if let Some(hsts) = &self.hsts {
+            hsts.validate()?;
+        }
+        match self.match_type {
+            PathMatch::Regex => Regex::new(&self.path)
+                .map(|_| ())
+                .map_err(|e| format!("Invalid path regex {}: {}", self.path, e)),
+            _ if !self.path.starts_with('/') => {
+                Err(format!("Path {} must start with '/'", self.path))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    // Compiled once on first use, an invalid pattern never matches
+    pub fn regex(&self) -> Option<&Regex> {
+        self.regex
+            .get_or_init(|| match Regex::new(&self.path) {
+                Ok(regex) => Some(regex),
+                Err(e) => {
+                    println!("Invalid path regex {}: {}", self.path, e);
+                    None
+                }
+            })
+            .as_ref()
+    }
+}
 
 /// Represents a server mapping from domain to backend
+///
+/// `from` is an exact host, a wildcard such as `*.example.com` or, when it
+/// starts with `~`, a regular expression matched against the whole host
 #[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct ServerMapping {
+    /// Optional in the body of a route API `PUT`, which names the host in its URL
+    #[serde(default)]
     pub from: String,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
     pub to: String,
+    #[serde(flatten)]
+    pub pool: PoolConfig,
+    /// Ordered path rules, the first matching rule picks the backend instead of `to`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub paths: Vec<PathRule>,
+    /// Replaces the global maintenance mode for this host
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub maintenance: Option<MaintenanceConfig>,
+    /// Pages served when the proxy fails to get a response from the backend
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub error_pages: ErrorPages,
+    /// Redirect plain HTTP requests to HTTPS, by default when a valid certificate
+    /// covers the host
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub force_https: Option<bool>,
+    /// Sent with HTTPS responses
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hsts: Option<HstsConfig>,
+    /// Redirect requests for the host instead of proxying them, unless a path
+    /// rule matches
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub redirect: Option<RedirectConfig>,
+    /// Path rewrites, the first matching rule rewrites the request
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub rewrites: Vec<RewriteRule>,
+    #[serde(skip)]
+    host_regex: OnceLock<Option<Regex>>,
+}
+
+impl ServerMapping {
+    /// Create a mapping sending every request for `from` to `to`
+    pub fn new(from: &str, to: &str) -> Self {
+        Self {
+            from: from.to_string(),
+            to: to.to_string(),
+            pool: PoolConfig::default(),
+            paths: Vec::new(),
+            maintenance: None,
+            error_pages: ErrorPages::new(),
+            force_https: None,
+            hsts: None,
+            redirect: None,
+            rewrites: Vec::new(),
+            host_regex: OnceLock::new(),
+        }
+    }
+
+    /// Address of every backend of the mapping: `to`, the pool upstreams and
+    /// those of the path rules
+    pub fn backends(&self) -> Vec<&str> {
+        let rules = self.paths.iter().map(|rule| (&rule.to, &rule.pool));
+        std::iter::once((&self.to, &self.pool))
+            .chain(rules)
+            .flat_map(|(to, pool)| {
+                std::iter::once(to.as_str()).chain(pool.upstreams.iter().map(|u| u.addr.as_str()))
+            })
+            .filter(|addr| !addr.trim().is_empty())
+            .collect()
+    }
+
+    /// Check that the mapping can be used for routing
+    pub fn validate(&self) -> Result<(), String> {
+        if self.from.trim().is_empty() {
+            return Err("Mapping has no host".to_string());
+        }
+        if let Some(pattern) = self.from.strip_prefix('~') {
+            Regex::new(pattern).map_err(|e| format!("Invalid host regex {}: {}", pattern, e))?;
+        }
+        if self.to.trim().is_empty() && self.pool.upstreams.is_empty() && self.redirect.is_none() {
+            return Err(format!("Mapping {} has no backend", self.from));
+        }
+        self.pool.validate()?;
+        self.paths.iter().try_for_each(PathRule::validate)?;
+        if let Some(maintenance) = &self.maintenance {
+            maintenance.validate()?;
+        }
+        validate_error_pages(&self.error_pages)?;
+        if let Some(hsts) = &self.hsts {
+            hsts.validate()?;
+        }
+        if let Some(redirect) = &self.redirect {
+            redirect.validate()?;
+        }
+        self.rewrites.iter().try_for_each(RewriteRule::validate)
+    }
+
+    /// Whether `from` is a regular expression rather than a host name
+    pub fn is_regex_host(&self) -> bool {
+        self.from.starts_with('~')
+    }
+
+    // Compiled once on first use, anchored so it matches the whole host
+    pub fn host_regex(&self) -> Option<&Regex> {
+        let pattern = self.from.strip_prefix('~')?;
+        self.host_regex
+            .get_or_init(|| match Regex::new(&format!("^(?i:{})$", pattern)) {
+                Ok(regex) => Some(regex),
+                Err(e) => {
+                    println!("Invalid host regex {}: {}", pattern, e);
+                    None
+                }
+            })
+            .as_ref()
+    }
 }
 
 /// Root configuration structure
 #[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct Configuration {
+    /// Raised by every saved change, `0` in files written before versioning
+    #[serde(default)]
+    pub version: u64,
     pub servers: Vec<ServerMapping>,
+    /// Maintenance mode of every host, unless a mapping has its own
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub maintenance: Option<MaintenanceConfig>,
 }
 
 impl Configuration {
     /// Create a new empty configuration
     pub fn new() -> Self {
-        Self { servers: vec![] }
+        Self {
+            version: 0,
+            servers: vec![],
+            maintenance: None,
+        }
+    }
+
+    /// Check that every mapping and the global maintenance mode can be used,
+    /// and that no host is mapped twice
+    pub fn validate(&self) -> Result<(), String> {
+        let mut hosts = std::collections::HashSet::new();
+        for mapping in &self.servers {
+            mapping.validate()?;
+            if !hosts.insert(mapping.from.as_str()) {
+                return Err(format!("Host {} is mapped more than once", mapping.from));
+            }
+        }
+        self.maintenance
+            .as_ref()
+            .map_or(Ok(()), MaintenanceConfig::validate)
     }
 
     /// Convert configuration to HashMap for easier lookup
     pub fn to_hashmap(&self) -> ConfigStore {
         let mut result = HashMap::new();
         self.servers.iter().for_each(|srv| {
-            result.insert(srv.from.clone(), srv.to.clone());
+            result.insert(srv.from.clone(), srv.clone());
         });
         result
     }
 
     /// Create configuration from HashMap
     pub fn from_hashmap(map: &ConfigStore) -> Self {
-        let servers = map
-            .iter()
-            .map(|(from, to)| ServerMapping {
-                from: from.clone(),
-                to: to.clone(),
-            })
-            .collect();
+        let mut servers: Vec<ServerMapping> = map.values().cloned().collect();
+        servers.sort_by(|a, b| a.from.cmp(&b.from));
 
-        Self { servers }
+        Self {
+            version: 0,
+            servers,
+            maintenance: None,
+        }
     }
 }
@@ -1,20 +1,46 @@
-use std::sync::{Arc, Mutex};
+use std::{
+    collections::HashMap,
+    fs::Permissions,
+    os::unix::fs::PermissionsExt,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+};
 
-use pingora::{listeners::tls::TlsSettings, server::Server};
+use pingora::{server::Server, services::listening::Service};
 
 mod cert;
 mod config;
 mod proxy;
 mod services;
 
+use crate::services::cert_renewal::{CertRenewalService, RenewalState};
+use crate::services::cert_watcher::CertWatcherService;
+use crate::services::config_watcher::ConfigWatcherService;
 use crate::services::docker_swarm::SwarmDiscoveryService;
-use cert::certbot::find_certbot_certs;
-use config::file_manager::get_config;
+use crate::services::health_check::HealthCheckService;
+use cert::acme::AcmeChallenges;
+use cert::certbot::{CERTBOT_DIR, CERTBOT_LIVE_DIR, ISSUER_OUTPUT_DIR};
+use cert::issuer::CertificateIssuer;
+use cert::resolver::SniCertResolver;
+use cert::store::CertStore;
+use config::audit::AuditLog;
+use config::file_manager::{get_config, get_maintenance};
+use proxy::auth::{ManagerAuth, Organizations};
+use proxy::balancer::UpstreamPools;
+use proxy::fallback::Fallback;
 use proxy::http::HttpProxy;
 use proxy::https::HttpsProxy;
+use proxy::maintenance::MaintenanceState;
 use proxy::manager::ManagerProxy;
+use proxy::tls::{TlsConnections, TlsTerminator};
 use proxy::utils::clean_backend_address;
 
+/// Loopback listener the HTTPS proxy serves decrypted traffic on
+const HTTPS_INTERNAL_ADDR: &str = "127.0.0.1:4443";
+
+/// Loopback listener the manager serves decrypted traffic on
+const MANAGER_INTERNAL_ADDR: &str = "127.0.0.1:4481";
+
 fn fix_config_file() {
     // Load the configuration
     let config_path = "config.json";
@@ -38,11 +64,17 @@ fn fix_config_file() {
     // Clean up the addresses
     let mut changes_made = false;
     for mapping in &mut config.servers {
-        let cleaned_to = clean_backend_address(&mapping.to);
-        if cleaned_to != mapping.to {
-            println!("Cleaning address: {} -> {}", mapping.to, cleaned_to);
-            mapping.to = cleaned_to;
-            changes_made = true;
+        // Pool-only mappings have no `to`
+        let addresses = std::iter::once(&mut mapping.to)
+            .filter(|to| !to.is_empty())
+            .chain(mapping.pool.upstreams.iter_mut().map(|u| &mut u.addr));
+        for address in addresses {
+            let cleaned = clean_backend_address(address);
+            if cleaned != *address {
+                println!("Cleaning address: {} -> {}", address, cleaned);
+                *address = cleaned;
+                changes_made = true;
+            }
         }
     }
 
@@ -69,6 +101,14 @@ fn main() {
     // Initialize logging
     env_logger::init();
 
+    // Both ring and aws-lc-rs are linked in, so rustls cannot pick a provider on its own
+    if rustls::crypto::aws_lc_rs::default_provider()
+        .install_default()
+        .is_err()
+    {
+        println!("A rustls crypto provider was already installed");
+    }
+
     // Fix the configuration file first
     fix_config_file();
 
@@ -79,103 +119,218 @@ fn main() {
     let mut server = Server::new(None).unwrap();
     server.bootstrap();
 
-    // Extract domain names for certificate lookup
-    let domains: Vec<String> = match config_store.lock() {
-        Ok(store) => store.keys().cloned().collect(),
-        Err(e) => {
-            println!(
-                "Error locking config store when extracting domains: {:?}",
-                e
-            );
-            // Provide an empty vector as fallback
-            Vec::new()
-        }
-    };
-    println!("Configured domains: {:?}", domains);
+    // Every TLS listener resolves its certificate by SNI from the same store,
+    // which is reloaded whenever certificates are issued or renewed on disk
+    let cert_resolver = Arc::new(SniCertResolver::new());
+    if let Ok(domain) = std::env::var("TLS_DEFAULT_DOMAIN") {
+        cert_resolver.set_default(&domain);
+    }
+    let cert_store = Arc::new(CertStore::new(
+        cert_resolver.clone(),
+        vec![
+            PathBuf::from(CERTBOT_LIVE_DIR),
+            PathBuf::from(ISSUER_OUTPUT_DIR),
+        ],
+    ));
+    cert_store.reload();
 
-    // Find certificates for domains
-    let certs = find_certbot_certs(&domains);
+    // Certificates are issued in-process, HTTP-01 challenges are answered by the HTTP proxy
+    let acme_challenges = AcmeChallenges::new();
+    let issuer = Arc::new(
+        CertificateIssuer::new(CERTBOT_DIR, ISSUER_OUTPUT_DIR, acme_challenges.clone())
+            .expect("Failed to initialize certificate issuer"),
+    );
+    let renewal_state: RenewalState = Arc::new(Mutex::new(HashMap::new()));
+    let tls_connections = TlsConnections::new();
+
+    // Load balancing state is shared so both proxies spread requests over the same pools
+    let upstream_pools = UpstreamPools::default();
+    proxy::affinity::init();
+
+    // Hosts without a mapping get the built-in 404 page unless configured otherwise
+    let fallback = Fallback::from_env();
+    let maintenance: MaintenanceState = Arc::new(Mutex::new(get_maintenance()));
 
     // Create HTTP proxy service
     let mut http_service = pingora_proxy::http_proxy_service(
         &server.configuration,
         HttpProxy {
             servers: config_store.clone(),
+            pools: upstream_pools.clone(),
+            fallback: fallback.clone(),
+            maintenance: maintenance.clone(),
+            challenges: acme_challenges.clone(),
+            cert_store: cert_store.clone(),
+            connections: tls_connections.clone(),
         },
     );
     http_service.add_tcp("0.0.0.0:80");
 
-    // Create HTTPS proxy service
+    // Create HTTPS proxy service, fed with decrypted traffic by the TLS terminator
     let mut https_service = pingora_proxy::http_proxy_service(
         &server.configuration,
         HttpsProxy {
             servers: config_store.clone(),
+            pools: upstream_pools.clone(),
+            fallback: fallback.clone(),
+            maintenance: maintenance.clone(),
+            connections: tls_connections.clone(),
         },
     );
+    https_service.add_tcp(HTTPS_INTERNAL_ADDR);
+
+    // Manager requests need a bearer token or a client certificate on the TLS
+    // listener, without either only clients on this host are accepted
+    let manager_client_ca = std::env::var("MANAGER_CLIENT_CA").ok();
+    // Organization tokens are limited to what swarm discovery finds labelled for them
+    let organizations = Organizations::default();
+    let manager_auth = ManagerAuth::from_env(manager_client_ca.is_some(), organizations.clone());
+    if !manager_auth.is_configured() {
+        println!(
+            "Warning: No manager credentials configured, the manager API only accepts local clients"
+        );
+    }
+
+    // Route and certificate changes from every source end up in the audit log
+    let audit_log = AuditLog::from_env();
 
     // Create manager service
     let mut manager_service = pingora_proxy::http_proxy_service(
         &server.configuration,
         ManagerProxy {
             servers: config_store.clone(),
+            cert_store: cert_store.clone(),
+            issuer: issuer.clone(),
+            renewals: renewal_state.clone(),
+            pools: upstream_pools.clone(),
+            maintenance: maintenance.clone(),
+            auth: manager_auth,
+            connections: tls_connections.clone(),
+            audit: audit_log.clone(),
         },
     );
 
-    // Always configure the manager service on HTTP port 81
-    manager_service.add_tcp("0.0.0.0:81");
-    println!("Manager service (HTTP) configured on port 81");
+    // The plaintext manager listens on MANAGER_BIND, an address or `unix:/path`
+    let manager_bind = std::env::var("MANAGER_BIND").unwrap_or_else(|_| "0.0.0.0:81".to_string());
+    match manager_bind.strip_prefix("unix:") {
+        Some(path) => {
+            // Left over by a previous run, binding would fail otherwise
+            let _ = std::fs::remove_file(path);
+            manager_service.add_uds(path, Some(Permissions::from_mode(0o660)));
+        }
+        None => manager_service.add_tcp(&manager_bind),
+    }
+    manager_service.add_tcp(MANAGER_INTERNAL_ADDR);
+    println!("Manager service (HTTP) configured on {}", manager_bind);
+
+    // Add all services to the server
+    server.add_service(http_service);
+    server.add_service(manager_service);
 
-    // Additionally, set up HTTPS on a different port if certificates are available
-    if !certs.is_empty() {
-        // Use the first certificate for the manager interface
-        let mgr_cert = &certs[0];
+    // HTTPS listeners start even without certificates so that certificates
+    // issued later are served as soon as they are picked up
+    if cert_resolver.is_empty() {
         println!(
-            "Also setting up TLS for manager on port 8443: {}",
-            mgr_cert.domain
+            "Warning: No TLS certificates found yet. HTTPS handshakes will fail until one is issued."
         );
+    }
 
-        match TlsSettings::intermediate(&mgr_cert.cert_path, &mgr_cert.key_path) {
-            Ok(tls_settings) => {
-                // Use a different port (8443) for HTTPS manager access
-                manager_service.add_tls_with_settings("0.0.0.0:8443", None, tls_settings);
-                println!("Manager TLS configured successfully on port 8443");
-            }
-            Err(e) => {
-                println!("Error setting up TLS for manager: {}", e);
-            }
-        };
+    match TlsTerminator::new(&cert_resolver, HTTPS_INTERNAL_ADDR, tls_connections.clone()) {
+        Ok(terminator) => {
+            let mut tls_service = Service::new("HTTPS TLS terminator".to_string(), terminator);
+            tls_service.add_tcp("0.0.0.0:443");
+            server.add_service(tls_service);
+            server.add_service(https_service);
+            println!("HTTPS service configured on port 443");
+        }
+        Err(e) => {
+            println!("Error setting up TLS for HTTPS: {}", e);
+        }
     }
 
-    // Configure HTTPS with domain-specific certificates
-    if !certs.is_empty() {
-        for cert in &certs {
-            println!("Setting up TLS for domain: {}", cert.domain);
-
-            // Create TLS settings
-            let tls_settings = match TlsSettings::intermediate(&cert.cert_path, &cert.key_path) {
-                Ok(settings) => settings,
-                Err(e) => {
-                    println!("Error creating TLS settings for {}: {}", cert.domain, e);
-                    continue;
-                }
-            };
-
-            // Add TLS endpoint
-            https_service.add_tls_with_settings("0.0.0.0:443", None, tls_settings);
+    // HTTPS manager access on MANAGER_TLS_BIND, verifying client certificates
+    // issued by MANAGER_CLIENT_CA when it is set
+    let manager_tls_bind =
+        std::env::var("MANAGER_TLS_BIND").unwrap_or_else(|_| "0.0.0.0:8443".to_string());
+    let manager_terminator = match &manager_client_ca {
+        Some(client_ca) => TlsTerminator::with_client_auth(
+            &cert_resolver,
+            client_ca,
+            MANAGER_INTERNAL_ADDR,
+            tls_connections.clone(),
+        ),
+        None => TlsTerminator::new(
+            &cert_resolver,
+            MANAGER_INTERNAL_ADDR,
+            tls_connections.clone(),
+        ),
+    };
+    match manager_terminator {
+        Ok(terminator) => {
+            let mut tls_service = Service::new("Manager TLS terminator".to_string(), terminator);
+            tls_service.add_tcp(&manager_tls_bind);
+            server.add_service(tls_service);
+            println!(
+                "Manager TLS configured successfully on {}",
+                manager_tls_bind
+            );
+        }
+        Err(e) => {
+            println!("Error setting up TLS for manager: {}", e);
         }
-    } else {
-        println!("Warning: No TLS certificates found. HTTPS service will not be available.");
     }
 
-    // Add all services to the server
-    server.add_service(http_service);
+    // Pick up new and renewed certificates without restarting
+    let cert_reload_interval = std::env::var("CERT_RELOAD_INTERVAL")
+        .ok()
+        .and_then(|v| v.parse::<u64>().ok())
+        .unwrap_or(10);
+    server.add_service(CertWatcherService::new(
+        cert_store.clone(),
+        cert_reload_interval,
+    ));
 
-    // Only add HTTPS service if we have certificates
-    if !certs.is_empty() {
-        server.add_service(https_service);
-    }
+    // Apply edits made to config.json on disk, also on SIGHUP
+    let config_reload_interval = std::env::var("CONFIG_RELOAD_INTERVAL")
+        .ok()
+        .and_then(|v| v.parse::<u64>().ok())
+        .unwrap_or(2);
+    server.add_service(ConfigWatcherService::new(
+        config_store.clone(),
+        maintenance.clone(),
+        config_reload_interval,
+        audit_log.clone(),
+    ));
 
-    server.add_service(manager_service);
+    // Renew expiring certificates and request missing ones for mapped domains
+    let cert_renewal_interval = std::env::var("CERT_RENEWAL_INTERVAL")
+        .ok()
+        .and_then(|v| v.parse::<u64>().ok())
+        .unwrap_or(600);
+    let cert_renew_before_days = std::env::var("CERT_RENEW_BEFORE_DAYS")
+        .ok()
+        .and_then(|v| v.parse::<u64>().ok())
+        .unwrap_or(30);
+    // Off unless asked for, issuance counts against the CA's rate limits
+    let on_demand_tls = std::env::var("ON_DEMAND_TLS")
+        .map(|v| v.to_lowercase() == "true")
+        .unwrap_or(false);
+    server.add_service(CertRenewalService::new(
+        config_store.clone(),
+        issuer.clone(),
+        cert_store.clone(),
+        renewal_state,
+        cert_renewal_interval,
+        cert_renew_before_days,
+        on_demand_tls,
+        audit_log.clone(),
+    ));
+
+    // Active health checks take failing upstreams out of their pools
+    server.add_service(HealthCheckService::new(
+        config_store.clone(),
+        upstream_pools.clone(),
+    ));
 
     let docker_endpoint = std::env::var("DOCKER_ENDPOINT")
         .unwrap_or_else(|_| "unix:///var/run/docker.sock".to_string());
@@ -196,6 +351,8 @@ fn main() {
             &docker_endpoint,
             networks,
             30, // Check every 30 seconds
+            audit_log.clone(),
+            organizations,
         ) {
             Ok(swarm_service) => {
                 println!("Adding Docker Swarm discovery service");
@@ -0,0 +1,57 @@
+// src/proxy/affinity.rs
+use std::sync::OnceLock;
+
+use base64::{Engine, engine::general_purpose::URL_SAFE_NO_PAD};
+use ring::{
+    hmac,
+    rand::{SecureRandom, SystemRandom},
+};
+
+use crate::config::model::StickyConfig;
+
+static KEY: OnceLock<hmac::Key> = OnceLock::new();
+
+// Key signing affinity cookies, random unless AFFINITY_SECRET is set, in which
+// case cookies stay valid across restarts and proxy replicas
+fn key() -> &'static hmac::Key {
+    KEY.get_or_init(|| match std::env::var("AFFINITY_SECRET") {
+        Ok(secret) if !secret.is_empty() => hmac::Key::new(hmac::HMAC_SHA256, secret.as_bytes()),
+        _ => {
+            // A predictable key would let clients forge cookies for any upstream
+            let mut secret = [0u8; 32];
+            SystemRandom::new()
+                .fill(&mut secret)
+                .expect("Error generating affinity cookie key");
+            hmac::Key::new(hmac::HMAC_SHA256, &secret)
+        }
+    })
+}
+
+/// Create the affinity cookie key at startup, so a failure stops the proxy
+/// before it serves anything
+pub fn init() {
+    key();
+}
+
+/// Cookie value naming an upstream: a signature of its configured address,
+/// which only this proxy can produce and which does not reveal the address
+pub fn cookie_value(target: &str) -> String {
+    let signature = hmac::sign(key(), target.as_bytes());
+    URL_SAFE_NO_PAD.encode(&signature.as_ref()[..16])
+}
+
+/// `Set-Cookie` header pinning the client to `target`
+pub fn set_cookie(config: &StickyConfig, target: &str, secure: bool) -> String {
+    let mut cookie = format!(
+        "{}={}; Path=/; HttpOnly; SameSite=Lax",
+        config.cookie,
+        cookie_value(target)
+    );
+    if let Some(max_age) = config.max_age {
+        cookie.push_str(&format!("; Max-Age={}", max_age));
+    }
+    if secure {
+        cookie.push_str("; Secure");
+    }
+    cookie
+}
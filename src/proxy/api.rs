@@ -0,0 +1,685 @@
+// src/proxy/api.rs
+use std::collections::BTreeSet;
+use std::sync::MutexGuard;
+
+use bytes::Bytes;
+use pingora::{Result, http::StatusCode};
+use pingora_http::{Method, ResponseHeader};
+use pingora_proxy::Session;
+use serde::{Deserialize, Serialize};
+use serde_json::{Value, json};
+
+use crate::config::audit::{AuditEntry, AuditFilter, AuditOrigin, parse_timestamp};
+use crate::config::file_manager::{
+    config_version, create_mappings_from_store, list_versions, load_version, save_config,
+    update_config,
+};
+use crate::config::model::{ConfigStore, Configuration, ServerMapping};
+
+use super::auth::{AuthError, Identity};
+use super::manager::ManagerProxy;
+use super::utils::{percent_decode, percent_encode};
+
+/// Prefix of the versioned JSON API of the manager
+pub const API_PREFIX: &str = "/api/v1";
+
+/// Audit entries listed when the request sets no `limit`, and the most it may ask for
+const AUDIT_DEFAULT_LIMIT: usize = 100;
+const AUDIT_MAX_LIMIT: usize = 1000;
+
+/// Response of an API request, errors use the same envelope everywhere:
+/// `{"error": {"code": "not_found", "message": "..."}}`
+struct ApiResponse {
+    status: StatusCode,
+    body: Option<Value>,
+    headers: Vec<(&'static str, String)>,
+}
+
+impl ApiResponse {
+    fn json(status: StatusCode, body: impl Serialize) -> Self {
+        Self {
+            status,
+            body: Some(serde_json::to_value(body).unwrap_or(Value::Null)),
+            headers: Vec::new(),
+        }
+    }
+
+    fn empty(status: StatusCode) -> Self {
+        Self {
+            status,
+            body: None,
+            headers: Vec::new(),
+        }
+    }
+
+    fn error(status: StatusCode, code: &str, message: impl std::fmt::Display) -> Self {
+        Self::json(
+            status,
+            json!({ "error": { "code": code, "message": message.to_string() } }),
+        )
+    }
+
+    fn not_allowed(allow: &str) -> Self {
+        Self::error(
+            StatusCode::METHOD_NOT_ALLOWED,
+            "method_not_allowed",
+            format!("Allowed methods: {}", allow),
+        )
+        .header("Allow", allow)
+    }
+
+    fn not_permitted() -> Self {
+        let error = AuthError::NotPermitted;
+        Self::error(StatusCode::FORBIDDEN, error.code(), error.message())
+    }
+
+    fn header(mut self, name: &'static str, value: impl Into<String>) -> Self {
+        self.headers.push((name, value.into()));
+        self
+    }
+}
+
+/// Whether `path` belongs to the versioned API
+pub fn is_api_path(path: &str) -> bool {
+    path == API_PREFIX
+        || path
+            .strip_prefix(API_PREFIX)
+            .is_some_and(|rest| rest.starts_with('/'))
+}
+
+/// Answer with a JSON body and extra headers
+pub async fn write_json(
+    session: &mut Session,
+    status: StatusCode,
+    body: Value,
+    headers: Vec<(&'static str, String)>,
+) -> Result<bool> {
+    let response = ApiResponse {
+        status,
+        body: Some(body),
+        headers,
+    };
+    write_response(session, response).await
+}
+
+/// Successful response, or the error answered instead
+type ApiResult = std::result::Result<ApiResponse, ApiResponse>;
+
+async fn write_response(session: &mut Session, response: ApiResponse) -> Result<bool> {
+    let body = response
+        .body
+        .map(|body| body.to_string())
+        .unwrap_or_default();
+
+    let mut header = ResponseHeader::build(response.status, None)?;
+    if !body.is_empty() {
+        header.insert_header("Content-Type", "application/json")?;
+    }
+    if response.status.is_success() {
+        header.insert_header("ETag", config_etag())?;
+    }
+    header.insert_header("Content-Length", body.len().to_string())?;
+    header.insert_header("Connection", "close")?;
+    for (name, value) in response.headers {
+        header.insert_header(name, value)?;
+    }
+
+    session
+        .write_response_header(Box::new(header), body.is_empty())
+        .await?;
+    if !body.is_empty() {
+        session
+            .write_response_body(Some(Bytes::from(body)), true)
+            .await?;
+    }
+    session.set_keepalive(None);
+    Ok(true)
+}
+
+// Write the mappings back to config.json. Callers put the previous entry
+// back when this fails, so routing never runs a change that was not saved
+fn save(servers: &ConfigStore) -> std::result::Result<(), ApiResponse> {
+    update_config(create_mappings_from_store(servers)).map_err(|e| {
+        println!("Error updating config file: {}", e);
+        ApiResponse::error(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "internal",
+            format!("Failed to save configuration: {}", e),
+        )
+    })
+}
+
+/// `ETag` of the configuration version in effect, sent back in `If-Match` by writes
+pub fn config_etag() -> String {
+    format!("\"{}\"", config_version())
+}
+
+/// Whether a write may go ahead: it sets no `If-Match` or one naming the
+/// configuration version in effect. Checked with the servers lock held, so no
+/// other write can come in between
+pub fn version_matches(session: &Session) -> bool {
+    let Some(header) = session.req_header().headers.get("if-match") else {
+        return true;
+    };
+    let current = config_version();
+    header.to_str().is_ok_and(|value| {
+        value.split(',').map(str::trim).any(|tag| {
+            tag == "*"
+                || tag
+                    .trim_start_matches("W/")
+                    .trim_matches('"')
+                    .parse::<u64>()
+                    .is_ok_and(|version| version == current)
+        })
+    })
+}
+
+/// Message of a `412` answered to a write with an outdated `If-Match`
+pub fn version_mismatch_message() -> String {
+    format!(
+        "The configuration is at version {}, not the one in If-Match",
+        config_version()
+    )
+}
+
+fn check_version(session: &Session) -> std::result::Result<(), ApiResponse> {
+    if version_matches(session) {
+        return Ok(());
+    }
+    Err(ApiResponse::error(
+        StatusCode::PRECONDITION_FAILED,
+        "version_mismatch",
+        version_mismatch_message(),
+    ))
+}
+
+// Routes added, removed and changed between two configurations
+fn config_diff(from: &Configuration, to: &Configuration) -> Value {
+    let old = from.to_hashmap();
+    let new = to.to_hashmap();
+    let hosts: BTreeSet<&String> = old.keys().chain(new.keys()).collect();
+
+    let (mut added, mut removed, mut changed) = (Vec::new(), Vec::new(), Vec::new());
+    for host in hosts {
+        match (old.get(host), new.get(host)) {
+            (None, Some(route)) => added.push(json!(route)),
+            (Some(route), None) => removed.push(json!(route)),
+            (Some(a), Some(b)) if json!(a) != json!(b) => {
+                changed.push(json!({ "host": host, "old": a, "new": b }))
+            }
+            _ => {}
+        }
+    }
+
+    let mut diff = json!({
+        "from": from.version,
+        "to": to.version,
+        "added": added,
+        "removed": removed,
+        "changed": changed,
+    });
+    if json!(from.maintenance) != json!(to.maintenance) {
+        diff["maintenance"] = json!({ "old": from.maintenance, "new": to.maintenance });
+    }
+    diff
+}
+
+/// Body of `POST /api/v1/config/rollback`
+#[derive(Deserialize)]
+struct RollbackRequest {
+    version: u64,
+}
+
+fn route_location(host: &str) -> String {
+    format!("{}/routes/{}", API_PREFIX, percent_encode(host))
+}
+
+// Filter and limit of an audit query such as `?origin=api&action=route.&limit=20`
+fn audit_query(query: Option<&str>) -> std::result::Result<(AuditFilter, usize), ApiResponse> {
+    let invalid =
+        |message: String| ApiResponse::error(StatusCode::BAD_REQUEST, "invalid_query", message);
+
+    let mut filter = AuditFilter::default();
+    let mut limit = AUDIT_DEFAULT_LIMIT;
+    for pair in query
+        .unwrap_or_default()
+        .split('&')
+        .filter(|p| !p.is_empty())
+    {
+        let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+        let value = percent_decode(&value.replace('+', " "))
+            .ok_or_else(|| invalid(format!("Invalid value for {}", name)))?;
+        match name {
+            "origin" => {
+                filter.origin = Some(
+                    serde_json::from_value::<AuditOrigin>(json!(value))
+                        .map_err(|_| invalid(format!("Unknown origin {}", value)))?,
+                )
+            }
+            "actor" => filter.actor = Some(value),
+            "action" => filter.action = Some(value),
+            "target" => filter.target = Some(value),
+            "since" | "until" => {
+                let time = parse_timestamp(&value)
+                    .ok_or_else(|| invalid(format!("{} must be an RFC 3339 timestamp", name)))?;
+                if name == "since" {
+                    filter.since = Some(time);
+                } else {
+                    filter.until = Some(time);
+                }
+            }
+            "limit" => {
+                limit = value
+                    .parse::<usize>()
+                    .ok()
+                    .filter(|limit| (1..=AUDIT_MAX_LIMIT).contains(limit))
+                    .ok_or_else(|| {
+                        invalid(format!("limit must be between 1 and {}", AUDIT_MAX_LIMIT))
+                    })?
+            }
+            _ => return Err(invalid(format!("Unknown parameter {}", name))),
+        }
+    }
+    Ok((filter, limit))
+}
+
+impl ManagerProxy {
+    /// Handle a request under [`API_PREFIX`]
+    pub async fn handle_api_request(
+        &self,
+        session: &mut Session,
+        identity: &Identity,
+    ) -> Result<bool> {
+        let path = session.req_header().uri.path().to_string();
+        let method = session.req_header().method.clone();
+
+        let resource = path.strip_prefix(API_PREFIX).unwrap_or_default();
+        let response = match resource.strip_prefix("/routes") {
+            Some("" | "/") => self.handle_routes(session, &method, identity).await,
+            Some(host) if host.starts_with('/') => {
+                self.handle_route(session, &method, host.trim_matches('/'), identity)
+                    .await
+            }
+            _ if resource.trim_end_matches('/') == "/audit" => {
+                self.handle_audit(session, &method, identity)
+            }
+            _ if resource == "/config" || resource.starts_with("/config/") => {
+                self.handle_config(session, &method, resource.trim_end_matches('/'), identity)
+                    .await
+            }
+            _ => Err(ApiResponse::error(
+                StatusCode::NOT_FOUND,
+                "not_found",
+                format!("No API endpoint at {}", path),
+            )),
+        };
+
+        let response = response.unwrap_or_else(|error| error);
+        println!("API {} {} -> {}", method, path, response.status.as_u16());
+        write_response(session, response).await
+    }
+
+    fn lock_servers(&self) -> std::result::Result<MutexGuard<'_, ConfigStore>, ApiResponse> {
+        self.servers.lock().map_err(|e| {
+            println!("Error locking servers mutex: {}", e);
+            ApiResponse::error(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "internal",
+                "Failed to acquire lock on server configuration",
+            )
+        })
+    }
+
+    // Full route object from the request body, checked before it is stored
+    async fn read_route(
+        &self,
+        session: &mut Session,
+    ) -> std::result::Result<ServerMapping, ApiResponse> {
+        let body = self.read_request_body(session).await.map_err(|e| {
+            ApiResponse::error(
+                StatusCode::BAD_REQUEST,
+                "invalid_body",
+                format!("Failed to read request body: {}", e),
+            )
+        })?;
+        serde_json::from_slice(&body).map_err(|e| {
+            ApiResponse::error(
+                StatusCode::BAD_REQUEST,
+                "invalid_json",
+                format!("Invalid route: {}", e),
+            )
+        })
+    }
+
+    // `/api/v1/routes`: list every route, or add one
+    async fn handle_routes(
+        &self,
+        session: &mut Session,
+        method: &Method,
+        identity: &Identity,
+    ) -> ApiResult {
+        match *method {
+            Method::GET => {
+                let mut routes = create_mappings_from_store(&*self.lock_servers()?);
+                routes.retain(|route| identity.can_access(route));
+                Ok(ApiResponse::json(
+                    StatusCode::OK,
+                    json!({ "routes": routes }),
+                ))
+            }
+
+            Method::POST => {
+                let route = self.read_route(session).await?;
+                route
+                    .validate()
+                    .map_err(|e| ApiResponse::error(StatusCode::BAD_REQUEST, "invalid_route", e))?;
+                let mut servers = self.lock_servers()?;
+                check_version(session)?;
+                if servers.contains_key(&route.from) {
+                    return Err(ApiResponse::error(
+                        StatusCode::CONFLICT,
+                        "conflict",
+                        format!("Route {} already exists", route.from),
+                    ));
+                }
+                if !identity.can_replace(&servers, &route) {
+                    return Err(ApiResponse::not_permitted());
+                }
+                servers.insert(route.from.clone(), route.clone());
+                if let Err(e) = save(&servers) {
+                    servers.remove(&route.from);
+                    return Err(e);
+                }
+                println!("Added route {}", route.from);
+                self.record_change(identity, "route.create", &route.from, None, Some(&route));
+
+                Ok(ApiResponse::json(StatusCode::CREATED, &route)
+                    .header("Location", route_location(&route.from)))
+            }
+
+            _ => Err(ApiResponse::not_allowed("GET, POST")),
+        }
+    }
+
+    // `/api/v1/routes/{host}`: show, replace or remove one route, routes of
+    // other organizations look unknown
+    async fn handle_route(
+        &self,
+        session: &mut Session,
+        method: &Method,
+        host: &str,
+        identity: &Identity,
+    ) -> ApiResult {
+        let host = percent_decode(host).ok_or_else(|| {
+            ApiResponse::error(StatusCode::BAD_REQUEST, "bad_request", "Invalid route host")
+        })?;
+        let not_found = || {
+            ApiResponse::error(
+                StatusCode::NOT_FOUND,
+                "not_found",
+                format!("Route {} not found", host),
+            )
+        };
+
+        match *method {
+            Method::GET => {
+                let servers = self.lock_servers()?;
+                let route = servers
+                    .get(&host)
+                    .filter(|route| identity.can_access(route))
+                    .ok_or_else(not_found)?;
+                Ok(ApiResponse::json(StatusCode::OK, route))
+            }
+
+            Method::PUT => {
+                let mut route = self.read_route(session).await?;
+                // Hosts are renamed by adding the new route and removing the old one
+                if route.from.is_empty() {
+                    route.from = host.clone();
+                } else if route.from != host {
+                    return Err(ApiResponse::error(
+                        StatusCode::BAD_REQUEST,
+                        "host_mismatch",
+                        format!("Route host {} does not match {}", route.from, host),
+                    ));
+                }
+                route
+                    .validate()
+                    .map_err(|e| ApiResponse::error(StatusCode::BAD_REQUEST, "invalid_route", e))?;
+
+                let mut servers = self.lock_servers()?;
+                check_version(session)?;
+                let current = servers
+                    .get_mut(&host)
+                    .filter(|current| identity.can_access(current))
+                    .ok_or_else(not_found)?;
+                if !identity.can_access(&route) {
+                    return Err(ApiResponse::not_permitted());
+                }
+                let old = std::mem::replace(current, route.clone());
+                if let Err(e) = save(&servers) {
+                    servers.insert(host, old);
+                    return Err(e);
+                }
+                println!("Replaced route {}", host);
+                self.record_change(identity, "route.update", &host, Some(&old), Some(&route));
+
+                Ok(ApiResponse::json(StatusCode::OK, &route))
+            }
+
+            Method::DELETE => {
+                let mut servers = self.lock_servers()?;
+                check_version(session)?;
+                if !servers
+                    .get(&host)
+                    .is_some_and(|route| identity.can_access(route))
+                {
+                    return Err(not_found());
+                }
+                let old = servers.remove(&host);
+                if let Err(e) = save(&servers) {
+                    servers.extend(old.map(|old| (host, old)));
+                    return Err(e);
+                }
+                println!("Removed route {}", host);
+                self.record_change(identity, "route.delete", &host, old.as_ref(), None);
+
+                Ok(ApiResponse::empty(StatusCode::NO_CONTENT))
+            }
+
+            _ => Err(ApiResponse::not_allowed("GET, PUT, DELETE")),
+        }
+    }
+
+    // `/api/v1/audit`: changes recorded in the audit log, newest first.
+    // Organization tokens only see changes to their own routes and domains
+    fn handle_audit(&self, session: &Session, method: &Method, identity: &Identity) -> ApiResult {
+        if *method != Method::GET {
+            return Err(ApiResponse::not_allowed("GET"));
+        }
+        let (filter, limit) = audit_query(session.req_header().uri.query())?;
+
+        // Copied so the log is not read while routing is blocked
+        let servers = match identity.org {
+            Some(_) => Some(self.lock_servers()?.clone()),
+            None => None,
+        };
+        let visible = |entry: &AuditEntry| {
+            let Some(servers) = &servers else {
+                return true;
+            };
+            [&entry.old, &entry.new]
+                .into_iter()
+                .flatten()
+                .filter_map(|value| serde_json::from_value::<ServerMapping>(value.clone()).ok())
+                .any(|route| identity.can_access(&route))
+                || identity.can_access_domain(servers, &entry.target)
+        };
+
+        let entries = self
+            .audit
+            .query(limit, |entry| filter.matches(entry) && visible(entry));
+        Ok(ApiResponse::json(
+            StatusCode::OK,
+            json!({ "entries": entries }),
+        ))
+    }
+
+    // `/api/v1/config[/versions[/{version}]|/diff|/rollback]`: the configuration
+    // in effect, its saved versions and going back to one of them
+    async fn handle_config(
+        &self,
+        session: &mut Session,
+        method: &Method,
+        resource: &str,
+        identity: &Identity,
+    ) -> ApiResult {
+        // Every version holds the routes of every organization
+        if identity.org.is_some() {
+            return Err(ApiResponse::not_permitted());
+        }
+        let not_found = |version: u64| {
+            ApiResponse::error(
+                StatusCode::NOT_FOUND,
+                "not_found",
+                format!("Version {} is not in the history", version),
+            )
+        };
+        let parse_version = |value: &str| {
+            value.parse::<u64>().map_err(|_| {
+                ApiResponse::error(
+                    StatusCode::BAD_REQUEST,
+                    "bad_request",
+                    format!("Invalid version {}", value),
+                )
+            })
+        };
+
+        let resource = resource.strip_prefix("/config").unwrap_or_default();
+        match (method.clone(), resource) {
+            (Method::GET, "") => {
+                let config = Configuration {
+                    version: config_version(),
+                    servers: create_mappings_from_store(&*self.lock_servers()?),
+                    maintenance: self.maintenance.lock().ok().and_then(|m| m.clone()),
+                };
+                Ok(ApiResponse::json(StatusCode::OK, config))
+            }
+
+            (Method::GET, "/versions") => Ok(ApiResponse::json(
+                StatusCode::OK,
+                json!({ "current": config_version(), "versions": list_versions() }),
+            )),
+
+            (Method::GET, version) if version.starts_with("/versions/") => {
+                let version = parse_version(version.trim_start_matches("/versions/"))?;
+                let config = load_version(version).ok_or_else(|| not_found(version))?;
+                Ok(ApiResponse::json(StatusCode::OK, config))
+            }
+
+            // `?from=3&to=5`, `to` defaults to the version in effect
+            (Method::GET, "/diff") => {
+                let query = session.req_header().uri.query().unwrap_or_default();
+                let param = |name: &str| {
+                    query
+                        .split('&')
+                        .filter_map(|pair| pair.split_once('='))
+                        .find(|(key, _)| *key == name)
+                        .map(|(_, value)| value.to_string())
+                };
+                let from = param("from").ok_or_else(|| {
+                    ApiResponse::error(StatusCode::BAD_REQUEST, "bad_request", "from is required")
+                })?;
+                let from = parse_version(&from)?;
+                let to = match param("to") {
+                    Some(to) => parse_version(&to)?,
+                    None => config_version(),
+                };
+                let from = load_version(from).ok_or_else(|| not_found(from))?;
+                let to = load_version(to).ok_or_else(|| not_found(to))?;
+                Ok(ApiResponse::json(StatusCode::OK, config_diff(&from, &to)))
+            }
+
+            (Method::POST, "/rollback") => self.rollback(session, identity).await,
+
+            (_, "" | "/versions" | "/diff") => Err(ApiResponse::not_allowed("GET")),
+            (_, "/rollback") => Err(ApiResponse::not_allowed("POST")),
+            (_, version) if version.starts_with("/versions/") => {
+                Err(ApiResponse::not_allowed("GET"))
+            }
+            _ => Err(ApiResponse::error(
+                StatusCode::NOT_FOUND,
+                "not_found",
+                format!("No API endpoint at {}/config{}", API_PREFIX, resource),
+            )),
+        }
+    }
+
+    // Make an earlier version the configuration in effect again. It is saved as
+    // a new version first, routing only switches over once that succeeded
+    async fn rollback(&self, session: &mut Session, identity: &Identity) -> ApiResult {
+        let body = self.read_request_body(session).await.map_err(|e| {
+            ApiResponse::error(
+                StatusCode::BAD_REQUEST,
+                "invalid_body",
+                format!("Failed to read request body: {}", e),
+            )
+        })?;
+        let request: RollbackRequest = serde_json::from_slice(&body).map_err(|e| {
+            ApiResponse::error(
+                StatusCode::BAD_REQUEST,
+                "invalid_json",
+                format!("Invalid rollback request: {}", e),
+            )
+        })?;
+
+        let target = load_version(request.version).ok_or_else(|| {
+            ApiResponse::error(
+                StatusCode::NOT_FOUND,
+                "not_found",
+                format!("Version {} is not in the history", request.version),
+            )
+        })?;
+        target
+            .validate()
+            .map_err(|e| ApiResponse::error(StatusCode::BAD_REQUEST, "invalid_version", e))?;
+
+        let mut servers = self.lock_servers()?;
+        check_version(session)?;
+        let previous = config_version();
+        let config = Configuration {
+            servers: target.servers.clone(),
+            maintenance: target.maintenance.clone(),
+            ..Configuration::new()
+        };
+        save_config(&config).map_err(|e| {
+            ApiResponse::error(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "internal",
+                format!("Failed to save configuration: {}", e),
+            )
+        })?;
+        *servers = config.to_hashmap();
+        if let Ok(mut maintenance) = self.maintenance.lock() {
+            *maintenance = config.maintenance;
+        }
+        drop(servers);
+
+        let version = config_version();
+        println!(
+            "Rolled back configuration to version {}, now version {}",
+            request.version, version
+        );
+        self.record_change(
+            identity,
+            "config.rollback",
+            "config",
+            Some(&json!({ "version": previous })),
+            Some(&json!({ "version": version, "restored": request.version })),
+        );
+        Ok(ApiResponse::json(
+            StatusCode::OK,
+            json!({ "version": version, "restored": request.version }),
+        ))
+    }
+}
@@ -0,0 +1,450 @@
+// src/proxy/auth.rs
+use std::{
+    collections::HashMap,
+    fmt,
+    net::IpAddr,
+    sync::{Arc, RwLock},
+};
+
+use pingora_proxy::Session;
+use ring::digest;
+use serde::Deserialize;
+
+use crate::config::model::{ConfigStore, ServerMapping};
+
+use super::router::find_mapping;
+use super::tls::TlsConnections;
+use super::utils::parse_swarm_target;
+
+/// What the holder of a manager token may do
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Role {
+    /// Read every endpoint, change nothing
+    ReadOnly,
+    /// Read, and change routes and their sections
+    RouteAdmin,
+    /// Read, and request certificates
+    CertAdmin,
+    /// Everything, the role of tokens that do not name one
+    #[default]
+    Superuser,
+}
+
+impl Role {
+    fn parse(name: &str) -> Option<Self> {
+        match name.trim() {
+            "read-only" => Some(Role::ReadOnly),
+            "route-admin" => Some(Role::RouteAdmin),
+            "cert-admin" => Some(Role::CertAdmin),
+            "superuser" => Some(Role::Superuser),
+            _ => None,
+        }
+    }
+
+    fn allows(self, permission: Permission) -> bool {
+        match self {
+            Role::ReadOnly => permission == Permission::Read,
+            Role::RouteAdmin => matches!(permission, Permission::Read | Permission::Routes),
+            Role::CertAdmin => matches!(permission, Permission::Read | Permission::Certificates),
+            Role::Superuser => true,
+        }
+    }
+}
+
+/// Access a manager request needs
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Permission {
+    /// Any GET
+    Read,
+    /// Changing routes and their sections
+    Routes,
+    /// Requesting certificates
+    Certificates,
+    /// Changing settings shared by every route, such as global maintenance
+    Global,
+}
+
+/// Manager API token as stored in `MANAGER_TOKENS_FILE`, only its hash is kept
+#[derive(Debug, Clone, Deserialize)]
+pub struct TokenEntry {
+    pub name: String,
+    /// Hex encoded SHA-256 of the token
+    pub sha256: String,
+    #[serde(default)]
+    pub role: Role,
+    /// Organization the token is limited to, as in `com.koompi.org.id`
+    #[serde(default)]
+    pub org: Option<String>,
+}
+
+/// How a manager request was authenticated
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Principal {
+    /// Bearer token, by name
+    Token(String),
+    /// Verified client certificate, by subject
+    ClientCert(String),
+    /// Client on this host while no credentials are configured
+    Local,
+}
+
+// As recorded in the audit log
+impl fmt::Display for Principal {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Principal::Token(name) => write!(f, "token:{}", name),
+            Principal::ClientCert(subject) => write!(f, "cert:{}", subject),
+            Principal::Local => write!(f, "local"),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct OrgLabels {
+    /// Organization by `service.network`
+    services: HashMap<String, String>,
+    /// Organization by domain, domains of services of several organizations
+    /// belong to none
+    domains: HashMap<String, String>,
+}
+
+/// Organizations of swarm services and of the domains they are published under,
+/// from their `com.koompi.org.id` labels. Kept by service discovery, so access
+/// of organization tokens never rests on names API clients choose
+#[derive(Debug, Clone, Default)]
+pub struct Organizations(Arc<RwLock<OrgLabels>>);
+
+impl Organizations {
+    /// Replace the labels with those of the latest discovery run
+    pub fn update(&self, services: HashMap<String, String>, domains: HashMap<String, String>) {
+        if let Ok(mut labels) = self.0.write() {
+            labels.services = services;
+            labels.domains = domains
+                .into_iter()
+                .map(|(domain, org)| (domain.to_lowercase(), org))
+                .collect();
+        }
+    }
+
+    fn service_org(&self, service: &str) -> Option<String> {
+        self.0.read().ok()?.services.get(service).cloned()
+    }
+
+    fn domain_org(&self, domain: &str) -> Option<String> {
+        self.0
+            .read()
+            .ok()?
+            .domains
+            .get(&domain.to_lowercase())
+            .cloned()
+    }
+}
+
+/// Who a manager request was authenticated as and what they may do
+#[derive(Debug, Clone)]
+pub struct Identity {
+    pub principal: Principal,
+    pub role: Role,
+    /// Only routes of domains and services labelled with this organization are visible
+    pub org: Option<String>,
+    /// Client address, the real peer for TLS connections, `None` on the Unix socket
+    pub source_ip: Option<IpAddr>,
+    organizations: Organizations,
+}
+
+impl Identity {
+    fn superuser(principal: Principal, source_ip: Option<IpAddr>) -> Self {
+        Self {
+            principal,
+            role: Role::Superuser,
+            org: None,
+            source_ip,
+            organizations: Organizations::default(),
+        }
+    }
+
+    /// Whether the role allows a request needing `permission`, organization
+    /// tokens never change global settings
+    pub fn allows(&self, permission: Permission) -> bool {
+        self.role.allows(permission) && (self.org.is_none() || permission != Permission::Global)
+    }
+
+    // Whether the backend is a swarm target of the organization, by its address
+    // and by the label of the service it names
+    fn owns_backend(&self, org: &str, addr: &str) -> bool {
+        let (service, _, prefix) = parse_swarm_target(addr);
+        prefix.as_deref() == Some(org)
+            && self.organizations.service_org(&service).as_deref() == Some(org)
+    }
+
+    /// Whether the mapping is visible to this identity. With an organization
+    /// its domain must be labelled with that organization, and it needs a
+    /// backend with every backend a swarm service of that organization
+    pub fn can_access(&self, mapping: &ServerMapping) -> bool {
+        let Some(org) = &self.org else {
+            return true;
+        };
+        let backends = mapping.backends();
+        self.organizations.domain_org(&mapping.from).as_deref() == Some(org.as_str())
+            && !backends.is_empty()
+            && backends.iter().all(|addr| self.owns_backend(org, addr))
+    }
+
+    /// Whether `new` may be stored in `servers`, replacing the mapping of its
+    /// host if there is one. A new host may not take requests away from a
+    /// mapping the identity cannot access, such as a wildcard covering it
+    pub fn can_replace(&self, servers: &ConfigStore, new: &ServerMapping) -> bool {
+        if !self.can_access(new) {
+            return false;
+        }
+        match servers.get(&new.from) {
+            Some(current) => self.can_access(current),
+            None => find_mapping(servers, &new.from).is_none_or(|m| self.can_access(m.mapping)),
+        }
+    }
+
+    /// Whether the domain may be managed, organization tokens need a visible mapping for it
+    pub fn can_access_domain(&self, servers: &ConfigStore, domain: &str) -> bool {
+        self.org.is_none()
+            || servers
+                .get(domain)
+                .is_some_and(|mapping| self.can_access(mapping))
+    }
+}
+
+/// Why a manager request was rejected
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AuthError {
+    /// Neither a token nor a client certificate was presented
+    Missing,
+    /// The bearer token is unknown
+    InvalidToken,
+    /// Remote client while no credentials are configured
+    Forbidden,
+    /// The role or organization of the token does not allow the request
+    NotPermitted,
+}
+
+impl AuthError {
+    pub fn status(self) -> u16 {
+        match self {
+            AuthError::Missing | AuthError::InvalidToken => 401,
+            AuthError::Forbidden | AuthError::NotPermitted => 403,
+        }
+    }
+
+    /// Machine readable error code
+    pub fn code(self) -> &'static str {
+        match self {
+            AuthError::Missing => "unauthorized",
+            AuthError::InvalidToken => "invalid_token",
+            AuthError::Forbidden | AuthError::NotPermitted => "forbidden",
+        }
+    }
+
+    pub fn message(self) -> &'static str {
+        match self {
+            AuthError::Missing => "Authentication required",
+            AuthError::InvalidToken => "Invalid bearer token",
+            AuthError::Forbidden => {
+                "The manager only accepts local clients until MANAGER_TOKENS or MANAGER_CLIENT_CA is set"
+            }
+            AuthError::NotPermitted => "Not permitted for the role or organization of this token",
+        }
+    }
+
+    /// `WWW-Authenticate` challenge of 401 responses
+    pub fn challenge(self) -> Option<&'static str> {
+        match self {
+            AuthError::Missing => Some("Bearer realm=\"manager\""),
+            AuthError::InvalidToken => Some("Bearer realm=\"manager\", error=\"invalid_token\""),
+            AuthError::Forbidden | AuthError::NotPermitted => None,
+        }
+    }
+}
+
+fn parse_sha256(hex: &str) -> Option<[u8; 32]> {
+    let hex = hex.trim().strip_prefix("sha256:").unwrap_or(hex.trim());
+    if hex.len() != 64 {
+        return None;
+    }
+    let mut hash = [0u8; 32];
+    for (i, byte) in hash.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(hex.get(i * 2..i * 2 + 2)?, 16).ok()?;
+    }
+    Some(hash)
+}
+
+// Compares every byte so the time taken does not depend on where hashes differ
+fn hashes_equal(a: &[u8; 32], b: &[u8]) -> bool {
+    b.len() == a.len() && a.iter().zip(b).fold(0u8, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+// Token as kept in memory
+struct StoredToken {
+    name: String,
+    hash: [u8; 32],
+    role: Role,
+    org: Option<String>,
+}
+
+/// Credentials accepted by the manager API
+#[derive(Clone, Default)]
+pub struct ManagerAuth {
+    tokens: Arc<Vec<StoredToken>>,
+    /// Client certificates are verified by the manager TLS listener
+    client_certs: bool,
+    /// What organization tokens may access
+    organizations: Organizations,
+}
+
+impl ManagerAuth {
+    /// Load the token hashes of `MANAGER_TOKENS_FILE`, a JSON array of
+    /// `{"name", "sha256", "role", "org"}` objects, and of `MANAGER_TOKENS`, a
+    /// comma separated list of `name:sha256[:role[:org]]`
+    pub fn from_env(client_certs: bool, organizations: Organizations) -> Self {
+        let mut entries: Vec<TokenEntry> = Vec::new();
+
+        if let Ok(path) = std::env::var("MANAGER_TOKENS_FILE") {
+            match std::fs::read_to_string(&path)
+                .map_err(|e| e.to_string())
+                .and_then(|data| {
+                    serde_json::from_str::<Vec<TokenEntry>>(&data).map_err(|e| e.to_string())
+                }) {
+                Ok(tokens) => entries.extend(tokens),
+                Err(e) => println!("Error loading manager tokens from {}: {}", path, e),
+            }
+        }
+
+        if let Ok(tokens) = std::env::var("MANAGER_TOKENS") {
+            for (i, token) in tokens
+                .split(',')
+                .map(str::trim)
+                .filter(|t| !t.is_empty())
+                .enumerate()
+            {
+                // Hashes may carry a `sha256:` prefix, with or without a name
+                let (name, rest) = match token.split_once(':') {
+                    Some((name, rest)) if name != "sha256" => (name.to_string(), rest),
+                    _ => (format!("token-{}", i + 1), token),
+                };
+                let mut fields = rest.strip_prefix("sha256:").unwrap_or(rest).split(':');
+                let sha256 = fields.next().unwrap_or_default().to_string();
+                let role = match fields.next().filter(|r| !r.is_empty()) {
+                    Some(role) => match Role::parse(role) {
+                        Some(role) => role,
+                        None => {
+                            println!("Ignoring manager token {}: unknown role {}", name, role);
+                            continue;
+                        }
+                    },
+                    None => Role::default(),
+                };
+                let org = fields
+                    .next()
+                    .filter(|org| !org.is_empty())
+                    .map(str::to_string);
+                entries.push(TokenEntry {
+                    name,
+                    sha256,
+                    role,
+                    org,
+                });
+            }
+        }
+
+        let tokens = entries
+            .into_iter()
+            .filter_map(|entry| match parse_sha256(&entry.sha256) {
+                Some(hash) => Some(StoredToken {
+                    name: entry.name,
+                    hash,
+                    role: entry.role,
+                    org: entry.org,
+                }),
+                None => {
+                    println!(
+                        "Ignoring manager token {}: not a SHA-256 hex digest",
+                        entry.name
+                    );
+                    None
+                }
+            })
+            .collect::<Vec<_>>();
+        println!("Loaded {} manager API token(s)", tokens.len());
+
+        Self {
+            tokens: Arc::new(tokens),
+            client_certs,
+            organizations,
+        }
+    }
+
+    /// Whether any credentials are configured
+    pub fn is_configured(&self) -> bool {
+        !self.tokens.is_empty() || self.client_certs
+    }
+
+    // Matching token, checking every token so timing does not tell which matched
+    fn verify_token(&self, token: &str) -> Option<&StoredToken> {
+        let hash = digest::digest(&digest::SHA256, token.as_bytes());
+        self.tokens.iter().fold(None, |found, stored| {
+            if hashes_equal(&stored.hash, hash.as_ref()) {
+                Some(stored)
+            } else {
+                found
+            }
+        })
+    }
+
+    /// Authenticate a manager request by its client certificate or bearer token.
+    /// Without configured credentials only clients on this host are let in.
+    /// Client certificates and local clients are superusers
+    pub fn authenticate(
+        &self,
+        session: &Session,
+        connections: &TlsConnections,
+    ) -> Result<Identity, AuthError> {
+        // TLS connections are forwarded from loopback, their real peer counts
+        let tls = connections.lookup(session);
+        let source_ip = connections.client_addr(session).map(|addr| addr.ip());
+
+        if let Some(subject) = tls.as_ref().and_then(|info| info.client_cert.clone()) {
+            return Ok(Identity::superuser(
+                Principal::ClientCert(subject),
+                source_ip,
+            ));
+        }
+
+        if let Some(header) = session.req_header().headers.get("authorization") {
+            let token = header
+                .to_str()
+                .ok()
+                .and_then(|value| value.strip_prefix("Bearer "))
+                .map(str::trim)
+                .ok_or(AuthError::InvalidToken)?;
+            return self
+                .verify_token(token)
+                .map(|stored| Identity {
+                    principal: Principal::Token(stored.name.clone()),
+                    role: stored.role,
+                    org: stored.org.clone(),
+                    source_ip,
+                    organizations: self.organizations.clone(),
+                })
+                .ok_or(AuthError::InvalidToken);
+        }
+
+        if self.is_configured() {
+            return Err(AuthError::Missing);
+        }
+
+        let unix = tls.is_none() && session.client_addr().is_some_and(|a| a.as_unix().is_some());
+        if unix || source_ip.is_some_and(|ip| ip.is_loopback()) {
+            Ok(Identity::superuser(Principal::Local, source_ip))
+        } else {
+            Err(AuthError::Forbidden)
+        }
+    }
+}
@@ -0,0 +1,675 @@
+// src/proxy/balancer.rs
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
+    hash::{DefaultHasher, Hash, Hasher},
+    net::SocketAddr,
+    sync::{
+        Arc, Mutex, RwLock,
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+    },
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{Result, anyhow};
+use async_trait::async_trait;
+use futures::future::join_all;
+use pingora_load_balancing::{
+    Backend, Backends, LoadBalancer,
+    discovery::ServiceDiscovery,
+    selection::{Consistent, Random, RoundRobin},
+};
+
+use serde::Serialize;
+
+use crate::cert::inspect::format_rfc3339;
+use crate::config::model::{
+    Algorithm, CircuitBreakerConfig, ConfigStore, PoolConfig, StickyConfig, Upstream,
+};
+
+use super::affinity::cookie_value;
+use super::circuit::{CircuitBreaker, CircuitState};
+use super::health::{CheckResults, UpstreamHealthCheck};
+use super::retry::RetryState;
+use super::utils::parse_swarm_target;
+
+/// Pools of routes no longer configured are dropped once unused for this long
+const POOL_IDLE_TIMEOUT: Duration = Duration::from_secs(10 * 60);
+
+const DEFAULT_RESOLVE_INTERVAL: u64 = 30;
+
+/// Bound on the backends tried when the preferred one is not usable
+const MAX_SELECT_ITERATIONS: usize = 256;
+
+fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+// Seconds between lookups of the upstream host names, UPSTREAM_RESOLVE_INTERVAL
+fn resolve_interval() -> u64 {
+    std::env::var("UPSTREAM_RESOLVE_INTERVAL")
+        .ok()
+        .and_then(|v| v.parse::<u64>().ok())
+        .unwrap_or(DEFAULT_RESOLVE_INTERVAL)
+        .max(1)
+}
+
+/// Upstreams that resolved to the same address, balanced as one backend so
+/// none of them is lost. Request counts and the circuit breaker belong to the
+/// address and survive lookups that keep it
+struct Endpoint {
+    /// Indexes of the upstreams in the pool configuration
+    upstreams: Vec<usize>,
+    /// Sum of the weights of its upstreams
+    weight: usize,
+    active: Arc<AtomicUsize>,
+    breaker: Option<Arc<CircuitBreaker>>,
+}
+
+type Endpoints = Arc<RwLock<HashMap<SocketAddr, Arc<Endpoint>>>>;
+
+/// Service discovery of a pool, resolving the configured upstreams each time
+/// pingora updates the backends
+struct UpstreamDiscovery {
+    upstreams: Vec<Upstream>,
+    /// Plain round robin treats every upstream the same
+    round_robin: bool,
+    circuit_breaker: Option<CircuitBreakerConfig>,
+    /// Address each upstream resolved to last, kept while lookups fail
+    resolved: Mutex<Vec<Option<SocketAddr>>>,
+    endpoints: Endpoints,
+}
+
+#[async_trait]
+impl ServiceDiscovery for UpstreamDiscovery {
+    async fn discover(&self) -> pingora::Result<(BTreeSet<Backend>, HashMap<u64, bool>)> {
+        let lookups = join_all(self.upstreams.iter().map(|upstream| async {
+            // Swarm targets may carry an organization prefix that is not part of the DNS name
+            let (host, port, _) = parse_swarm_target(&upstream.addr);
+            tokio::net::lookup_host((host.as_str(), port))
+                .await
+                .map(|addrs| addrs.collect::<Vec<_>>())
+        }))
+        .await;
+
+        let mut groups: BTreeMap<SocketAddr, Vec<usize>> = BTreeMap::new();
+        {
+            let mut resolved = self.resolved.lock().map_err(|_| {
+                pingora::Error::explain(pingora::ErrorType::InternalError, "Poisoned lock")
+            })?;
+            resolved.resize(self.upstreams.len(), None);
+            for (index, lookup) in lookups.into_iter().enumerate() {
+                let previous = &mut resolved[index];
+                match lookup {
+                    // An address that still resolves is kept, so connections
+                    // are not moved around between equal records
+                    Ok(addrs) if !addrs.is_empty() => {
+                        if !previous.is_some_and(|addr| addrs.contains(&addr)) {
+                            *previous = Some(addrs[0]);
+                        }
+                    }
+                    Ok(_) => println!(
+                        "Upstream {} resolved to no address",
+                        self.upstreams[index].addr
+                    ),
+                    Err(e) => println!(
+                        "Error resolving upstream {}: {}",
+                        self.upstreams[index].addr, e
+                    ),
+                }
+                if let Some(addr) = previous {
+                    groups.entry(*addr).or_default().push(index);
+                }
+            }
+        }
+
+        if groups.is_empty() {
+            return Err(pingora::Error::explain(
+                pingora::ErrorType::InternalError,
+                "No upstream of the pool could be resolved",
+            ));
+        }
+
+        let mut backends = BTreeSet::new();
+        let mut endpoints = HashMap::new();
+        let current = self
+            .endpoints
+            .read()
+            .map(|endpoints| endpoints.clone())
+            .unwrap_or_default();
+        for (addr, upstreams) in groups {
+            let weight = match self.round_robin {
+                true => upstreams.len(),
+                false => upstreams.iter().map(|i| self.upstreams[*i].weight).sum(),
+            };
+            let existing = current.get(&addr);
+            let breaker = match (existing, &self.circuit_breaker) {
+                (Some(existing), Some(_)) if existing.breaker.is_some() => existing.breaker.clone(),
+                (_, Some(config)) => {
+                    let targets: Vec<&str> = upstreams
+                        .iter()
+                        .map(|i| self.upstreams[*i].addr.as_str())
+                        .collect();
+                    let breaker = CircuitBreaker::new(config.clone(), targets.join(", "));
+                    Some(Arc::new(breaker))
+                }
+                (_, None) => None,
+            };
+            let endpoint = Endpoint {
+                upstreams,
+                weight,
+                active: existing.map_or_else(Default::default, |e| e.active.clone()),
+                breaker,
+            };
+            backends.insert(Backend::new_with_weight(&addr.to_string(), weight.max(1))?);
+            endpoints.insert(addr, Arc::new(endpoint));
+        }
+
+        if let Ok(mut current) = self.endpoints.write() {
+            *current = endpoints;
+        }
+        Ok((backends, HashMap::new()))
+    }
+}
+
+enum Selector {
+    RoundRobin(LoadBalancer<RoundRobin>),
+    Random(LoadBalancer<Random>),
+    Consistent(LoadBalancer<Consistent>),
+}
+
+/// Counts a request against an upstream until it is dropped
+#[derive(Debug)]
+pub struct ActiveRequest(Arc<AtomicUsize>);
+
+impl Drop for ActiveRequest {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Upstream picked for a request
+#[derive(Debug)]
+pub struct Selected {
+    /// Resolved address to connect to
+    pub addr: SocketAddr,
+    /// Address as configured, e.g. a swarm service name
+    pub target: String,
+    // Held for the lifetime of the request
+    _active: ActiveRequest,
+    breaker: Option<Arc<CircuitBreaker>>,
+}
+
+impl Selected {
+    /// Feed the outcome of the request to the circuit breaker of the upstream
+    pub fn record(mut self, success: bool) {
+        if let Some(breaker) = self.breaker.take() {
+            breaker.record(success);
+        }
+    }
+}
+
+impl Drop for Selected {
+    // A request that ended without an outcome frees its half open trial
+    fn drop(&mut self) {
+        if let Some(breaker) = self.breaker.take() {
+            breaker.release();
+        }
+    }
+}
+
+/// Health of a pool upstream, as reported by the manager API
+#[derive(Debug, Clone, Serialize)]
+pub struct UpstreamHealth {
+    pub addr: String,
+    pub resolved: String,
+    pub healthy: bool,
+    pub active_requests: usize,
+    pub last_check: Option<String>,
+    pub last_error: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub circuit: Option<CircuitState>,
+}
+
+/// Load balancer built from a pool configuration
+pub struct UpstreamPool {
+    selector: Selector,
+    algorithm: Algorithm,
+    hash_on: Option<String>,
+    /// Configured address of every upstream
+    upstreams: Vec<String>,
+    /// Affinity cookie value of every upstream
+    affinity_ids: Vec<String>,
+    endpoints: Endpoints,
+    retry: Option<Arc<RetryState>>,
+    sticky: Option<StickyConfig>,
+    /// Last time a request used the pool, pools of removed routes are dropped
+    /// once it is long enough ago
+    last_used: AtomicU64,
+    next_resolve: AtomicU64,
+    check_interval: Option<Duration>,
+    next_check: AtomicU64,
+    check_results: CheckResults,
+}
+
+impl UpstreamPool {
+    async fn build(config: &PoolConfig) -> Result<Self> {
+        let balancing = config.load_balancing.clone().unwrap_or_default();
+        if config.upstreams.is_empty() {
+            return Err(anyhow!("Pool has no upstreams"));
+        }
+
+        // Every selector shares the health state kept by its `Backends`
+        let endpoints = Endpoints::default();
+        let check_results = CheckResults::default();
+        let backends = || {
+            let mut backends = Backends::new(Box::new(UpstreamDiscovery {
+                upstreams: config.upstreams.clone(),
+                round_robin: balancing.algorithm == Algorithm::RoundRobin,
+                circuit_breaker: config.circuit_breaker.clone(),
+                resolved: Mutex::default(),
+                endpoints: endpoints.clone(),
+            }));
+            if let Some(health_check) = &config.health_check {
+                backends.set_health_check(Box::new(UpstreamHealthCheck::new(
+                    health_check.clone(),
+                    check_results.clone(),
+                )));
+            }
+            backends
+        };
+        let selector = match balancing.algorithm {
+            Algorithm::Random => {
+                let lb = LoadBalancer::from_backends(backends());
+                lb.update().await?;
+                Selector::Random(lb)
+            }
+            Algorithm::ConsistentHash => {
+                let lb = LoadBalancer::from_backends(backends());
+                lb.update().await?;
+                Selector::Consistent(lb)
+            }
+            _ => {
+                let lb = LoadBalancer::from_backends(backends());
+                lb.update().await?;
+                Selector::RoundRobin(lb)
+            }
+        };
+
+        Ok(Self {
+            selector,
+            algorithm: balancing.algorithm,
+            hash_on: balancing.hash_on,
+            upstreams: config.upstreams.iter().map(|u| u.addr.clone()).collect(),
+            affinity_ids: match &config.sticky {
+                Some(_) => config
+                    .upstreams
+                    .iter()
+                    .map(|u| cookie_value(&u.addr))
+                    .collect(),
+                None => Vec::new(),
+            },
+            endpoints,
+            retry: config
+                .retry
+                .clone()
+                .map(|policy| Arc::new(RetryState::new(policy))),
+            sticky: config.sticky.clone(),
+            last_used: AtomicU64::new(now_secs()),
+            next_resolve: AtomicU64::new(now_secs() + resolve_interval()),
+            check_interval: config
+                .health_check
+                .as_ref()
+                .map(|health_check| Duration::from_secs(health_check.interval)),
+            next_check: AtomicU64::new(0),
+            check_results,
+        })
+    }
+
+    fn resolve_due(&self) -> bool {
+        self.next_resolve.load(Ordering::Relaxed) <= now_secs()
+    }
+
+    /// Look the upstreams up again when it is due, so the pool follows DNS
+    /// changes such as rescheduled swarm tasks. Health and request counts of
+    /// addresses that stay are kept
+    pub async fn refresh(&self) {
+        let now = now_secs();
+        let due = self.next_resolve.load(Ordering::Relaxed);
+        if due > now
+            || self
+                .next_resolve
+                .compare_exchange(
+                    due,
+                    now + resolve_interval(),
+                    Ordering::Relaxed,
+                    Ordering::Relaxed,
+                )
+                .is_err()
+        {
+            return;
+        }
+
+        let result = match &self.selector {
+            Selector::RoundRobin(lb) => lb.update().await,
+            Selector::Random(lb) => lb.update().await,
+            Selector::Consistent(lb) => lb.update().await,
+        };
+        if let Err(e) = result {
+            println!(
+                "Error resolving upstreams, keeping the previous addresses: {}",
+                e
+            );
+        }
+    }
+
+    fn endpoint(&self, addr: &SocketAddr) -> Option<Arc<Endpoint>> {
+        self.endpoints.read().ok()?.get(addr).cloned()
+    }
+
+    /// Check the upstreams when the pool has a health check that is due
+    pub async fn run_health_check(&self) {
+        let Some(interval) = self.check_interval else {
+            return;
+        };
+        let now = now_secs();
+        if self.next_check.load(Ordering::Relaxed) > now {
+            return;
+        }
+        self.next_check
+            .store(now + interval.as_secs(), Ordering::Relaxed);
+
+        self.backends().run_health_check(true).await;
+    }
+
+    /// Health of every upstream, sorted by configured address. Upstreams that
+    /// never resolved are listed without an address
+    pub fn health(&self) -> Vec<UpstreamHealth> {
+        let results = self
+            .check_results
+            .lock()
+            .map(|results| results.clone())
+            .unwrap_or_default();
+        let endpoints = self
+            .endpoints
+            .read()
+            .map(|endpoints| endpoints.clone())
+            .unwrap_or_default();
+        let backends = self.backends();
+        let backend_list = backends.get_backend();
+
+        let mut health: Vec<UpstreamHealth> = self
+            .upstreams
+            .iter()
+            .enumerate()
+            .map(|(index, upstream)| {
+                let Some((addr, endpoint)) = endpoints
+                    .iter()
+                    .find(|(_, endpoint)| endpoint.upstreams.contains(&index))
+                else {
+                    return UpstreamHealth {
+                        addr: upstream.clone(),
+                        resolved: String::new(),
+                        healthy: false,
+                        active_requests: 0,
+                        last_check: None,
+                        last_error: Some("Not resolved".to_string()),
+                        circuit: None,
+                    };
+                };
+                let result = results.get(addr).cloned().unwrap_or_default();
+                UpstreamHealth {
+                    addr: upstream.clone(),
+                    resolved: addr.to_string(),
+                    healthy: backend_list
+                        .iter()
+                        .find(|backend| backend.addr.as_inet() == Some(addr))
+                        .is_some_and(|backend| backends.ready(backend)),
+                    active_requests: endpoint.active.load(Ordering::Relaxed),
+                    last_check: result.last_check.map(format_rfc3339),
+                    last_error: result.last_error,
+                    circuit: endpoint.breaker.as_ref().map(|breaker| breaker.state()),
+                }
+            })
+            .collect();
+        health.sort_by(|a, b| a.addr.cmp(&b.addr));
+        health
+    }
+
+    /// What consistent hashing keys on, the client address when unset
+    pub fn hash_on(&self) -> Option<&str> {
+        self.hash_on.as_deref()
+    }
+
+    /// Retry policy of the pool and its budget
+    pub fn retry(&self) -> Option<Arc<RetryState>> {
+        self.retry.clone()
+    }
+
+    /// Session affinity of the pool
+    pub fn sticky(&self) -> Option<&StickyConfig> {
+        self.sticky.as_ref()
+    }
+
+    fn backends(&self) -> &Backends {
+        match &self.selector {
+            Selector::RoundRobin(lb) => lb.backends(),
+            Selector::Random(lb) => lb.backends(),
+            Selector::Consistent(lb) => lb.backends(),
+        }
+    }
+
+    // Upstreams whose circuit is open are skipped like unhealthy ones
+    fn is_available(&self, backend: &Backend) -> bool {
+        backend
+            .addr
+            .as_inet()
+            .and_then(|addr| self.endpoint(addr))
+            .and_then(|endpoint| endpoint.breaker.clone())
+            .is_none_or(|breaker| breaker.is_available())
+    }
+
+    fn select_backend(&self, key: &[u8], exclude: &[SocketAddr]) -> Option<Backend> {
+        let excluded = |backend: &Backend| {
+            backend
+                .addr
+                .as_inet()
+                .is_some_and(|addr| exclude.contains(addr))
+        };
+        let accept = |backend: &Backend, healthy: bool| {
+            healthy && self.is_available(backend) && !excluded(backend)
+        };
+
+        if self.algorithm == Algorithm::LeastConnections {
+            let backends = self.backends();
+            return backends
+                .get_backend()
+                .iter()
+                .filter(|backend| {
+                    backends.ready(backend) && self.is_available(backend) && !excluded(backend)
+                })
+                .min_by_key(|backend| {
+                    let endpoint = backend.addr.as_inet().and_then(|addr| self.endpoint(addr));
+                    let active = endpoint
+                        .as_ref()
+                        .map_or(0, |endpoint| endpoint.active.load(Ordering::Relaxed));
+                    let weight = endpoint.map_or(1, |endpoint| endpoint.weight);
+                    // Compare active / weight without floating point
+                    (active * 1000) / weight.max(1)
+                })
+                .cloned();
+        }
+
+        match &self.selector {
+            Selector::RoundRobin(lb) => lb.select_with(key, MAX_SELECT_ITERATIONS, accept),
+            Selector::Random(lb) => lb.select_with(key, MAX_SELECT_ITERATIONS, accept),
+            Selector::Consistent(lb) => lb.select_with(key, MAX_SELECT_ITERATIONS, accept),
+        }
+    }
+
+    /// Pick an upstream, `key` is only used by consistent hashing; upstreams in
+    /// `exclude` are only picked again when no other one is available
+    pub fn select(&self, key: &[u8], exclude: &[SocketAddr]) -> Option<Selected> {
+        let backend = self
+            .select_backend(key, exclude)
+            .or_else(|| self.select_backend(key, &[]))?;
+        self.selected(*backend.addr.as_inet()?)
+    }
+
+    // Healthy, circuit not open and not tried yet
+    fn usable_addrs(&self, exclude: &[SocketAddr]) -> Vec<SocketAddr> {
+        let backends = self.backends();
+        backends
+            .get_backend()
+            .iter()
+            .filter(|backend| backends.ready(backend) && self.is_available(backend))
+            .filter_map(|backend| backend.addr.as_inet().copied())
+            .filter(|addr| !exclude.contains(addr))
+            .collect()
+    }
+
+    /// Pick the upstream an affinity cookie value names when it is usable
+    pub fn select_affinity(&self, value: &str, exclude: &[SocketAddr]) -> Option<Selected> {
+        let index = self.affinity_ids.iter().position(|id| id == value)?;
+        let addr = self.usable_addrs(exclude).into_iter().find(|addr| {
+            self.endpoint(addr)
+                .is_some_and(|endpoint| endpoint.upstreams.contains(&index))
+        })?;
+        self.selected(addr)
+    }
+
+    /// Pick an upstream by rendezvous hashing of `key`, so only the keys of an
+    /// upstream that goes away move elsewhere
+    pub fn select_hashed(&self, key: &[u8], exclude: &[SocketAddr]) -> Option<Selected> {
+        let addr = self.usable_addrs(exclude).into_iter().max_by_key(|addr| {
+            let mut hasher = DefaultHasher::new();
+            key.hash(&mut hasher);
+            addr.hash(&mut hasher);
+            hasher.finish()
+        })?;
+        self.selected(addr)
+    }
+
+    // Count the request against the picked upstream
+    fn selected(&self, addr: SocketAddr) -> Option<Selected> {
+        self.last_used.store(now_secs(), Ordering::Relaxed);
+
+        let endpoint = self.endpoint(&addr)?;
+        let active = endpoint.active.clone();
+        active.fetch_add(1, Ordering::Relaxed);
+        let breaker = endpoint.breaker.clone();
+        if let Some(breaker) = &breaker {
+            breaker.on_selected();
+        }
+
+        Some(Selected {
+            addr,
+            target: endpoint
+                .upstreams
+                .first()
+                .and_then(|index| self.upstreams.get(*index))
+                .cloned()
+                .unwrap_or_default(),
+            _active: ActiveRequest(active),
+            breaker,
+        })
+    }
+}
+
+/// Pools by configuration, shared by the proxies so balancing state survives
+/// across requests while config changes build fresh pools
+#[derive(Clone, Default)]
+pub struct UpstreamPools {
+    pub pools: Arc<Mutex<HashMap<String, Arc<UpstreamPool>>>>,
+}
+
+/// Pools of every configured route as `(domain, path, pool)`, routes whose
+/// upstreams use host captures are only known once a request arrives
+pub fn configured_pools(store: &ConfigStore) -> Vec<(String, Option<String>, PoolConfig)> {
+    let mut pools = Vec::new();
+    for mapping in store.values() {
+        if let Some(pool) = mapping.pool.effective(&mapping.to) {
+            pools.push((mapping.from.clone(), None, pool));
+        }
+        for rule in &mapping.paths {
+            if let Some(pool) = rule.pool.effective(&rule.to) {
+                pools.push((mapping.from.clone(), Some(rule.path.clone()), pool));
+            }
+        }
+    }
+
+    pools.retain(|(_, _, pool)| !pool.upstreams.iter().any(|u| u.addr.contains('{')));
+    pools.sort_by(|a, b| (&a.0, &a.1).cmp(&(&b.0, &b.1)));
+    pools
+}
+
+impl UpstreamPools {
+    /// Pool already built for a configuration
+    pub fn peek(&self, config: &PoolConfig) -> Option<Arc<UpstreamPool>> {
+        let key = serde_json::to_string(config).ok()?;
+        self.pools.lock().ok()?.get(&key).cloned()
+    }
+
+    /// Get the pool for a request, building it on first use
+    pub async fn get(&self, config: &PoolConfig) -> Result<Arc<UpstreamPool>> {
+        let pool = self.get_or_build(config).await?;
+        pool.last_used.store(now_secs(), Ordering::Relaxed);
+
+        // Pools of routes the health check service does not see, e.g. with host
+        // captures, are looked up again in the background as requests come in
+        if pool.resolve_due() {
+            let pool = pool.clone();
+            tokio::spawn(async move { pool.refresh().await });
+        }
+        Ok(pool)
+    }
+
+    /// Get the pool for a configuration without counting it as used
+    pub async fn get_or_build(&self, config: &PoolConfig) -> Result<Arc<UpstreamPool>> {
+        let key = serde_json::to_string(config)?;
+
+        if let Some(pool) = self
+            .pools
+            .lock()
+            .ok()
+            .and_then(|pools| pools.get(&key).cloned())
+        {
+            return Ok(pool);
+        }
+
+        // Built without holding the lock, resolving upstreams may take a while
+        let pool = Arc::new(UpstreamPool::build(config).await?);
+        println!(
+            "Built {:?} pool with {} upstreams",
+            config
+                .load_balancing
+                .as_ref()
+                .map(|lb| lb.algorithm)
+                .unwrap_or_default(),
+            config.upstreams.len()
+        );
+
+        if let Ok(mut pools) = self.pools.lock() {
+            pools.insert(key, pool.clone());
+        }
+
+        Ok(pool)
+    }
+
+    /// Drop the pools of routes that are no longer configured once no request
+    /// used them for a while, configured pools are kept however idle they are
+    pub fn prune(&self, configured: &[PoolConfig]) {
+        let keys: HashSet<String> = configured
+            .iter()
+            .filter_map(|config| serde_json::to_string(config).ok())
+            .collect();
+        let cutoff = now_secs().saturating_sub(POOL_IDLE_TIMEOUT.as_secs());
+        if let Ok(mut pools) = self.pools.lock() {
+            pools.retain(|key, pool| {
+                keys.contains(key) || pool.last_used.load(Ordering::Relaxed) >= cutoff
+            });
+        }
+    }
+}
@@ -0,0 +1,176 @@
+// src/proxy/circuit.rs
+use std::{
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use serde::Serialize;
+
+use crate::config::model::CircuitBreakerConfig;
+
+/// State of the circuit of an upstream
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CircuitState {
+    /// Requests flow, failures are counted
+    Closed,
+    /// Requests are rejected until the cooldown is over
+    Open,
+    /// A few trial requests decide whether to close or open again
+    HalfOpen,
+}
+
+#[derive(Debug)]
+struct Inner {
+    state: CircuitState,
+    consecutive_failures: usize,
+    window_start: Instant,
+    requests: usize,
+    failures: usize,
+    opened_at: Instant,
+    trials: usize,
+}
+
+/// Passive health of an upstream, judged from the outcome of real requests
+#[derive(Debug)]
+pub struct CircuitBreaker {
+    config: CircuitBreakerConfig,
+    target: String,
+    inner: Mutex<Inner>,
+}
+
+impl CircuitBreaker {
+    pub fn new(config: CircuitBreakerConfig, target: String) -> Self {
+        let now = Instant::now();
+        Self {
+            config,
+            target,
+            inner: Mutex::new(Inner {
+                state: CircuitState::Closed,
+                consecutive_failures: 0,
+                window_start: now,
+                requests: 0,
+                failures: 0,
+                opened_at: now,
+                trials: 0,
+            }),
+        }
+    }
+
+    fn cooldown(&self) -> Duration {
+        Duration::from_secs(self.config.cooldown)
+    }
+
+    /// Current state, an open circuit past its cooldown is reported as half open
+    pub fn state(&self) -> CircuitState {
+        match self.inner.lock() {
+            Ok(inner) if inner.state == CircuitState::Open => {
+                if inner.opened_at.elapsed() >= self.cooldown() {
+                    CircuitState::HalfOpen
+                } else {
+                    CircuitState::Open
+                }
+            }
+            Ok(inner) => inner.state,
+            Err(_) => CircuitState::Closed,
+        }
+    }
+
+    /// Whether a request may be sent to the upstream
+    pub fn is_available(&self) -> bool {
+        let Ok(inner) = self.inner.lock() else {
+            return true;
+        };
+        match inner.state {
+            CircuitState::Closed => true,
+            CircuitState::Open => inner.opened_at.elapsed() >= self.cooldown(),
+            CircuitState::HalfOpen => inner.trials < self.config.half_open_requests,
+        }
+    }
+
+    /// Note that a request was sent, past the cooldown it becomes a trial request
+    pub fn on_selected(&self) {
+        let Ok(mut inner) = self.inner.lock() else {
+            return;
+        };
+        if inner.state == CircuitState::Open && inner.opened_at.elapsed() >= self.cooldown() {
+            println!("Circuit of upstream {} is half open", self.target);
+            inner.state = CircuitState::HalfOpen;
+            inner.trials = 0;
+        }
+        if inner.state == CircuitState::HalfOpen {
+            inner.trials += 1;
+        }
+    }
+
+    /// Forget a request whose outcome is unknown
+    pub fn release(&self) {
+        if let Ok(mut inner) = self.inner.lock()
+            && inner.state == CircuitState::HalfOpen
+        {
+            inner.trials = inner.trials.saturating_sub(1);
+        }
+    }
+
+    /// Record the outcome of a request sent to the upstream
+    pub fn record(&self, success: bool) {
+        let Ok(mut inner) = self.inner.lock() else {
+            return;
+        };
+
+        match inner.state {
+            CircuitState::HalfOpen => {
+                inner.trials = inner.trials.saturating_sub(1);
+                if success {
+                    println!("Circuit of upstream {} is closed", self.target);
+                    inner.state = CircuitState::Closed;
+                    inner.consecutive_failures = 0;
+                    inner.window_start = Instant::now();
+                    inner.requests = 0;
+                    inner.failures = 0;
+                } else {
+                    self.open(&mut inner, "trial request failed");
+                }
+            }
+            // Requests sent before the circuit opened do not extend the cooldown
+            CircuitState::Open => {}
+            CircuitState::Closed => {
+                // Tumbling window, counts start over once it has passed
+                if inner.window_start.elapsed() >= Duration::from_secs(self.config.window) {
+                    inner.window_start = Instant::now();
+                    inner.requests = 0;
+                    inner.failures = 0;
+                }
+
+                inner.requests += 1;
+                if success {
+                    inner.consecutive_failures = 0;
+                    return;
+                }
+                inner.failures += 1;
+                inner.consecutive_failures += 1;
+
+                if inner.consecutive_failures >= self.config.consecutive_failures {
+                    let reason = format!("{} failures in a row", inner.consecutive_failures);
+                    self.open(&mut inner, &reason);
+                } else if inner.requests >= self.config.min_requests
+                    && inner.failures * 100 >= inner.requests * self.config.error_rate as usize
+                {
+                    let reason =
+                        format!("{} of {} requests failed", inner.failures, inner.requests);
+                    self.open(&mut inner, &reason);
+                }
+            }
+        }
+    }
+
+    fn open(&self, inner: &mut Inner, reason: &str) {
+        println!(
+            "Circuit of upstream {} is open for {}s: {}",
+            self.target, self.config.cooldown, reason
+        );
+        inner.state = CircuitState::Open;
+        inner.opened_at = Instant::now();
+        inner.trials = 0;
+    }
+}
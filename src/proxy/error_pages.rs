@@ -0,0 +1,189 @@
+// src/proxy/error_pages.rs
+use pingora::{Error, ErrorSource, ErrorType};
+use pingora_http::StatusCode;
+use pingora_proxy::Session;
+
+use crate::config::model::{ErrorPage, ErrorPages, read_error_page};
+
+use super::pages::write_page;
+use super::router::RouteContext;
+use super::utils::generate_request_id;
+
+/// Header carrying the id of a request
+pub const REQUEST_ID_HEADER: &str = "X-Request-Id";
+
+/// Status answered for an error, 0 when the client connection is already gone;
+/// the same as pingora's except that upstream timeouts are 504
+pub fn error_status(e: &Error) -> u16 {
+    match e.etype() {
+        ErrorType::HTTPStatus(code) => *code,
+        ErrorType::ConnectTimedout | ErrorType::ReadTimedout | ErrorType::WriteTimedout
+            if *e.esource() == ErrorSource::Upstream =>
+        {
+            504
+        }
+        _ => match e.esource() {
+            ErrorSource::Upstream => 502,
+            ErrorSource::Downstream => match e.etype() {
+                ErrorType::WriteError | ErrorType::ReadError | ErrorType::ConnectionClosed => 0,
+                _ => 400,
+            },
+            ErrorSource::Internal | ErrorSource::Unset => 500,
+        },
+    }
+}
+
+/// Id of the request: the one sent by the client, or a new one
+pub fn request_id(session: &Session) -> String {
+    session
+        .req_header()
+        .headers
+        .get(REQUEST_ID_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .filter(|value| !value.is_empty() && value.len() <= 128)
+        .map(str::to_string)
+        .unwrap_or_else(generate_request_id)
+}
+
+// API clients asking for JSON rather than HTML get JSON errors
+fn prefers_json(session: &Session) -> bool {
+    let Some(accept) = session
+        .req_header()
+        .headers
+        .get("accept")
+        .and_then(|value| value.to_str().ok())
+    else {
+        return false;
+    };
+
+    // Highest quality given to a media type, ties go to the one listed first
+    let preference = |wanted: &dyn Fn(&str) -> bool| {
+        accept
+            .split(',')
+            .enumerate()
+            .filter_map(|(position, media)| {
+                let mut params = media.split(';');
+                if !wanted(params.next().unwrap_or_default().trim()) {
+                    return None;
+                }
+                let quality = params
+                    .filter_map(|param| param.trim().strip_prefix("q="))
+                    .find_map(|q| q.parse::<f32>().ok())
+                    .unwrap_or(1.0);
+                Some((quality, position))
+            })
+            .max_by(|a, b| a.0.total_cmp(&b.0).then(b.1.cmp(&a.1)))
+            .filter(|(quality, _)| *quality > 0.0)
+    };
+    let json = preference(&|media| media == "application/json" || media.ends_with("+json"));
+    let html = preference(&|media| media == "text/html");
+    match (json, html) {
+        (Some(json), Some(html)) => json.0 > html.0 || (json.0 == html.0 && json.1 < html.1),
+        (Some(_), None) => true,
+        _ => false,
+    }
+}
+
+// Page for a status: its own, else the one of its class
+fn find_page(pages: &ErrorPages, status: u16) -> Option<&ErrorPage> {
+    pages
+        .get(&status.to_string())
+        .or_else(|| pages.get(&format!("{}xx", status / 100)))
+}
+
+fn escape_html(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&#39;")
+}
+
+// Fill `{{host}}`, `{{status}}`, `{{reason}}` and `{{request_id}}`
+fn render(template: &str, vars: &[(&str, &str)], html: bool) -> String {
+    vars.iter()
+        .fold(template.to_string(), |page, (name, value)| {
+            let value = if html {
+                escape_html(value)
+            } else {
+                value.to_string()
+            };
+            page.replace(&format!("{{{{{}}}}}", name), &value)
+        })
+}
+
+/// Answer a request that failed, with the route's error page or a JSON error
+/// for API clients; returns the status for logging like pingora's default
+pub async fn respond_error(
+    session: &mut Session,
+    e: &Error,
+    ctx: &mut RouteContext,
+    host: &str,
+) -> u16 {
+    let status = error_status(e);
+    if status == 0 {
+        return 0;
+    }
+
+    if ctx.request_id.is_empty() {
+        ctx.request_id = request_id(session);
+    }
+    let reason = StatusCode::from_u16(status)
+        .ok()
+        .and_then(|code| code.canonical_reason())
+        .unwrap_or("Error");
+    let headers = [(REQUEST_ID_HEADER, ctx.request_id.clone())];
+
+    let result = if prefers_json(session) {
+        let body = serde_json::json!({
+            "status": "error",
+            "error": reason,
+            "code": status,
+            "request_id": ctx.request_id,
+        })
+        .to_string();
+        write_page(session, status, "application/json", &body, &headers).await
+    } else {
+        let page = ctx
+            .target
+            .as_ref()
+            .and_then(|target| find_page(&target.error_pages, status));
+        let template = page.and_then(|page| match (&page.template, &page.file) {
+            (Some(template), _) => Some(template.clone()),
+            (None, Some(file)) => read_error_page(file)
+                .inspect_err(|err| println!("Error reading error page {}: {}", file, err))
+                .ok(),
+            (None, None) => None,
+        });
+
+        match (page, template) {
+            (Some(page), Some(template)) => {
+                let content_type = page
+                    .content_type
+                    .as_deref()
+                    .unwrap_or("text/html; charset=utf-8");
+                let status_text = status.to_string();
+                let body = render(
+                    &template,
+                    &[
+                        ("host", host),
+                        ("status", &status_text),
+                        ("reason", reason),
+                        ("request_id", &ctx.request_id),
+                    ],
+                    content_type.starts_with("text/html"),
+                );
+                write_page(session, status, content_type, &body, &headers).await
+            }
+            // Routes without a page get pingora's empty error response
+            _ => session.respond_error(status).await,
+        }
+    };
+
+    if let Err(err) = result {
+        session.set_keepalive(None);
+        println!("Error sending error response: {}", err);
+    }
+    status
+}
@@ -0,0 +1,64 @@
+// src/proxy/fallback.rs
+use pingora::{Result, prelude::HttpPeer};
+use pingora_proxy::Session;
+
+use super::pages::{NOT_FOUND_PAGE, write_html, write_redirect};
+
+/// What requests for hosts without a mapping get
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Fallback {
+    /// The built-in 404 page, with this status
+    Page(u16),
+    /// Forwarded to this backend
+    Backend(String),
+    /// Redirected to this URL
+    Redirect(String),
+}
+
+impl Default for Fallback {
+    fn default() -> Self {
+        Fallback::Page(404)
+    }
+}
+
+impl Fallback {
+    /// Read from FALLBACK_BACKEND, FALLBACK_REDIRECT or FALLBACK_STATUS, the
+    /// built-in 404 page when none is set
+    pub fn from_env() -> Self {
+        let var = |name: &str| std::env::var(name).ok().filter(|v| !v.trim().is_empty());
+
+        if let Some(backend) = var("FALLBACK_BACKEND") {
+            return Fallback::Backend(backend.trim().to_string());
+        }
+        if let Some(url) = var("FALLBACK_REDIRECT") {
+            return Fallback::Redirect(url.trim().to_string());
+        }
+        match var("FALLBACK_STATUS").map(|status| status.parse::<u16>()) {
+            Some(Ok(status)) if (200..=599).contains(&status) => Fallback::Page(status),
+            Some(_) => {
+                println!("Invalid FALLBACK_STATUS, using 404");
+                Fallback::default()
+            }
+            None => Fallback::default(),
+        }
+    }
+
+    /// Answer a request for an unknown host, false when it goes to the fallback backend
+    pub async fn respond(&self, session: &mut Session) -> Result<bool> {
+        match self {
+            Fallback::Backend(_) => Ok(false),
+            Fallback::Redirect(url) => write_redirect(session, 302, url).await.map(|_| true),
+            Fallback::Page(status) => write_html(session, *status, NOT_FOUND_PAGE, &[])
+                .await
+                .map(|_| true),
+        }
+    }
+
+    /// Peer of the fallback backend
+    pub fn peer(&self) -> Option<HttpPeer> {
+        match self {
+            Fallback::Backend(addr) => Some(HttpPeer::new(addr.as_str(), false, String::new())),
+            _ => None,
+        }
+    }
+}
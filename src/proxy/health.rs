@@ -0,0 +1,149 @@
+// src/proxy/health.rs
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime},
+};
+
+use async_trait::async_trait;
+use pingora::{Error, ErrorType, Result, prelude::HttpPeer};
+use pingora_core::connectors::http::Connector as HttpConnector;
+use pingora_http::RequestHeader;
+use pingora_load_balancing::{Backend, health_check::HealthCheck};
+
+use crate::config::model::{HealthCheckConfig, HealthCheckType};
+
+/// Only this much of a response body is searched for `expected_body`
+const MAX_BODY_SIZE: usize = 64 * 1024;
+
+/// Outcome of the latest check of an upstream
+#[derive(Debug, Clone, Default)]
+pub struct CheckResult {
+    pub last_check: Option<SystemTime>,
+    pub last_error: Option<String>,
+}
+
+/// Check results per resolved upstream address
+pub type CheckResults = Arc<Mutex<HashMap<SocketAddr, CheckResult>>>;
+
+/// TCP or HTTP check of the upstreams of a pool, run by pingora's `Backends`
+pub struct UpstreamHealthCheck {
+    config: HealthCheckConfig,
+    connector: HttpConnector,
+    results: CheckResults,
+}
+
+impl UpstreamHealthCheck {
+    pub fn new(config: HealthCheckConfig, results: CheckResults) -> Self {
+        Self {
+            config,
+            connector: HttpConnector::new(None),
+            results,
+        }
+    }
+
+    async fn check_tcp(&self, target: &Backend) -> Result<()> {
+        let timeout = Duration::from_secs(self.config.timeout);
+        let addr = target.addr.to_string();
+
+        match tokio::time::timeout(timeout, tokio::net::TcpStream::connect(&addr)).await {
+            Ok(Ok(_)) => Ok(()),
+            Ok(Err(e)) => Error::e_explain(ErrorType::ConnectError, e.to_string()),
+            Err(_) => Error::e_explain(ErrorType::ConnectTimedout, format!("after {:?}", timeout)),
+        }
+    }
+
+    async fn check_http(&self, target: &Backend) -> Result<()> {
+        let timeout = Duration::from_secs(self.config.timeout);
+        let host = self
+            .config
+            .host
+            .clone()
+            .unwrap_or_else(|| target.addr.to_string());
+
+        let mut peer = HttpPeer::new(target.addr.to_string(), false, String::new());
+        peer.options.connection_timeout = Some(timeout);
+        peer.options.read_timeout = Some(timeout);
+
+        let mut req = RequestHeader::build("GET", self.config.path.as_bytes(), None)?;
+        req.insert_header("Host", host)?;
+        req.insert_header("User-Agent", "pingora-proxy-health-check")?;
+
+        let (mut session, _) = self.connector.get_http_session(&peer).await?;
+        session.write_request_header(Box::new(req)).await?;
+        session.finish_request_body().await?;
+        session.set_read_timeout(timeout);
+        session.read_response_header().await?;
+
+        let status = session
+            .response_header()
+            .map(|resp| resp.status.as_u16())
+            .unwrap_or_default();
+        if status != self.config.expected_status {
+            return Error::e_explain(
+                ErrorType::CustomCode("unexpected status", status),
+                format!("expected {}", self.config.expected_status),
+            );
+        }
+
+        if let Some(expected) = &self.config.expected_body {
+            let mut body = Vec::new();
+            while let Some(chunk) = session.read_response_body().await? {
+                body.extend_from_slice(&chunk);
+                if body.len() >= MAX_BODY_SIZE {
+                    break;
+                }
+            }
+            if !String::from_utf8_lossy(&body).contains(expected.as_str()) {
+                return Error::e_explain(
+                    ErrorType::Custom("unexpected body"),
+                    format!("body does not contain {:?}", expected),
+                );
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl HealthCheck for UpstreamHealthCheck {
+    fn health_threshold(&self, success: bool) -> usize {
+        if success {
+            self.config.healthy_threshold
+        } else {
+            self.config.unhealthy_threshold
+        }
+    }
+
+    async fn check(&self, target: &Backend) -> Result<()> {
+        let result = match self.config.check_type {
+            HealthCheckType::Tcp => self.check_tcp(target).await,
+            HealthCheckType::Http => self.check_http(target).await,
+        };
+
+        if let (Some(addr), Ok(mut results)) = (target.addr.as_inet(), self.results.lock()) {
+            results.insert(
+                *addr,
+                CheckResult {
+                    last_check: Some(SystemTime::now()),
+                    last_error: result
+                        .as_ref()
+                        .err()
+                        .map(|e| e.to_string().trim().to_string()),
+                },
+            );
+        }
+
+        result
+    }
+
+    async fn health_status_change(&self, target: &Backend, healthy: bool) {
+        println!(
+            "Upstream {} is now {}",
+            target.addr,
+            if healthy { "healthy" } else { "unhealthy" }
+        );
+    }
+}
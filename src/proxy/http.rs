@@ -1,32 +1,52 @@
-use std::{
-    collections::HashMap,
-    fs,
-    path::Path,
-    sync::{Arc, Mutex},
-};
+use std::sync::{Arc, Mutex};
 
 use bytes::Bytes;
-use pingora::{Result, connectors::http, prelude::HttpPeer};
-use pingora_http::StatusCode;
+use pingora::{Result, prelude::HttpPeer};
+use pingora_http::{Method, RequestHeader, ResponseHeader, StatusCode};
 use pingora_proxy::{ProxyHttp, Session};
 
-use crate::proxy::utils::parse_swarm_target;
+use crate::cert::acme::AcmeChallenges;
+use crate::cert::store::CertStore;
+use crate::config::model::ConfigStore;
+use crate::proxy::balancer::UpstreamPools;
+use crate::proxy::error_pages::{REQUEST_ID_HEADER, request_id, respond_error};
+use crate::proxy::fallback::Fallback;
+use crate::proxy::maintenance::{MaintenanceState, respond as respond_maintenance};
+use crate::proxy::pages::write_redirect;
+use crate::proxy::retry::{retry_connect, retry_error, retry_status};
+use crate::proxy::router::{
+    RouteContext, normalize_host, record_connect_failure, record_upstream_outcome, route,
+    select_peer,
+};
+use crate::proxy::tls::TlsConnections;
 
 use super::utils::extract_hostname;
 
 /// HTTP Proxy implementation
 #[derive(Clone)]
 pub struct HttpProxy {
-    pub servers: Arc<Mutex<HashMap<String, String>>>,
+    pub servers: Arc<Mutex<ConfigStore>>,
+    pub pools: UpstreamPools,
+    pub fallback: Fallback,
+    pub maintenance: MaintenanceState,
+    pub challenges: AcmeChallenges,
+    pub cert_store: Arc<CertStore>,
+    /// Only consulted for client addresses, plain HTTP is never in it
+    pub connections: TlsConnections,
 }
 
 #[async_trait::async_trait]
 impl ProxyHttp for HttpProxy {
-    type CTX = ();
+    type CTX = RouteContext;
+
+    fn new_ctx(&self) -> Self::CTX {
+        RouteContext::default()
+    }
 
-    fn new_ctx(&self) -> Self::CTX {}
+    async fn request_filter(&self, session: &mut Session, ctx: &mut Self::CTX) -> Result<bool> {
+        ctx.request_id = request_id(session);
+        ctx.client_ip = self.connections.client_addr(session).map(|addr| addr.ip());
 
-    async fn request_filter(&self, session: &mut Session, _ctx: &mut Self::CTX) -> Result<bool> {
         // Get the path from the request header
         let path = session.req_header().uri.path().to_string(); // Create an owned copy of the path
 
@@ -34,18 +54,15 @@ impl ProxyHttp for HttpProxy {
         if path.starts_with("/.well-known/acme-challenge/") {
             println!("Handling ACME challenge: {}", path);
 
-            let token = path.split('/').last().unwrap_or_default();
+            let token = path.split('/').next_back().unwrap_or_default();
 
             if token.is_empty() {
                 return Err(pingora::Error::new(pingora::ErrorType::HTTPStatus(404)));
             }
 
-            // Read from webroot where certbot expects to find the challenge
-            let challenge_dir = Path::new("/var/www/html/.well-known/acme-challenge");
-            let challenge_file = challenge_dir.join(token);
-
-            match fs::read_to_string(challenge_file) {
-                Ok(proof) => {
+            // Answer with the key authorization the ACME client is waiting on
+            match self.challenges.get(token) {
+                Some(proof) => {
                     let mut res_headers =
                         pingora_http::ResponseHeader::build(StatusCode::OK, None)?;
 
@@ -62,13 +79,64 @@ impl ProxyHttp for HttpProxy {
                     println!("Successfully served ACME challenge for token: {}", token);
                     return Ok(true);
                 }
-                Err(e) => {
-                    println!("Failed to read ACME challenge file: {}", e);
+                None => {
+                    println!("Unknown ACME challenge token: {}", token);
                     return Err(pingora::Error::new(pingora::ErrorType::HTTPStatus(404)));
                 }
             }
         }
 
+        // Routed under the lock, the pool lookup in upstream_peer may have to resolve upstreams
+        let hostname = extract_hostname(&session.request_summary()).unwrap_or_default();
+        let query = session.req_header().uri.query().map(str::to_string);
+        ctx.target = match self.servers.lock() {
+            Ok(servers) => route(&servers, &hostname, &path, query.as_deref()),
+            Err(e) => {
+                println!("Error locking servers mutex in HttpProxy: {:?}", e);
+                None
+            }
+        };
+
+        if ctx.target.is_none() {
+            println!("No backend found for host: {}, using fallback", hostname);
+            return self.fallback.respond(session).await;
+        }
+
+        // Redirect routes answer before the HTTPS redirect, which would only add a hop
+        if let Some(redirect) = ctx.target.as_ref().and_then(|t| t.redirect.as_ref()) {
+            let location = redirect.location("http");
+            println!("Redirecting {} to {}", hostname, location);
+            write_redirect(session, redirect.status, &location).await?;
+            return Ok(true);
+        }
+
+        // Hosts with a valid certificate are only served over HTTPS unless the route opts out
+        let host = normalize_host(&hostname);
+        let force_https = ctx.target.as_ref().and_then(|t| t.force_https);
+        if force_https.unwrap_or_else(|| self.cert_store.has_valid(&host)) {
+            let req = session.req_header();
+            let location = format!(
+                "https://{}{}",
+                host,
+                req.uri.path_and_query().map_or("/", |pq| pq.as_str())
+            );
+            // 308 keeps the method and body of anything but GET and HEAD
+            let status = match req.method {
+                Method::GET | Method::HEAD => 301,
+                _ => 308,
+            };
+            println!("Redirecting {} to {}", hostname, location);
+            write_redirect(session, status, &location).await?;
+            return Ok(true);
+        }
+
+        // Clients on the allow-list or with the bypass token still reach the backend
+        let maintenance = ctx.target.as_ref().and_then(|t| t.maintenance.as_ref());
+        if respond_maintenance(session, maintenance, &self.maintenance, ctx.client_ip).await? {
+            println!("Host {} is in maintenance", hostname);
+            return Ok(true);
+        }
+
         // Continue with normal request processing
         Ok(false)
     }
@@ -77,52 +145,110 @@ impl ProxyHttp for HttpProxy {
     async fn upstream_peer(
         &self,
         session: &mut Session,
-        _ctx: &mut Self::CTX,
+        ctx: &mut Self::CTX,
     ) -> Result<Box<HttpPeer>> {
+        let Some(target) = ctx.target.clone() else {
+            // Unknown hosts only get here with a fallback backend
+            return self
+                .fallback
+                .peer()
+                .map(Box::new)
+                .ok_or_else(|| pingora::Error::new(pingora::ErrorType::HTTPStatus(404)));
+        };
         let hostname = extract_hostname(&session.request_summary()).unwrap_or_default();
 
-        match self.servers.lock() {
-            Ok(servers) => match servers.get(&hostname) {
-                Some(to) => {
-                    println!("Routing HTTP request to backend: {}", to);
-
-                    // Parse swarm target if needed
-                    let (target, use_tls, org_header) = if to.contains(".") && to.contains(":") {
-                        // Likely a swarm DNS name
-                        let (host, port, org_id) = parse_swarm_target(to);
-                        (format!("{}:{}", host, port), false, org_id)
-                    } else {
-                        // Standard target
-                        (to.to_owned(), false, None)
-                    };
-
-                    // Create HTTP peer
-                    let mut peer = HttpPeer::new(target, use_tls, hostname.to_string());
-
-                    // Add organization header if present
-                    if let Some(org) = org_header {
-                        // Access the correct location for extra headers in PeerOptions
-                        peer.options.extra_proxy_headers.insert(
-                            "X-Organization-ID".to_string(),
-                            org.to_string().into_bytes(),
-                        );
-                    }
-
-                    Ok(Box::new(peer))
-                }
-                None => {
-                    // Default backend when no matching host is found
-                    println!("No backend found for host: {}", hostname);
-                    let res = HttpPeer::new("127.0.0.1:5500", false, "".to_string());
-                    Ok(Box::new(res))
-                }
-            },
-            Err(e) => {
-                println!("Error locking servers mutex in HttpProxy: {:?}", e);
-                // Return default backend on lock error
-                let res = HttpPeer::new("127.0.0.1:5500", false, "".to_string());
-                Ok(Box::new(res))
+        println!("Routing HTTP request to backend: {}", target.to);
+        let peer = select_peer(&self.pools, &target, session, &hostname, ctx).await?;
+
+        ctx.upstream_uri = target.upstream_uri;
+        Ok(Box::new(peer))
+    }
+
+    async fn upstream_request_filter(
+        &self,
+        _session: &mut Session,
+        upstream_request: &mut RequestHeader,
+        ctx: &mut Self::CTX,
+    ) -> Result<()> {
+        upstream_request.insert_header(REQUEST_ID_HEADER, &ctx.request_id)?;
+
+        // Forward the path rewritten by the matching path rule
+        if let Some(uri) = ctx.upstream_uri.take() {
+            match uri.parse() {
+                Ok(uri) => upstream_request.set_uri(uri),
+                Err(e) => println!("Invalid rewritten path {}: {:?}", uri, e),
             }
         }
+
+        Ok(())
+    }
+
+    fn upstream_response_filter(
+        &self,
+        _session: &mut Session,
+        upstream_response: &mut ResponseHeader,
+        ctx: &mut Self::CTX,
+    ) {
+        ctx.upstream_status = Some(upstream_response.status.as_u16());
+    }
+
+    async fn response_filter(
+        &self,
+        session: &mut Session,
+        upstream_response: &mut ResponseHeader,
+        ctx: &mut Self::CTX,
+    ) -> Result<()> {
+        retry_status(ctx, session, upstream_response.status.as_u16())?;
+
+        // Pin the client to the upstream that answered
+        if let Some(cookie) = ctx.set_cookie.take() {
+            upstream_response.append_header("Set-Cookie", cookie)?;
+        }
+        Ok(())
+    }
+
+    async fn fail_to_proxy(
+        &self,
+        session: &mut Session,
+        e: &pingora::Error,
+        ctx: &mut Self::CTX,
+    ) -> u16 {
+        let hostname = extract_hostname(&session.request_summary()).unwrap_or_default();
+        respond_error(session, e, ctx, &hostname).await
+    }
+
+    fn fail_to_connect(
+        &self,
+        session: &mut Session,
+        _peer: &HttpPeer,
+        ctx: &mut Self::CTX,
+        e: Box<pingora::Error>,
+    ) -> Box<pingora::Error> {
+        record_connect_failure(ctx);
+        retry_connect(ctx, session, e)
+    }
+
+    fn error_while_proxy(
+        &self,
+        peer: &HttpPeer,
+        session: &mut Session,
+        e: Box<pingora::Error>,
+        ctx: &mut Self::CTX,
+        client_reused: bool,
+    ) -> Box<pingora::Error> {
+        // Same as the default, pooled connections that broke are retried
+        let mut e = e.more_context(format!("Peer: {}", peer));
+        e.retry
+            .decide_reuse(client_reused && !session.as_ref().retry_buffer_truncated());
+        retry_error(ctx, session, e)
+    }
+
+    async fn logging(
+        &self,
+        _session: &mut Session,
+        error: Option<&pingora::Error>,
+        ctx: &mut Self::CTX,
+    ) {
+        record_upstream_outcome(ctx, error);
     }
 }
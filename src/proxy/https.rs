@@ -1,35 +1,82 @@
-use std::{
-    collections::HashMap,
-    sync::{Arc, Mutex},
-};
+use std::sync::{Arc, Mutex};
 
 use pingora::{Result, prelude::HttpPeer};
+use pingora_http::{RequestHeader, ResponseHeader};
 use pingora_proxy::{ProxyHttp, Session};
 
-use crate::proxy::utils::parse_swarm_target;
+use crate::config::model::ConfigStore;
+use crate::proxy::balancer::UpstreamPools;
+use crate::proxy::error_pages::{REQUEST_ID_HEADER, request_id, respond_error};
+use crate::proxy::fallback::Fallback;
+use crate::proxy::maintenance::{MaintenanceState, respond as respond_maintenance};
+use crate::proxy::pages::write_redirect;
+use crate::proxy::{
+    retry::{retry_connect, retry_error, retry_status},
+    router::{RouteContext, record_connect_failure, record_upstream_outcome, route, select_peer},
+    tls::TlsConnections,
+};
 
 use super::utils::extract_hostname;
 
 /// HTTPS Proxy implementation
 #[derive(Clone)]
 pub struct HttpsProxy {
-    pub servers: Arc<Mutex<HashMap<String, String>>>,
+    pub servers: Arc<Mutex<ConfigStore>>,
+    pub pools: UpstreamPools,
+    pub fallback: Fallback,
+    pub maintenance: MaintenanceState,
+    pub connections: TlsConnections,
 }
 
 #[async_trait::async_trait]
 impl ProxyHttp for HttpsProxy {
-    type CTX = ();
+    type CTX = RouteContext;
+
+    fn new_ctx(&self) -> Self::CTX {
+        RouteContext::default()
+    }
 
-    fn new_ctx(&self) -> Self::CTX {}
+    async fn request_filter(&self, session: &mut Session, ctx: &mut Self::CTX) -> Result<bool> {
+        ctx.request_id = request_id(session);
+        ctx.client_ip = self.connections.client_addr(session).map(|addr| addr.ip());
+        ctx.secure = true;
 
-    async fn request_filter(&self, session: &mut Session, _ctx: &mut Self::CTX) -> Result<bool> {
         // For HTTPS, we don't need to handle ACME challenges (they're HTTP-only)
         // This is just a placeholder for any HTTPS-specific request filtering
 
-        // Extract hostname for logging purposes
+        // Routed under the lock, the pool lookup in upstream_peer may have to resolve upstreams
         let hostname = extract_hostname(&session.request_summary()).unwrap_or_default();
         println!("HTTPS request for hostname: {}", hostname);
 
+        let path = session.req_header().uri.path().to_string();
+        let query = session.req_header().uri.query().map(str::to_string);
+        ctx.target = match self.servers.lock() {
+            Ok(servers) => route(&servers, &hostname, &path, query.as_deref()),
+            Err(e) => {
+                println!("Error locking servers mutex in HttpsProxy: {:?}", e);
+                None
+            }
+        };
+
+        if ctx.target.is_none() {
+            println!("No backend found for host: {}, using fallback", hostname);
+            return self.fallback.respond(session).await;
+        }
+
+        if let Some(redirect) = ctx.target.as_ref().and_then(|t| t.redirect.as_ref()) {
+            let location = redirect.location("https");
+            println!("Redirecting {} to {}", hostname, location);
+            write_redirect(session, redirect.status, &location).await?;
+            return Ok(true);
+        }
+
+        // Clients on the allow-list or with the bypass token still reach the backend
+        let maintenance = ctx.target.as_ref().and_then(|t| t.maintenance.as_ref());
+        if respond_maintenance(session, maintenance, &self.maintenance, ctx.client_ip).await? {
+            println!("Host {} is in maintenance", hostname);
+            return Ok(true);
+        }
+
         // Return false to continue normal request processing
         Ok(false)
     }
@@ -37,68 +84,128 @@ impl ProxyHttp for HttpsProxy {
     async fn upstream_peer(
         &self,
         session: &mut Session,
-        _ctx: &mut Self::CTX,
+        ctx: &mut Self::CTX,
     ) -> Result<Box<HttpPeer>> {
+        let Some(target) = ctx.target.clone() else {
+            // Unknown hosts only get here with a fallback backend
+            return self
+                .fallback
+                .peer()
+                .map(Box::new)
+                .ok_or_else(|| pingora::Error::new(pingora::ErrorType::HTTPStatus(404)));
+        };
         let hostname = extract_hostname(&session.request_summary()).unwrap_or_default();
 
-        match self.servers.lock() {
-            Ok(servers) => match servers.get(&hostname) {
-                Some(to) => {
-                    println!("Routing HTTPS request to backend: {}", to);
-
-                    // Parse swarm target if needed
-                    let (target, use_tls, org_header) = if to.contains(".") && to.contains(":") {
-                        // Likely a swarm DNS name
-                        let (host, port, org_id) = parse_swarm_target(to);
-                        (format!("{}:{}", host, port), false, org_id)
-                    } else {
-                        // Standard target
-                        (to.to_owned(), false, None)
-                    };
-
-                    // Create HTTPS peer
-                    let mut peer = HttpPeer::new(target, use_tls, hostname.to_string());
-
-                    // Add organization header if present
-                    if let Some(org) = org_header {
-                        // Use the correct field: options.extra_proxy_headers instead of extra_headers
-                        peer.options.extra_proxy_headers.insert(
-                            "X-Organization-ID".to_string(),
-                            org.to_string().into_bytes(),
-                        );
-                    }
-
-                    Ok(Box::new(peer))
-                }
-                None => {
-                    println!("No backend found for hostname: {}, using default", hostname);
-                    // Default backend when no matching host is found
-                    let res = HttpPeer::new("127.0.0.1:5500", false, "".to_string());
-                    Ok(Box::new(res))
-                }
-            },
-            Err(e) => {
-                println!("Error locking servers mutex in HttpsProxy: {:?}", e);
-                // Return default backend on lock error
-                let res = HttpPeer::new("127.0.0.1:5500", false, "".to_string());
-                Ok(Box::new(res))
+        println!("Routing HTTPS request to backend: {}", target.to);
+        let peer = select_peer(&self.pools, &target, session, &hostname, ctx).await?;
+
+        ctx.upstream_uri = target.upstream_uri;
+        Ok(Box::new(peer))
+    }
+
+    async fn upstream_request_filter(
+        &self,
+        _session: &mut Session,
+        upstream_request: &mut RequestHeader,
+        ctx: &mut Self::CTX,
+    ) -> Result<()> {
+        upstream_request.insert_header(REQUEST_ID_HEADER, &ctx.request_id)?;
+
+        // Forward the path rewritten by the matching path rule
+        if let Some(uri) = ctx.upstream_uri.take() {
+            match uri.parse() {
+                Ok(uri) => upstream_request.set_uri(uri),
+                Err(e) => println!("Invalid rewritten path {}: {:?}", uri, e),
             }
         }
+
+        Ok(())
+    }
+
+    fn upstream_response_filter(
+        &self,
+        _session: &mut Session,
+        upstream_response: &mut ResponseHeader,
+        ctx: &mut Self::CTX,
+    ) {
+        ctx.upstream_status = Some(upstream_response.status.as_u16());
+    }
+
+    async fn response_filter(
+        &self,
+        session: &mut Session,
+        upstream_response: &mut ResponseHeader,
+        ctx: &mut Self::CTX,
+    ) -> Result<()> {
+        retry_status(ctx, session, upstream_response.status.as_u16())?;
+
+        // Pin the client to the upstream that answered
+        if let Some(cookie) = ctx.set_cookie.take() {
+            upstream_response.append_header("Set-Cookie", cookie)?;
+        }
+
+        if let Some(hsts) = ctx.target.as_ref().and_then(|t| t.hsts.as_ref()) {
+            upstream_response.insert_header("Strict-Transport-Security", hsts.header_value())?;
+        }
+        Ok(())
+    }
+
+    async fn fail_to_proxy(
+        &self,
+        session: &mut Session,
+        e: &pingora::Error,
+        ctx: &mut Self::CTX,
+    ) -> u16 {
+        let hostname = extract_hostname(&session.request_summary()).unwrap_or_default();
+        respond_error(session, e, ctx, &hostname).await
+    }
+
+    fn fail_to_connect(
+        &self,
+        session: &mut Session,
+        _peer: &HttpPeer,
+        ctx: &mut Self::CTX,
+        e: Box<pingora::Error>,
+    ) -> Box<pingora::Error> {
+        record_connect_failure(ctx);
+        retry_connect(ctx, session, e)
+    }
+
+    fn error_while_proxy(
+        &self,
+        peer: &HttpPeer,
+        session: &mut Session,
+        e: Box<pingora::Error>,
+        ctx: &mut Self::CTX,
+        client_reused: bool,
+    ) -> Box<pingora::Error> {
+        // Same as the default, pooled connections that broke are retried
+        let mut e = e.more_context(format!("Peer: {}", peer));
+        e.retry
+            .decide_reuse(client_reused && !session.as_ref().retry_buffer_truncated());
+        retry_error(ctx, session, e)
     }
 
     // Optional: Add a logging method to track HTTPS requests
     async fn logging(
         &self,
         session: &mut Session,
-        _error: Option<&pingora::Error>,
-        _ctx: &mut Self::CTX,
+        error: Option<&pingora::Error>,
+        ctx: &mut Self::CTX,
     ) {
+        record_upstream_outcome(ctx, error);
+
         if let Some(response) = session.response_written() {
             let status = response.status;
             let hostname = extract_hostname(&session.request_summary()).unwrap_or_default();
             println!(
-                "HTTPS request completed: host={}, status={}",
-                hostname, status
+                "HTTPS request completed: host={}, status={}, client={:?}, sni={:?}",
+                hostname,
+                status,
+                self.connections.client_addr(session),
+                self.connections
+                    .lookup(session)
+                    .and_then(|info| info.server_name)
             );
         }
     }
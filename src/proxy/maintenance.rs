@@ -0,0 +1,101 @@
+// src/proxy/maintenance.rs
+use std::{
+    net::IpAddr,
+    sync::{Arc, Mutex},
+};
+
+use pingora::Result;
+use pingora_proxy::Session;
+
+use crate::config::model::MaintenanceConfig;
+
+use super::pages::{MAINTENANCE_PAGE, write_html};
+
+/// Header whose value lets a request through maintenance mode
+pub const BYPASS_HEADER: &str = "X-Maintenance-Bypass";
+
+/// Cookie whose value lets a request through maintenance mode
+pub const BYPASS_COOKIE: &str = "maintenance_bypass";
+
+/// Maintenance mode of every host, shared by the proxies and the manager
+pub type MaintenanceState = Arc<Mutex<Option<MaintenanceConfig>>>;
+
+// Whether `ip` is one of the addresses or within one of the CIDR ranges
+fn ip_allowed(ip: IpAddr, entries: &[String]) -> bool {
+    entries.iter().any(|entry| {
+        let (addr, prefix) = match entry.split_once('/') {
+            Some((addr, prefix)) => (addr, prefix.parse::<u32>().ok()),
+            None => (entry.as_str(), None),
+        };
+        let Ok(addr) = addr.trim().parse::<IpAddr>() else {
+            return false;
+        };
+        match (ip, addr) {
+            (IpAddr::V4(ip), IpAddr::V4(addr)) => {
+                let prefix = prefix.unwrap_or(32).min(32);
+                let mask = u32::MAX.checked_shl(32 - prefix).unwrap_or(0);
+                u32::from(ip) & mask == u32::from(addr) & mask
+            }
+            (IpAddr::V6(ip), IpAddr::V6(addr)) => {
+                let prefix = prefix.unwrap_or(128).min(128);
+                let mask = u128::MAX.checked_shl(128 - prefix).unwrap_or(0);
+                u128::from(ip) & mask == u128::from(addr) & mask
+            }
+            _ => false,
+        }
+    })
+}
+
+// Whether the request may still reach the backend
+fn bypassed(config: &MaintenanceConfig, session: &Session, client_ip: Option<IpAddr>) -> bool {
+    if client_ip.is_some_and(|ip| ip_allowed(ip, &config.allow_ips)) {
+        return true;
+    }
+
+    let Some(token) = &config.bypass_token else {
+        return false;
+    };
+    let headers = &session.req_header().headers;
+    let header = headers
+        .get(BYPASS_HEADER)
+        .and_then(|value| value.to_str().ok());
+    let cookie = headers
+        .get_all("cookie")
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(';'))
+        .filter_map(|cookie| cookie.trim().split_once('='))
+        .find(|(name, _)| *name == BYPASS_COOKIE)
+        .map(|(_, value)| value);
+    header == Some(token.as_str()) || cookie == Some(token.as_str())
+}
+
+/// Answer with the maintenance page when the route, or every host when the
+/// route has no setting of its own, is in maintenance; true when answered
+pub async fn respond(
+    session: &mut Session,
+    route: Option<&MaintenanceConfig>,
+    global: &MaintenanceState,
+    client_ip: Option<IpAddr>,
+) -> Result<bool> {
+    let config = match route {
+        Some(config) => Some(config.clone()),
+        None => global.lock().ok().and_then(|global| global.clone()),
+    };
+    let Some(config) = config.filter(|config| config.enabled) else {
+        return Ok(false);
+    };
+    if bypassed(&config, session, client_ip) {
+        return Ok(false);
+    }
+
+    let retry_after = config.retry_after.to_string();
+    write_html(
+        session,
+        503,
+        MAINTENANCE_PAGE,
+        &[("Retry-After", retry_after)],
+    )
+    .await?;
+    Ok(true)
+}
@@ -1,21 +1,144 @@
 // src/proxy/manager.rs
-use std::{
-    collections::HashMap,
-    sync::{Arc, Mutex},
-};
+use std::sync::{Arc, Mutex};
 
 use bytes::Bytes;
 use pingora::{Result, http, prelude::HttpPeer};
 use pingora_http::ResponseHeader;
 use pingora_proxy::{ProxyHttp, Session};
+use serde::{Serialize, de::DeserializeOwned};
 
 use crate::cert::issuer::{CertificateIssuer, CertificateRequest, CertificateStatus};
-use crate::config::file_manager::{create_mappings_from_store, update_config};
+use crate::cert::store::CertStore;
+use crate::config::audit::{AuditEntry, AuditLog, AuditOrigin};
+use crate::config::file_manager::{create_mappings_from_store, save_config, update_config};
+use crate::config::model::{
+    ConfigStore, Configuration, ErrorPages, HstsConfig, MaintenanceConfig, PathRule, PoolConfig,
+    RedirectConfig, RewriteRule, ServerMapping, validate_error_pages,
+};
+use crate::proxy::api::{
+    API_PREFIX, config_etag, is_api_path, version_matches, version_mismatch_message, write_json,
+};
+use crate::proxy::auth::{AuthError, Identity, ManagerAuth, Permission};
+use crate::proxy::balancer::{UpstreamPools, configured_pools};
+use crate::proxy::maintenance::MaintenanceState;
+use crate::proxy::tls::TlsConnections;
+use crate::services::cert_renewal::RenewalState;
 
 /// Manager Proxy for configuration endpoints
 #[derive(Clone)]
 pub struct ManagerProxy {
-    pub servers: Arc<Mutex<HashMap<String, String>>>,
+    pub servers: Arc<Mutex<ConfigStore>>,
+    pub cert_store: Arc<CertStore>,
+    pub issuer: Arc<CertificateIssuer>,
+    pub renewals: RenewalState,
+    pub pools: UpstreamPools,
+    pub maintenance: MaintenanceState,
+    pub auth: ManagerAuth,
+    pub connections: TlsConnections,
+    pub audit: AuditLog,
+}
+
+/// Part of a mapping managed through its own endpoint, `/{domain}/{name}`
+struct MappingSection<T> {
+    name: &'static str,
+    get: fn(&ServerMapping) -> T,
+    set: fn(&mut ServerMapping, T),
+    validate: fn(&T) -> std::result::Result<(), String>,
+    /// PUT on an unknown domain adds a mapping for it
+    create: bool,
+}
+
+const PATHS_SECTION: MappingSection<Vec<PathRule>> = MappingSection {
+    name: "paths",
+    get: |mapping| mapping.paths.clone(),
+    set: |mapping, paths| mapping.paths = paths,
+    validate: |paths| paths.iter().try_for_each(PathRule::validate),
+    create: false,
+};
+
+const POOL_SECTION: MappingSection<PoolConfig> = MappingSection {
+    name: "pool",
+    get: |mapping| mapping.pool.clone(),
+    set: |mapping, pool| mapping.pool = pool,
+    validate: PoolConfig::validate,
+    create: false,
+};
+
+const MAINTENANCE_SECTION: MappingSection<Option<MaintenanceConfig>> = MappingSection {
+    name: "maintenance",
+    get: |mapping| mapping.maintenance.clone(),
+    set: |mapping, maintenance| mapping.maintenance = maintenance,
+    validate: |maintenance| {
+        maintenance
+            .as_ref()
+            .map_or(Ok(()), MaintenanceConfig::validate)
+    },
+    create: false,
+};
+
+const ERROR_PAGES_SECTION: MappingSection<ErrorPages> = MappingSection {
+    name: "error_pages",
+    get: |mapping| mapping.error_pages.clone(),
+    set: |mapping, pages| mapping.error_pages = pages,
+    validate: validate_error_pages,
+    create: false,
+};
+
+const FORCE_HTTPS_SECTION: MappingSection<Option<bool>> = MappingSection {
+    name: "force_https",
+    get: |mapping| mapping.force_https,
+    set: |mapping, force_https| mapping.force_https = force_https,
+    validate: |_| Ok(()),
+    create: false,
+};
+
+const HSTS_SECTION: MappingSection<Option<HstsConfig>> = MappingSection {
+    name: "hsts",
+    get: |mapping| mapping.hsts.clone(),
+    set: |mapping, hsts| mapping.hsts = hsts,
+    validate: |hsts| hsts.as_ref().map_or(Ok(()), HstsConfig::validate),
+    create: false,
+};
+
+const REDIRECT_SECTION: MappingSection<Option<RedirectConfig>> = MappingSection {
+    name: "redirect",
+    get: |mapping| mapping.redirect.clone(),
+    set: |mapping, redirect| mapping.redirect = redirect,
+    validate: |redirect| redirect.as_ref().map_or(Ok(()), RedirectConfig::validate),
+    // A redirect is a route of its own, it needs no backend
+    create: true,
+};
+
+const REWRITES_SECTION: MappingSection<Vec<RewriteRule>> = MappingSection {
+    name: "rewrites",
+    get: |mapping| mapping.rewrites.clone(),
+    set: |mapping, rewrites| mapping.rewrites = rewrites,
+    validate: |rewrites| rewrites.iter().try_for_each(RewriteRule::validate),
+    create: false,
+};
+
+// Access a manager request needs, every role may read
+fn required_permission(method: &str, path: &str) -> Permission {
+    if method == "GET" || method == "HEAD" {
+        return Permission::Read;
+    }
+    let path = match is_api_path(path) {
+        true => path.strip_prefix(API_PREFIX).unwrap_or_default(),
+        false => path,
+    };
+    let endpoint = path
+        .trim_start_matches('/')
+        .split('/')
+        .next()
+        .unwrap_or_default()
+        .trim_end_matches(',');
+    if endpoint.starts_with("certificates") {
+        Permission::Certificates
+    } else if endpoint == "maintenance" || endpoint == "config" {
+        Permission::Global
+    } else {
+        Permission::Routes
+    }
 }
 
 impl ManagerProxy {
@@ -29,6 +152,9 @@ impl ManagerProxy {
         let mut resp = ResponseHeader::build(status, None)?;
         resp.insert_header("content-type", "application/json")?;
         resp.insert_header("connection", "close")?;
+        if status.is_success() {
+            resp.insert_header("etag", config_etag())?;
+        }
 
         let body_bytes = json.as_bytes();
         session.write_response_header(Box::new(resp), false).await?;
@@ -48,76 +174,126 @@ impl ManagerProxy {
         status: http::StatusCode,
         message: &str,
     ) -> Result<bool> {
-        let error_json = format!("{{\"status\":\"error\",\"error\":\"{}\"}}", message);
+        let error_json = serde_json::json!({
+            "status": "error",
+            "error": message,
+        })
+        .to_string();
         self.respond_with_json(session, status, &error_json).await
     }
 
+    // 401 or 403, in the error format of the requested endpoint
+    async fn respond_auth_error(&self, session: &mut Session, error: AuthError) -> Result<bool> {
+        let status =
+            http::StatusCode::from_u16(error.status()).unwrap_or(http::StatusCode::UNAUTHORIZED);
+        let body = if is_api_path(session.req_header().uri.path()) {
+            serde_json::json!({ "error": { "code": error.code(), "message": error.message() } })
+        } else {
+            serde_json::json!({ "status": "error", "error": error.message() })
+        };
+        let headers = error
+            .challenge()
+            .map(|challenge| ("WWW-Authenticate", challenge.to_string()))
+            .into_iter()
+            .collect();
+        write_json(session, status, body, headers).await
+    }
+
+    // Record a change made through the manager in the audit log
+    pub(crate) fn record_change<T: Serialize>(
+        &self,
+        identity: &Identity,
+        action: &str,
+        target: &str,
+        old: Option<&T>,
+        new: Option<&T>,
+    ) {
+        self.audit.record(
+            AuditEntry::new(AuditOrigin::Api, action, target)
+                .by(&identity.principal, identity.source_ip)
+                .change(old, new),
+        );
+    }
+
+    // Read the request body chunks directly
+    pub(crate) async fn read_request_body(&self, session: &mut Session) -> Result<Vec<u8>> {
+        let mut body = Vec::new();
+        while let Some(chunk) = session.downstream_session.read_request_body().await? {
+            body.extend_from_slice(&chunk);
+        }
+        Ok(body)
+    }
+
     // Handle certificate requests
     async fn handle_certificate_request(
         &self,
         session: &mut Session,
         method: &str,
         path_segments: &[String],
+        identity: &Identity,
     ) -> Result<bool> {
         match method {
             // Request a new certificate
             "POST" => {
-                // Read the request body chunks directly
-                let mut body = Vec::new();
-                loop {
-                    match session.downstream_session.read_request_body().await {
-                        Ok(Some(chunk)) => {
-                            body.extend_from_slice(&chunk);
-                        }
-                        Ok(None) => {
-                            // End of body
-                            break;
-                        }
-                        Err(e) => {
-                            return self
-                                .respond_with_error(
-                                    session,
-                                    http::StatusCode::BAD_REQUEST,
-                                    &format!("Failed to read request body: {}", e),
-                                )
-                                .await;
-                        }
-                    }
-                }
-
-                // Parse certificate request
-                let request: CertificateRequest = match serde_json::from_slice(&body) {
-                    Ok(req) => req,
+                let body = match self.read_request_body(session).await {
+                    Ok(body) => body,
                     Err(e) => {
                         return self
                             .respond_with_error(
                                 session,
                                 http::StatusCode::BAD_REQUEST,
-                                &format!("Invalid request format: {}", e),
+                                &format!("Failed to read request body: {}", e),
                             )
                             .await;
                     }
                 };
 
-                // Process the certificate request
-                let issuer = match CertificateIssuer::new("certbot/letsencrypt", "certs") {
-                    Ok(issuer) => issuer,
+                // Parse certificate request
+                let request: CertificateRequest = match serde_json::from_slice(&body) {
+                    Ok(req) => req,
                     Err(e) => {
                         return self
                             .respond_with_error(
                                 session,
-                                http::StatusCode::INTERNAL_SERVER_ERROR,
-                                &format!("Certificate issuer initialization failed: {}", e),
+                                http::StatusCode::BAD_REQUEST,
+                                &format!("Invalid request format: {}", e),
                             )
                             .await;
                     }
                 };
 
+                // Organization tokens only get certificates for their own routes
+                let permitted = self.servers.lock().is_ok_and(|servers| {
+                    request
+                        .names()
+                        .iter()
+                        .all(|name| identity.can_access_domain(&servers, name))
+                });
+                if !permitted {
+                    return self
+                        .respond_auth_error(session, AuthError::NotPermitted)
+                        .await;
+                }
+
                 println!(
                     "Processing certificate request for domain: {}",
                     request.domain
                 );
-                let status = issuer.process_request(request).await;
+                let domain = request.domain.clone();
+                let previous = self.issuer.check_certificate(&domain);
+                let status = self.issuer.process_request(request).await;
+
+                // Serve the new certificate right away instead of waiting for the watcher
+                if status.error.is_none() {
+                    self.cert_store.reload();
+                    self.record_change(
+                        identity,
+                        "certificate.issue",
+                        &domain,
+                        previous.as_ref(),
+                        Some(&status),
+                    );
+                }
 
                 // Respond with the result
                 let response_json = serde_json::to_string(&status).unwrap_or_else(|_| {
@@ -140,31 +316,47 @@ impl ManagerProxy {
 
             // Check certificate status
             "GET" => {
-                if path_segments.len() < 3 {
-                    return self
-                        .respond_with_error(
-                            session,
-                            http::StatusCode::BAD_REQUEST,
-                            "Domain parameter required",
+                // Without a domain, list the renewal state of every mapped domain
+                if path_segments.len() < 3 || path_segments[2].is_empty() {
+                    // Copy the state out so no lock guard is held across an await
+                    let renewals = self.servers.lock().ok().and_then(|servers| {
+                        let renewals = self.renewals.lock().ok()?;
+                        Some(
+                            renewals
+                                .values()
+                                .filter(|r| identity.can_access_domain(&servers, &r.domain))
+                                .cloned()
+                                .collect::<Vec<_>>(),
                         )
-                        .await;
-                }
-
-                let domain = &path_segments[2];
-                let issuer = match CertificateIssuer::new("certbot/letsencrypt", "certs") {
-                    Ok(issuer) => issuer,
-                    Err(e) => {
+                    });
+                    let Some(mut renewals) = renewals else {
                         return self
                             .respond_with_error(
                                 session,
                                 http::StatusCode::INTERNAL_SERVER_ERROR,
-                                &format!("Certificate issuer initialization failed: {}", e),
+                                "Failed to acquire lock on renewal state",
                             )
                             .await;
-                    }
-                };
+                    };
+                    renewals.sort_by(|a, b| a.domain.cmp(&b.domain));
+
+                    let response_json = serde_json::to_string(&serde_json::json!({
+                        "status": "success",
+                        "certificates": renewals,
+                    }))
+                    .unwrap_or_default();
+                    return self
+                        .respond_with_json(session, http::StatusCode::OK, &response_json)
+                        .await;
+                }
 
-                let status = match issuer.check_certificate(domain) {
+                let domain = &path_segments[2];
+                // Domains of other organizations look unknown
+                let visible = self
+                    .servers
+                    .lock()
+                    .is_ok_and(|servers| identity.can_access_domain(&servers, domain));
+                let status = match self.issuer.check_certificate(domain).filter(|_| visible) {
                     Some(status) => status,
                     None => CertificateStatus {
                         domain: domain.clone(),
@@ -173,6 +365,7 @@ impl ManagerProxy {
                         key_path: None,
                         expiry: None,
                         error: None,
+                        certificate: None,
                     },
                 };
 
@@ -192,6 +385,365 @@ impl ManagerProxy {
             }
         }
     }
+
+    // Report the health of every pool that has been built, either by a request
+    // or by its first health check
+    async fn handle_health_request(
+        &self,
+        session: &mut Session,
+        method: &str,
+        identity: &Identity,
+    ) -> Result<bool> {
+        if method != "GET" {
+            return self
+                .respond_with_error(
+                    session,
+                    http::StatusCode::METHOD_NOT_ALLOWED,
+                    "Method not allowed for health endpoint",
+                )
+                .await;
+        }
+
+        let Some(configs) = self.servers.lock().ok().map(|store| {
+            let mut pools = configured_pools(&store);
+            pools.retain(|(domain, _, _)| identity.can_access_domain(&store, domain));
+            pools
+        }) else {
+            return self
+                .respond_with_error(
+                    session,
+                    http::StatusCode::INTERNAL_SERVER_ERROR,
+                    "Failed to acquire lock on server configuration",
+                )
+                .await;
+        };
+
+        let pools: Vec<_> = configs
+            .into_iter()
+            .filter_map(|(domain, path, config)| {
+                let pool = self.pools.peek(&config)?;
+                Some(serde_json::json!({
+                    "domain": domain,
+                    "path": path,
+                    "health_checked": config.health_check.is_some(),
+                    "upstreams": pool.health(),
+                }))
+            })
+            .collect();
+
+        let response_json = serde_json::to_string(&serde_json::json!({
+            "status": "success",
+            "pools": pools,
+        }))
+        .unwrap_or_default();
+        self.respond_with_json(session, http::StatusCode::OK, &response_json)
+            .await
+    }
+
+    // Show or change the maintenance mode of every host, DELETE turns it off
+    async fn handle_maintenance_request(
+        &self,
+        session: &mut Session,
+        method: &str,
+        identity: &Identity,
+    ) -> Result<bool> {
+        match method {
+            "GET" => {
+                let Ok(maintenance) = self.maintenance.lock().map(|m| m.clone()) else {
+                    return self
+                        .respond_with_error(
+                            session,
+                            http::StatusCode::INTERNAL_SERVER_ERROR,
+                            "Failed to acquire lock on maintenance mode",
+                        )
+                        .await;
+                };
+                let response_json = serde_json::to_string(&serde_json::json!({
+                    "status": "success",
+                    "maintenance": maintenance,
+                }))
+                .unwrap_or_default();
+                self.respond_with_json(session, http::StatusCode::OK, &response_json)
+                    .await
+            }
+
+            "PUT" | "DELETE" => {
+                let maintenance = if method == "PUT" {
+                    let body = match self.read_request_body(session).await {
+                        Ok(body) => body,
+                        Err(e) => {
+                            return self
+                                .respond_with_error(
+                                    session,
+                                    http::StatusCode::BAD_REQUEST,
+                                    &format!("Failed to read request body: {}", e),
+                                )
+                                .await;
+                        }
+                    };
+                    match serde_json::from_slice::<MaintenanceConfig>(&body) {
+                        Ok(maintenance) => Some(maintenance),
+                        Err(e) => {
+                            return self
+                                .respond_with_error(
+                                    session,
+                                    http::StatusCode::BAD_REQUEST,
+                                    &format!("Invalid request format: {}", e),
+                                )
+                                .await;
+                        }
+                    }
+                } else {
+                    None
+                };
+
+                if let Some(Err(e)) = maintenance.as_ref().map(MaintenanceConfig::validate) {
+                    return self
+                        .respond_with_error(session, http::StatusCode::BAD_REQUEST, &e)
+                        .await;
+                }
+
+                // Saved with the mappings so it survives restarts, and switched
+                // under the same lock so a config reload never sees half of it
+                let previous = match self.servers.lock() {
+                    Ok(_) if !version_matches(session) => None,
+                    Ok(servers) => {
+                        let config = Configuration {
+                            servers: create_mappings_from_store(&servers),
+                            maintenance: maintenance.clone(),
+                            ..Configuration::new()
+                        };
+                        if let Err(e) = save_config(&config) {
+                            println!("Error updating config file: {}", e);
+                        }
+                        Some(
+                            self.maintenance
+                                .lock()
+                                .map(|mut current| {
+                                    std::mem::replace(&mut *current, maintenance.clone())
+                                })
+                                .ok(),
+                        )
+                    }
+                    Err(e) => {
+                        println!("Error locking servers mutex: {}", e);
+                        Some(None)
+                    }
+                };
+                let Some(previous) = previous else {
+                    return self
+                        .respond_with_error(
+                            session,
+                            http::StatusCode::PRECONDITION_FAILED,
+                            &version_mismatch_message(),
+                        )
+                        .await;
+                };
+                let updated = previous.is_some();
+
+                if !updated {
+                    return self
+                        .respond_with_error(
+                            session,
+                            http::StatusCode::INTERNAL_SERVER_ERROR,
+                            "Failed to update maintenance mode",
+                        )
+                        .await;
+                }
+                println!("Updated global maintenance mode");
+                self.record_change(
+                    identity,
+                    "maintenance.update",
+                    "*",
+                    previous.flatten().as_ref(),
+                    maintenance.as_ref(),
+                );
+                self.respond_with_json(session, http::StatusCode::OK, "{\"status\":\"success\"}")
+                    .await
+            }
+
+            _ => {
+                self.respond_with_error(
+                    session,
+                    http::StatusCode::METHOD_NOT_ALLOWED,
+                    "Method not allowed for maintenance endpoint",
+                )
+                .await
+            }
+        }
+    }
+
+    // Handle GET, PUT and DELETE of one section of a domain's mapping, e.g. its
+    // path rules, DELETE resets the section to its default
+    async fn handle_mapping_section<T>(
+        &self,
+        session: &mut Session,
+        method: &str,
+        domain: &str,
+        section: &MappingSection<T>,
+        identity: &Identity,
+    ) -> Result<bool>
+    where
+        T: Serialize + DeserializeOwned + Default + Send,
+    {
+        match method {
+            "GET" => {
+                let value = self.servers.lock().ok().map(|servers| {
+                    servers
+                        .get(domain)
+                        .filter(|mapping| identity.can_access(mapping))
+                        .map(section.get)
+                });
+                match value {
+                    Some(Some(value)) => {
+                        let response_json = serde_json::to_string(&serde_json::json!({
+                            "status": "success",
+                            "domain": domain,
+                            section.name: value,
+                        }))
+                        .unwrap_or_default();
+                        self.respond_with_json(session, http::StatusCode::OK, &response_json)
+                            .await
+                    }
+                    Some(None) => {
+                        self.respond_with_error(
+                            session,
+                            http::StatusCode::NOT_FOUND,
+                            &format!("Domain {} not found", domain),
+                        )
+                        .await
+                    }
+                    None => {
+                        self.respond_with_error(
+                            session,
+                            http::StatusCode::INTERNAL_SERVER_ERROR,
+                            "Failed to acquire lock on server configuration",
+                        )
+                        .await
+                    }
+                }
+            }
+
+            "PUT" | "DELETE" => {
+                let value: T = if method == "PUT" {
+                    let body = match self.read_request_body(session).await {
+                        Ok(body) => body,
+                        Err(e) => {
+                            return self
+                                .respond_with_error(
+                                    session,
+                                    http::StatusCode::BAD_REQUEST,
+                                    &format!("Failed to read request body: {}", e),
+                                )
+                                .await;
+                        }
+                    };
+                    match serde_json::from_slice(&body) {
+                        Ok(value) => value,
+                        Err(e) => {
+                            return self
+                                .respond_with_error(
+                                    session,
+                                    http::StatusCode::BAD_REQUEST,
+                                    &format!("Invalid request format: {}", e),
+                                )
+                                .await;
+                        }
+                    }
+                } else {
+                    T::default()
+                };
+
+                if let Err(e) = (section.validate)(&value) {
+                    return self
+                        .respond_with_error(session, http::StatusCode::BAD_REQUEST, &e)
+                        .await;
+                }
+
+                // Mappings of other organizations look unknown, and a change
+                // may not hand the mapping to backends outside the organization
+                let updated = match self.servers.lock() {
+                    Ok(_) if !version_matches(session) => Err((
+                        http::StatusCode::PRECONDITION_FAILED,
+                        version_mismatch_message(),
+                    )),
+                    Ok(mut servers) => match servers.get(domain) {
+                        Some(mapping) if identity.can_access(mapping) => Ok(mapping.clone()),
+                        None if section.create && method == "PUT" => {
+                            Ok(ServerMapping::new(domain, ""))
+                        }
+                        _ => Err((
+                            http::StatusCode::NOT_FOUND,
+                            format!("Domain {} not found", domain),
+                        )),
+                    }
+                    .and_then(|mut mapping| {
+                        (section.set)(&mut mapping, value);
+                        if !identity.can_replace(&servers, &mapping) {
+                            return Err((http::StatusCode::FORBIDDEN, String::new()));
+                        }
+                        // A section may be valid on its own and still leave a
+                        // mapping that is not, e.g. a redirect-only route without
+                        // its redirect
+                        mapping
+                            .validate()
+                            .map_err(|e| (http::StatusCode::BAD_REQUEST, e))?;
+                        let old = servers.insert(domain.to_string(), mapping.clone());
+                        if old.is_none() {
+                            println!("Added mapping for {}", domain);
+                        }
+                        println!("Updated {} of {}", section.name, domain);
+                        let action = match old {
+                            Some(_) => "route.update",
+                            None => "route.create",
+                        };
+                        self.record_change(identity, action, domain, old.as_ref(), Some(&mapping));
+
+                        let updates = create_mappings_from_store(&servers);
+                        if let Err(e) = update_config(updates) {
+                            println!("Error updating config file: {}", e);
+                        }
+                        Ok(())
+                    }),
+                    Err(e) => {
+                        println!("Error locking servers mutex: {}", e);
+                        Err((
+                            http::StatusCode::INTERNAL_SERVER_ERROR,
+                            "Failed to acquire lock on server configuration".to_string(),
+                        ))
+                    }
+                };
+
+                match updated {
+                    Ok(()) => {
+                        self.respond_with_json(
+                            session,
+                            http::StatusCode::OK,
+                            "{\"status\":\"success\"}",
+                        )
+                        .await
+                    }
+                    Err((http::StatusCode::FORBIDDEN, _)) => {
+                        self.respond_auth_error(session, AuthError::NotPermitted)
+                            .await
+                    }
+                    Err((status, message)) => {
+                        self.respond_with_error(session, status, &message).await
+                    }
+                }
+            }
+
+            // Method not supported
+            _ => {
+                self.respond_with_error(
+                    session,
+                    http::StatusCode::METHOD_NOT_ALLOWED,
+                    &format!("Method not allowed for {} endpoint", section.name),
+                )
+                .await
+            }
+        }
+    }
 }
 
 #[async_trait::async_trait]
@@ -201,12 +753,41 @@ impl ProxyHttp for ManagerProxy {
     fn new_ctx(&self) -> Self::CTX {}
 
     async fn request_filter(&self, session: &mut Session, _ctx: &mut Self::CTX) -> Result<bool> {
+        // Every endpoint needs a token or client certificate once either is configured
+        let identity = match self.auth.authenticate(session, &self.connections) {
+            Ok(identity) => identity,
+            Err(e) => {
+                println!(
+                    "Rejected manager request {}: {}",
+                    session.request_summary(),
+                    e.message()
+                );
+                return self.respond_auth_error(session, e).await;
+            }
+        };
+        println!("Manager request authenticated as {:?}", identity);
+
+        // The role decides the kind of request, the organization which routes it sees
+        let req = session.req_header();
+        let permission = required_permission(req.method.as_str(), req.uri.path());
+        if !identity.allows(permission) {
+            println!(
+                "Manager request {} needs {:?}, not allowed for {:?}",
+                session.request_summary(),
+                permission,
+                identity.principal
+            );
+            return self
+                .respond_auth_error(session, AuthError::NotPermitted)
+                .await;
+        }
+
         // Process admin commands
         let summary = session.request_summary();
         println!("Request summary: {}", summary);
 
         let segments = summary.split_whitespace().collect::<Vec<&str>>();
-        let method = segments.get(0).map(|s| s.to_string()).unwrap_or_default();
+        let method = segments.first().map(|s| s.to_string()).unwrap_or_default();
         let pathname = segments.get(1).map(|s| s.to_string()).unwrap_or_default();
 
         let path_segments: Vec<String> = pathname.split('/').map(|seg| seg.to_string()).collect();
@@ -214,10 +795,12 @@ impl ProxyHttp for ManagerProxy {
 
         println!("Full request URI: {}", session.req_header().uri);
 
-        if path_segments.len() > 1 && path_segments[1].starts_with("certificates") {
-            // Remove any trailing commas from the path segment
-            let segment = path_segments[1].trim_end_matches(",");
+        // Versioned JSON API, the routes below are kept for existing clients
+        if is_api_path(session.req_header().uri.path()) {
+            return self.handle_api_request(session, &identity).await;
+        }
 
+        if path_segments.len() > 1 && path_segments[1].starts_with("certificates") {
             // Create a cleaned vector
             let clean_segments: Vec<String> = path_segments
                 .iter()
@@ -225,13 +808,84 @@ impl ProxyHttp for ManagerProxy {
                 .collect();
 
             return self
-                .handle_certificate_request(session, &method, &clean_segments)
+                .handle_certificate_request(session, &method, &clean_segments, &identity)
+                .await;
+        }
+
+        if path_segments.len() > 1 && path_segments[1].trim_end_matches(',') == "health" {
+            return self
+                .handle_health_request(session, &method, &identity)
+                .await;
+        }
+
+        if path_segments.len() == 2 && path_segments[1].trim_end_matches(',') == "maintenance" {
+            return self
+                .handle_maintenance_request(session, &method, &identity)
+                .await;
+        }
+
+        // Sections of a domain's mapping: /{domain}/paths, /{domain}/pool,
+        // /{domain}/maintenance, /{domain}/error_pages, /{domain}/force_https, /{domain}/hsts,
+        // /{domain}/redirect and /{domain}/rewrites
+        let section = path_segments
+            .get(2)
+            .map(|s| s.trim_end_matches([',', '/']))
+            .unwrap_or_default();
+        let domain = path_segments.get(1).map(String::as_str).unwrap_or_default();
+        if section == PATHS_SECTION.name {
+            return self
+                .handle_mapping_section(session, &method, domain, &PATHS_SECTION, &identity)
+                .await;
+        }
+        if section == POOL_SECTION.name {
+            return self
+                .handle_mapping_section(session, &method, domain, &POOL_SECTION, &identity)
+                .await;
+        }
+        if section == MAINTENANCE_SECTION.name {
+            return self
+                .handle_mapping_section(session, &method, domain, &MAINTENANCE_SECTION, &identity)
+                .await;
+        }
+        if section == ERROR_PAGES_SECTION.name {
+            return self
+                .handle_mapping_section(session, &method, domain, &ERROR_PAGES_SECTION, &identity)
+                .await;
+        }
+        if section == FORCE_HTTPS_SECTION.name {
+            return self
+                .handle_mapping_section(session, &method, domain, &FORCE_HTTPS_SECTION, &identity)
+                .await;
+        }
+        if section == HSTS_SECTION.name {
+            return self
+                .handle_mapping_section(session, &method, domain, &HSTS_SECTION, &identity)
+                .await;
+        }
+        if section == REDIRECT_SECTION.name {
+            return self
+                .handle_mapping_section(session, &method, domain, &REDIRECT_SECTION, &identity)
+                .await;
+        }
+        if section == REWRITES_SECTION.name {
+            return self
+                .handle_mapping_section(session, &method, domain, &REWRITES_SECTION, &identity)
                 .await;
         }
 
         // Handle regular route management requests
         let mut response_status = 200;
         let mut response_body = String::from("{\"status\":\"success\"}");
+        let not_permitted = serde_json::json!({
+            "status": "error",
+            "message": AuthError::NotPermitted.message(),
+        })
+        .to_string();
+        let version_mismatch = serde_json::json!({
+            "status": "error",
+            "message": version_mismatch_message(),
+        })
+        .to_string();
 
         // Handle PUT requests (update existing mapping)
         if method == "PUT" && path_segments.len() > 2 {
@@ -242,7 +896,7 @@ impl ProxyHttp for ManagerProxy {
                 .get(2)
                 .unwrap_or(&String::new())
                 .clone()
-                .trim_end_matches(|c| c == ',' || c == ' ' || c == ';')
+                .trim_end_matches([',', ' ', ';'])
                 .to_string();
 
             println!("Processing PUT request: mapping {} -> {}", from, &to);
@@ -251,13 +905,42 @@ impl ProxyHttp for ManagerProxy {
                 // Use a block to limit the mutex lock scope
                 {
                     match self.servers.lock() {
+                        Ok(_) if !version_matches(session) => {
+                            response_status = 412;
+                            response_body = version_mismatch.clone();
+                        }
                         Ok(mut servers) => {
-                            servers.insert(from.clone(), to.clone());
+                            // Changing the backend keeps the path rules of the domain
+                            let mut mapping = servers
+                                .get(&from)
+                                .cloned()
+                                .unwrap_or_else(|| ServerMapping::new(&from, &to));
+                            mapping.to = to.clone();
+
+                            if identity.can_replace(&servers, &mapping) {
+                                let old = servers.insert(from.clone(), mapping.clone());
+                                let action = match old {
+                                    Some(_) => "route.update",
+                                    None => "route.create",
+                                };
+                                self.record_change(
+                                    &identity,
+                                    action,
+                                    &from,
+                                    old.as_ref(),
+                                    Some(&mapping),
+                                );
 
-                            let updates = create_mappings_from_store(&servers);
-                            update_config(updates);
+                                let updates = create_mappings_from_store(&servers);
+                                if let Err(e) = update_config(updates) {
+                                    println!("Error updating config file: {}", e);
+                                }
 
-                            println!("Updated mapping: {} -> {}", from, &to);
+                                println!("Updated mapping: {} -> {}", from, &to);
+                            } else {
+                                response_status = 403;
+                                response_body = not_permitted.clone();
+                            }
                         }
                         Err(e) => {
                             println!("Error locking servers mutex: {}", e);
@@ -282,7 +965,7 @@ impl ProxyHttp for ManagerProxy {
                 .get(2)
                 .unwrap_or(&String::new())
                 .clone()
-                .trim_end_matches(|c| c == ',' || c == ' ' || c == ';')
+                .trim_end_matches([',', ' ', ';'])
                 .to_string();
 
             println!("Processing POST request: mapping {} -> {}", from, &to);
@@ -291,13 +974,42 @@ impl ProxyHttp for ManagerProxy {
                 // Use a block to limit the mutex lock scope
                 {
                     match self.servers.lock() {
+                        Ok(_) if !version_matches(session) => {
+                            response_status = 412;
+                            response_body = version_mismatch.clone();
+                        }
                         Ok(mut servers) => {
-                            servers.insert(from.clone(), to.clone());
+                            // Changing the backend keeps the path rules of the domain
+                            let mut mapping = servers
+                                .get(&from)
+                                .cloned()
+                                .unwrap_or_else(|| ServerMapping::new(&from, &to));
+                            mapping.to = to.clone();
+
+                            if identity.can_replace(&servers, &mapping) {
+                                let old = servers.insert(from.clone(), mapping.clone());
+                                let action = match old {
+                                    Some(_) => "route.update",
+                                    None => "route.create",
+                                };
+                                self.record_change(
+                                    &identity,
+                                    action,
+                                    &from,
+                                    old.as_ref(),
+                                    Some(&mapping),
+                                );
 
-                            let updates = create_mappings_from_store(&servers);
-                            update_config(updates);
+                                let updates = create_mappings_from_store(&servers);
+                                if let Err(e) = update_config(updates) {
+                                    println!("Error updating config file: {}", e);
+                                }
 
-                            println!("Added mapping: {} -> {}", from, &to);
+                                println!("Added mapping: {} -> {}", from, &to);
+                            } else {
+                                response_status = 403;
+                                response_body = not_permitted.clone();
+                            }
                         }
                         Err(e) => {
                             println!("Error locking servers mutex: {}", e);
@@ -321,10 +1033,7 @@ impl ProxyHttp for ManagerProxy {
             // Clean up path segments by removing trailing commas, semicolons, and whitespace
             let clean_path_segments: Vec<String> = path_segments
                 .iter()
-                .map(|s| {
-                    s.trim_end_matches(|c| c == ',' || c == ' ' || c == ';')
-                        .to_string()
-                })
+                .map(|s| s.trim_end_matches([',', ' ', ';']).to_string())
                 .collect();
 
             let from = clean_path_segments.get(1).unwrap_or(&String::new()).clone();
@@ -343,11 +1052,23 @@ impl ProxyHttp for ManagerProxy {
                 // Use a block to limit the mutex lock scope
                 {
                     match self.servers.lock() {
+                        Ok(_) if !version_matches(session) => {
+                            response_status = 412;
+                            response_body = version_mismatch.clone();
+                        }
                         Ok(mut servers) => {
-                            // Check if the domain exists before trying to remove it
-                            if servers.contains_key(from) {
+                            // Check if the domain exists before trying to remove it,
+                            // mappings of other organizations look unknown
+                            if servers.get(from).is_some_and(|m| identity.can_access(m)) {
                                 // Remove the entry from the HashMap
-                                servers.remove(from);
+                                let old = servers.remove(from);
+                                self.record_change(
+                                    &identity,
+                                    "route.delete",
+                                    from,
+                                    old.as_ref(),
+                                    None,
+                                );
                                 deletion_success = true;
 
                                 println!("Removed mapping for: {} from in-memory store", from);
@@ -460,19 +1181,13 @@ impl ProxyHttp for ManagerProxy {
 
             match self.servers.lock() {
                 Ok(servers) => {
-                    let mut mappings_json = Vec::new();
-
-                    for (domain, backend) in servers.iter() {
-                        mappings_json.push(format!(
-                            "{{\"from\":\"{}\",\"to\":\"{}\"}}",
-                            domain, backend
-                        ));
-                    }
-
-                    response_body = format!(
-                        "{{\"status\":\"success\",\"mappings\":[{}]}}",
-                        mappings_json.join(",")
-                    );
+                    let mut mappings = create_mappings_from_store(&servers);
+                    mappings.retain(|mapping| identity.can_access(mapping));
+                    response_body = serde_json::to_string(&serde_json::json!({
+                        "status": "success",
+                        "mappings": mappings,
+                    }))
+                    .unwrap_or_default();
                 }
                 Err(e) => {
                     println!("Error locking servers mutex: {}", e);
@@ -500,6 +1215,11 @@ impl ProxyHttp for ManagerProxy {
         // Add Connection: close header to force the connection to close after response
         resp.insert_header("connection", "close")?;
 
+        // Version of the configuration, for the If-Match of the next write
+        if resp.status.is_success() {
+            resp.insert_header("etag", config_etag())?;
+        }
+
         // Write the response header - passing true for end_of_stream if there's no body
         let body_bytes = response_body.into_bytes();
         session
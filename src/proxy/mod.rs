@@ -1,4 +1,17 @@
+pub mod affinity;
+pub mod api;
+pub mod auth;
+pub mod balancer;
+pub mod circuit;
+pub mod error_pages;
+pub mod fallback;
+pub mod health;
 pub mod http;
 pub mod https;
+pub mod maintenance;
 pub mod manager;
+pub mod pages;
+pub mod retry;
+pub mod router;
+pub mod tls;
 pub mod utils;
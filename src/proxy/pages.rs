@@ -0,0 +1,58 @@
+// src/proxy/pages.rs
+use bytes::Bytes;
+use pingora::Result;
+use pingora_http::{Method, ResponseHeader};
+use pingora_proxy::Session;
+
+/// Page served to hosts without a mapping
+pub const NOT_FOUND_PAGE: &str = include_str!("../../assets/404.html");
+
+/// Page served to routes in maintenance
+pub const MAINTENANCE_PAGE: &str = include_str!("../../assets/maintanance.html");
+
+/// Answer the request with an HTML page and any `headers` besides the usual ones
+pub async fn write_html(
+    session: &mut Session,
+    status: u16,
+    html: &str,
+    headers: &[(&'static str, String)],
+) -> Result<()> {
+    write_page(session, status, "text/html; charset=utf-8", html, headers).await
+}
+
+/// Answer the request with a body of any type
+pub async fn write_page(
+    session: &mut Session,
+    status: u16,
+    content_type: &str,
+    body: &str,
+    headers: &[(&'static str, String)],
+) -> Result<()> {
+    let mut header = ResponseHeader::build(status, Some(3 + headers.len()))?;
+    for (name, value) in headers {
+        header.insert_header(*name, value)?;
+    }
+    header.insert_header("Content-Type", content_type)?;
+    header.insert_header("Content-Length", body.len().to_string())?;
+    header.insert_header("Cache-Control", "no-store")?;
+
+    // HEAD requests get the headers of the page only
+    let head = session.req_header().method == Method::HEAD;
+    session
+        .write_response_header(Box::new(header), head)
+        .await?;
+    if !head {
+        session
+            .write_response_body(Some(Bytes::copy_from_slice(body.as_bytes())), true)
+            .await?;
+    }
+    Ok(())
+}
+
+/// Answer the request with a redirect to `location`
+pub async fn write_redirect(session: &mut Session, status: u16, location: &str) -> Result<()> {
+    let mut header = ResponseHeader::build(status, Some(2))?;
+    header.insert_header("Location", location)?;
+    header.insert_header("Content-Length", "0")?;
+    session.write_response_header(Box::new(header), true).await
+}
@@ -0,0 +1,194 @@
+// src/proxy/retry.rs
+use std::{
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use pingora::{Error, ErrorSource, ErrorType, Result};
+use pingora_http::Method;
+use pingora_proxy::Session;
+
+use crate::config::model::{RetryOn, RetryPolicy};
+
+use super::router::RouteContext;
+
+/// Window over which the retry budget is measured
+const BUDGET_WINDOW: Duration = Duration::from_secs(10);
+
+/// Retries always allowed per window, so quiet routes can still retry
+const MIN_BUDGET: usize = 10;
+
+/// Why an attempt failed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Failure {
+    Error(RetryOn),
+    Status(u16),
+}
+
+#[derive(Debug)]
+struct BudgetWindow {
+    start: Instant,
+    requests: usize,
+    retries: usize,
+}
+
+/// Retry policy of a pool and the retries it spent lately
+#[derive(Debug)]
+pub struct RetryState {
+    pub policy: RetryPolicy,
+    window: Mutex<BudgetWindow>,
+}
+
+impl RetryState {
+    pub fn new(policy: RetryPolicy) -> Self {
+        Self {
+            policy,
+            window: Mutex::new(BudgetWindow {
+                start: Instant::now(),
+                requests: 0,
+                retries: 0,
+            }),
+        }
+    }
+
+    fn with_window<T>(&self, f: impl FnOnce(&mut BudgetWindow) -> T) -> Option<T> {
+        let mut window = self.window.lock().ok()?;
+        // Tumbling window, counts start over once it has passed
+        if window.start.elapsed() >= BUDGET_WINDOW {
+            window.start = Instant::now();
+            window.requests = 0;
+            window.retries = 0;
+        }
+        Some(f(&mut window))
+    }
+
+    /// Count a request towards the budget
+    pub fn note_request(&self) {
+        self.with_window(|window| window.requests += 1);
+    }
+
+    /// Spend a retry, false once the budget of the window is used up
+    pub fn acquire(&self) -> bool {
+        let budget = self.policy.budget as usize;
+        self.with_window(|window| {
+            let allowed = (window.requests * budget / 100).max(MIN_BUDGET);
+            if window.retries >= allowed {
+                return false;
+            }
+            window.retries += 1;
+            true
+        })
+        .unwrap_or(false)
+    }
+}
+
+fn is_idempotent(method: &Method) -> bool {
+    matches!(
+        *method,
+        Method::GET | Method::HEAD | Method::OPTIONS | Method::PUT | Method::DELETE | Method::TRACE
+    )
+}
+
+/// Retry cause of an error met while talking to the upstream
+pub fn classify(e: &Error) -> Option<RetryOn> {
+    match e.etype() {
+        ErrorType::ConnectTimedout
+        | ErrorType::ConnectRefused
+        | ErrorType::ConnectNoRoute
+        | ErrorType::ConnectError
+        | ErrorType::ConnectProxyFailure
+        | ErrorType::TLSHandshakeFailure
+        | ErrorType::TLSHandshakeTimedout => Some(RetryOn::ConnectFailure),
+        ErrorType::ReadTimedout | ErrorType::WriteTimedout => Some(RetryOn::Timeout),
+        ErrorType::ConnectionClosed | ErrorType::ReadError | ErrorType::WriteError => {
+            Some(RetryOn::Reset)
+        }
+        _ => None,
+    }
+}
+
+/// Whether a failed attempt is sent again, counting the retry when it is
+pub fn should_retry(ctx: &mut RouteContext, session: &Session, failure: Failure) -> bool {
+    let Some(retry) = ctx.retry.clone() else {
+        return false;
+    };
+    let policy = &retry.policy;
+
+    let matches = match failure {
+        Failure::Error(cause) => policy.retry_on.contains(&cause),
+        Failure::Status(status) => policy.status_codes.contains(&status),
+    };
+    if !matches || ctx.retries >= policy.max_retries {
+        return false;
+    }
+
+    // Nothing can be sent again once the client got part of the response
+    // or the request body no longer fits in the retry buffer
+    if session.response_written().is_some() || session.as_ref().retry_buffer_truncated() {
+        return false;
+    }
+
+    // A request that never reached the upstream is safe to send whatever its method
+    if policy.idempotent_only
+        && failure != Failure::Error(RetryOn::ConnectFailure)
+        && !is_idempotent(&session.req_header().method)
+    {
+        return false;
+    }
+
+    if !retry.acquire() {
+        println!("Retry budget exhausted, not retrying {:?}", failure);
+        return false;
+    }
+
+    ctx.retries += 1;
+    println!(
+        "Retrying request after {:?} ({}/{})",
+        failure, ctx.retries, policy.max_retries
+    );
+    true
+}
+
+/// Mark a failed connection for retry when the policy allows it
+pub fn retry_connect(ctx: &mut RouteContext, session: &Session, mut e: Box<Error>) -> Box<Error> {
+    if should_retry(ctx, session, Failure::Error(RetryOn::ConnectFailure)) {
+        e.set_retry(true);
+    }
+    e
+}
+
+/// Mark an error met while proxying for retry when the policy allows it
+pub fn retry_error(ctx: &mut RouteContext, session: &Session, mut e: Box<Error>) -> Box<Error> {
+    // Already retried by pingora, e.g. a stale pooled connection
+    if e.retry() || *e.esource() != ErrorSource::Upstream {
+        return e;
+    }
+
+    if let Some(cause) = classify(&e)
+        && should_retry(ctx, session, Failure::Error(cause))
+    {
+        if let Some(selected) = ctx.upstream.take() {
+            selected.record(false);
+        }
+        e.set_retry(true);
+    }
+    e
+}
+
+/// Turn an upstream response the policy retries into a retryable error,
+/// before anything is sent to the client
+pub fn retry_status(ctx: &mut RouteContext, session: &Session, status: u16) -> Result<()> {
+    if ctx.upstream_status.is_none() || !should_retry(ctx, session, Failure::Status(status)) {
+        return Ok(());
+    }
+
+    if let Some(selected) = ctx.upstream.take() {
+        selected.record(status < 500);
+    }
+    let mut e = Error::explain(
+        ErrorType::HTTPStatus(status),
+        "upstream response is retried",
+    );
+    e.set_retry(true);
+    Err(e)
+}
@@ -0,0 +1,576 @@
+// src/proxy/router.rs
+use std::{
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+    time::Duration,
+};
+
+use pingora::prelude::HttpPeer;
+use pingora_proxy::Session;
+
+use crate::config::model::{
+    ConfigStore, ErrorPages, HstsConfig, MaintenanceConfig, PathMatch, PathRule, PoolConfig,
+    RedirectConfig, ServerMapping,
+};
+
+use super::affinity::set_cookie;
+use super::balancer::{Selected, UpstreamPools};
+use super::retry::RetryState;
+use super::utils::parse_swarm_target;
+
+/// Per-request routing state shared between the proxy phases
+#[derive(Debug, Default)]
+pub struct RouteContext {
+    /// `X-Request-Id` of the request, sent upstream and shown on error pages
+    pub request_id: String,
+    /// Address of the client, also when TLS was terminated in front of the proxy
+    pub client_ip: Option<IpAddr>,
+    /// Whether the client connected over TLS
+    pub secure: bool,
+    /// Route of the request, `None` for hosts without a mapping
+    pub target: Option<RouteTarget>,
+    /// Path and query to send upstream when a path rule rewrites the request
+    pub upstream_uri: Option<String>,
+    /// Pool upstream serving the request, counted for least connections and
+    /// told the outcome of the request for its circuit breaker
+    pub upstream: Option<Selected>,
+    /// Status the upstream answered with
+    pub upstream_status: Option<u16>,
+    /// Retry policy of the route, taken from its pool
+    pub retry: Option<Arc<RetryState>>,
+    /// Retries made so far
+    pub retries: usize,
+    /// Upstreams already tried, retries go elsewhere when the pool allows
+    pub tried: Vec<SocketAddr>,
+    /// Affinity cookie to send with the response
+    pub set_cookie: Option<String>,
+}
+
+/// Backend picked for a request
+#[derive(Debug, Clone, PartialEq)]
+pub struct RouteTarget {
+    pub to: String,
+    pub pool: PoolConfig,
+    pub upstream_uri: Option<String>,
+    /// Maintenance mode of the path rule or else of the mapping
+    pub maintenance: Option<MaintenanceConfig>,
+    /// Error pages of the mapping and the path rule
+    pub error_pages: ErrorPages,
+    /// HTTPS redirect of the path rule or else of the mapping, `None` to redirect
+    /// when the host has a certificate
+    pub force_https: Option<bool>,
+    /// HSTS header of the path rule or else of the mapping
+    pub hsts: Option<HstsConfig>,
+    /// Redirect answered instead of proxying
+    pub redirect: Option<RedirectTarget>,
+}
+
+/// Redirect of a route, resolved for a request
+#[derive(Debug, Clone, PartialEq)]
+pub struct RedirectTarget {
+    pub status: u16,
+    pub to: String,
+    /// Path and query appended to `to`
+    pub path: Option<String>,
+}
+
+impl RedirectTarget {
+    /// `Location` of the redirect for a request received over `scheme`
+    pub fn location(&self, scheme: &str) -> String {
+        let base = if self.to.contains("://") {
+            self.to.clone()
+        } else {
+            format!("{}://{}", scheme, self.to)
+        };
+        match &self.path {
+            Some(path) => format!("{}{}", base.trim_end_matches('/'), path),
+            None => base,
+        }
+    }
+}
+
+fn redirect_target(redirect: &RedirectConfig, path: &str, query: Option<&str>) -> RedirectTarget {
+    RedirectTarget {
+        status: redirect.status,
+        to: redirect.to.clone(),
+        path: redirect
+            .preserve_path
+            .then(|| with_query(path.to_string(), query)),
+    }
+}
+
+// A rewrite may add its own query parameters
+fn with_query(path: String, query: Option<&str>) -> String {
+    match query {
+        Some(query) if path.contains('?') => format!("{}&{}", path, query),
+        Some(query) => format!("{}?{}", path, query),
+        None => path,
+    }
+}
+
+// Path and query after the first matching rewrite rule of the mapping
+fn rewrite_request(
+    mapping: &ServerMapping,
+    path: &str,
+    query: Option<&str>,
+) -> Option<(String, Option<String>)> {
+    let rewritten = mapping.rewrites.iter().find_map(|rule| {
+        let regex = rule.regex()?;
+        regex
+            .is_match(path)
+            .then(|| regex.replace(path, rule.replacement.as_str()).into_owned())
+    })?;
+
+    let (path, query) = match rewritten.split_once('?') {
+        Some((path, extra)) => (
+            path.to_string(),
+            Some(match query {
+                Some(query) => format!("{}&{}", extra, query),
+                None => extra.to_string(),
+            }),
+        ),
+        None => (rewritten, query.map(str::to_string)),
+    };
+    let path = if path.starts_with('/') {
+        path
+    } else {
+        format!("/{}", path)
+    };
+    Some((path, query))
+}
+
+// Prefixes only match whole segments: `/api` matches `/api` and `/api/x`, not `/apix`
+fn prefix_len(rule_path: &str, path: &str) -> Option<usize> {
+    let rest = path.strip_prefix(rule_path)?;
+    if rest.is_empty() || rest.starts_with('/') || rule_path.ends_with('/') {
+        Some(rule_path.len())
+    } else {
+        None
+    }
+}
+
+// `rest` appended to `base` with exactly one `/` between them
+fn join_path(base: &str, rest: &str) -> String {
+    let base = base.trim_end_matches('/');
+    if rest.is_empty() {
+        base.to_string()
+    } else {
+        format!("{}/{}", base, rest.trim_start_matches('/'))
+    }
+}
+
+// New path for a request matched by `rule`, `None` when it is forwarded unchanged
+fn rewrite_path(rule: &PathRule, path: &str) -> Option<Option<String>> {
+    let rewritten = match rule.match_type {
+        PathMatch::Exact => {
+            if path != rule.path {
+                return None;
+            }
+            match (&rule.rewrite, rule.strip_prefix) {
+                (Some(rewrite), _) => Some(rewrite.clone()),
+                (None, true) => Some("/".to_string()),
+                (None, false) => None,
+            }
+        }
+        PathMatch::Prefix => {
+            let len = prefix_len(&rule.path, path)?;
+            let rest = &path[len..];
+            match (&rule.rewrite, rule.strip_prefix) {
+                (Some(rewrite), _) => Some(join_path(rewrite, rest)),
+                (None, true) => Some(rest.to_string()),
+                (None, false) => None,
+            }
+        }
+        PathMatch::Regex => {
+            let regex = rule.regex()?;
+            if !regex.is_match(path) {
+                return None;
+            }
+            match (&rule.rewrite, rule.strip_prefix) {
+                (Some(rewrite), _) => Some(regex.replace(path, rewrite.as_str()).into_owned()),
+                (None, true) => Some(regex.replace(path, "").into_owned()),
+                (None, false) => None,
+            }
+        }
+    };
+
+    // Stripping everything leaves the root path
+    Some(rewritten.map(|p| {
+        if p.starts_with('/') {
+            p
+        } else {
+            format!("/{}", p)
+        }
+    }))
+}
+
+/// Pick the backend for a request to `mapping`, path rules are tried in order
+/// and the mapping's own backend is used when none matches
+pub fn route_request(mapping: &ServerMapping, path: &str, query: Option<&str>) -> RouteTarget {
+    for rule in &mapping.paths {
+        if let Some(rewritten) = rewrite_path(rule, path) {
+            let redirect = rule.redirect.as_ref().map(|redirect| {
+                redirect_target(redirect, rewritten.as_deref().unwrap_or(path), query)
+            });
+            return RouteTarget {
+                to: rule.to.clone(),
+                pool: rule.pool.clone(),
+                upstream_uri: rewritten.map(|p| with_query(p, query)),
+                maintenance: rule
+                    .maintenance
+                    .clone()
+                    .or_else(|| mapping.maintenance.clone()),
+                error_pages: mapping
+                    .error_pages
+                    .clone()
+                    .into_iter()
+                    .chain(rule.error_pages.clone())
+                    .collect(),
+                force_https: rule.force_https.or(mapping.force_https),
+                hsts: rule.hsts.clone().or_else(|| mapping.hsts.clone()),
+                redirect,
+            };
+        }
+    }
+
+    RouteTarget {
+        to: mapping.to.clone(),
+        pool: mapping.pool.clone(),
+        upstream_uri: None,
+        maintenance: mapping.maintenance.clone(),
+        error_pages: mapping.error_pages.clone(),
+        force_https: mapping.force_https,
+        hsts: mapping.hsts.clone(),
+        redirect: mapping
+            .redirect
+            .as_ref()
+            .map(|redirect| redirect_target(redirect, path, query)),
+    }
+}
+
+/// Mapping picked for a request host
+#[derive(Debug)]
+pub struct HostMatch<'a> {
+    pub mapping: &'a ServerMapping,
+    /// `{0}` is the whole host, `{1}`.. the wildcard label(s) or regex groups
+    pub captures: Vec<String>,
+}
+
+/// Lowercase a Host header value and drop its port and trailing dot
+pub fn normalize_host(host: &str) -> String {
+    let host = host.trim();
+    let host = match host.strip_prefix('[') {
+        // IPv6 literal, `[::1]:8080`
+        Some(rest) => rest.split(']').next().unwrap_or_default(),
+        None => host.split(':').next().unwrap_or_default(),
+    };
+    host.trim_end_matches('.').to_ascii_lowercase()
+}
+
+// Exact lookup, falling back to a case insensitive scan for entries not stored lowercase
+fn get_host<'a>(servers: &'a ConfigStore, host: &str) -> Option<&'a ServerMapping> {
+    servers.get(host).or_else(|| {
+        servers
+            .values()
+            .find(|mapping| !mapping.is_regex_host() && normalize_host(&mapping.from) == host)
+    })
+}
+
+/// Find the mapping for a host, preferring an exact entry, then the most
+/// specific `*.` wildcard, then the first matching `~` regex by name
+pub fn find_mapping<'a>(servers: &'a ConfigStore, host: &str) -> Option<HostMatch<'a>> {
+    let host = normalize_host(host);
+    if host.is_empty() {
+        return None;
+    }
+
+    if let Some(mapping) = get_host(servers, &host) {
+        return Some(HostMatch {
+            mapping,
+            captures: vec![host],
+        });
+    }
+
+    // `a.b.example.com` tries `*.b.example.com`, then `*.example.com`
+    for (index, _) in host.match_indices('.') {
+        if let Some(mapping) = get_host(servers, &format!("*{}", &host[index..])) {
+            return Some(HostMatch {
+                mapping,
+                captures: vec![host.clone(), host[..index].to_string()],
+            });
+        }
+    }
+
+    let mut patterns: Vec<&ServerMapping> = servers
+        .values()
+        .filter(|mapping| mapping.is_regex_host())
+        .collect();
+    patterns.sort_by(|a, b| a.from.cmp(&b.from));
+
+    patterns.into_iter().find_map(|mapping| {
+        let captures = mapping.host_regex()?.captures(&host)?;
+        Some(HostMatch {
+            mapping,
+            captures: captures
+                .iter()
+                .map(|group| group.map(|g| g.as_str().to_string()).unwrap_or_default())
+                .collect(),
+        })
+    })
+}
+
+// Replace `{N}` placeholders in a backend address with host captures
+fn expand_captures(to: &str, captures: &[String]) -> String {
+    let mut to = to.to_string();
+    for (index, capture) in captures.iter().enumerate().rev() {
+        to = to.replace(&format!("{{{}}}", index), capture);
+    }
+    to
+}
+
+/// Route a request by host and path, `None` when no mapping matches the host
+pub fn route(
+    servers: &ConfigStore,
+    host: &str,
+    path: &str,
+    query: Option<&str>,
+) -> Option<RouteTarget> {
+    let matched = find_mapping(servers, host)?;
+
+    // Rewrite rules change the path before path rules see it
+    let rewritten = rewrite_request(matched.mapping, path, query);
+    let (path, query) = match &rewritten {
+        Some((path, query)) => (path.as_str(), query.as_deref()),
+        None => (path, query),
+    };
+
+    let mut target = route_request(matched.mapping, path, query);
+    if rewritten.is_some() && target.upstream_uri.is_none() {
+        target.upstream_uri = Some(with_query(path.to_string(), query));
+    }
+    if let Some(redirect) = &mut target.redirect {
+        redirect.to = expand_captures(&redirect.to, &matched.captures);
+    }
+    target.to = expand_captures(&target.to, &matched.captures);
+    for upstream in &mut target.pool.upstreams {
+        upstream.addr = expand_captures(&upstream.addr, &matched.captures);
+    }
+    Some(target)
+}
+
+// Swarm targets look like `[org.]service.network:port`
+fn swarm_target(to: &str) -> (String, Option<String>) {
+    if to.contains('.') && to.contains(':') {
+        // Likely a swarm DNS name
+        let (host, port, org_id) = parse_swarm_target(to);
+        (format!("{}:{}", host, port), org_id)
+    } else {
+        // Standard target
+        (to.to_owned(), None)
+    }
+}
+
+// Peer for `addr`, forwarding the organization of swarm target `to` if any
+fn peer_with_org(addr: String, to: &str, hostname: &str) -> HttpPeer {
+    let (_, org_header) = swarm_target(to);
+    let mut peer = HttpPeer::new(addr, false, hostname.to_string());
+
+    // Add organization header if present
+    if let Some(org) = org_header {
+        peer.options
+            .extra_proxy_headers
+            .insert("X-Organization-ID".to_string(), org.into_bytes());
+    }
+
+    peer
+}
+
+/// Build the upstream peer for a backend address, understanding swarm targets
+pub fn backend_peer(to: &str, hostname: &str) -> HttpPeer {
+    let (target, _) = swarm_target(to);
+    peer_with_org(target, to, hostname)
+}
+
+// Value of a request cookie
+fn cookie<'a>(session: &'a Session, name: &str) -> Option<&'a str> {
+    session
+        .req_header()
+        .headers
+        .get_all("cookie")
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(';'))
+        .filter_map(|cookie| cookie.trim().split_once('='))
+        .find(|(key, _)| *key == name)
+        .map(|(_, value)| value)
+}
+
+// Key for consistent hashing: a header, a cookie or the client address
+fn hash_key(session: &Session, hash_on: Option<&str>, client_ip: Option<IpAddr>) -> Vec<u8> {
+    let headers = &session.req_header().headers;
+    let value = match hash_on.and_then(|hash_on| hash_on.split_once(':')) {
+        Some(("header", name)) => headers.get(name).map(|value| value.as_bytes().to_vec()),
+        Some(("cookie", name)) => cookie(session, name).map(|value| value.as_bytes().to_vec()),
+        _ => None,
+    };
+
+    // Requests without the header or cookie are spread by client address
+    value.unwrap_or_else(|| {
+        client_ip
+            .map(|ip| ip.to_string().into_bytes())
+            .unwrap_or_default()
+    })
+}
+
+/// Peer for a routed request, picking an upstream from the pool when the route
+/// has one; 502 when the pool cannot be built and 503 when no upstream is healthy
+pub async fn select_peer(
+    pools: &UpstreamPools,
+    target: &RouteTarget,
+    session: &Session,
+    hostname: &str,
+    ctx: &mut RouteContext,
+) -> pingora::Result<HttpPeer> {
+    let Some(pool_config) = target.pool.effective(&target.to) else {
+        return Ok(backend_peer(&target.to, hostname));
+    };
+
+    let pool = pools.get(&pool_config).await.map_err(|e| {
+        println!("Error building upstream pool for {}: {}", hostname, e);
+        pingora::Error::new(pingora::ErrorType::HTTPStatus(502))
+    })?;
+
+    // Only the first attempt counts towards the retry budget
+    ctx.retry = pool.retry();
+    if let Some(retry) = &ctx.retry
+        && ctx.tried.is_empty()
+    {
+        retry.note_request();
+    }
+
+    // Sticky requests go back to their upstream while it is usable
+    let sticky = pool.sticky();
+    let pinned = sticky.and_then(|sticky| match &sticky.hash_on {
+        Some(hash_on) => {
+            pool.select_hashed(&hash_key(session, Some(hash_on), ctx.client_ip), &ctx.tried)
+        }
+        None => cookie(session, &sticky.cookie)
+            .and_then(|value| pool.select_affinity(value, &ctx.tried)),
+    });
+    let selected = pinned.or_else(|| {
+        let selected = pool.select(
+            &hash_key(session, pool.hash_on(), ctx.client_ip),
+            &ctx.tried,
+        )?;
+        // Clients without a usable affinity cookie get one for their new upstream
+        if let Some(sticky) = sticky.filter(|sticky| sticky.hash_on.is_none()) {
+            ctx.set_cookie = Some(set_cookie(sticky, &selected.target, ctx.secure));
+        }
+        Some(selected)
+    });
+
+    let Some(selected) = selected else {
+        println!("No healthy upstream for {}", hostname);
+        return Err(pingora::Error::new(pingora::ErrorType::HTTPStatus(503)));
+    };
+    println!("Selected upstream {} ({})", selected.target, selected.addr);
+
+    let mut peer = peer_with_org(selected.addr.to_string(), &selected.target, hostname);
+    if let Some(timeout) = ctx.retry.as_ref().and_then(|retry| retry.policy.timeout) {
+        peer.options.read_timeout = Some(Duration::from_secs(timeout));
+    }
+    ctx.tried.push(selected.addr);
+    ctx.upstream = Some(selected);
+    ctx.upstream_status = None;
+    Ok(peer)
+}
+
+/// Count a failed connection against the circuit of the upstream
+pub fn record_connect_failure(ctx: &mut RouteContext) {
+    if let Some(selected) = ctx.upstream.take() {
+        selected.record(false);
+    }
+}
+
+/// Count a finished request against the circuit of its upstream, upstream
+/// errors such as timeouts and 5xx responses are failures
+pub fn record_upstream_outcome(ctx: &mut RouteContext, error: Option<&pingora::Error>) {
+    let Some(selected) = ctx.upstream.take() else {
+        return;
+    };
+
+    let upstream_error = error.is_some_and(|e| *e.esource() == pingora::ErrorSource::Upstream);
+    match ctx.upstream_status {
+        _ if upstream_error => selected.record(false),
+        Some(status) => selected.record(status < 500),
+        // Neither side of the upstream said anything, e.g. the client went away
+        None => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn prefix_rule(path: &str, strip_prefix: bool, rewrite: Option<&str>) -> PathRule {
+        serde_json::from_value(serde_json::json!({
+            "path": path,
+            "match": "prefix",
+            "to": "127.0.0.1:3000",
+            "strip_prefix": strip_prefix,
+            "rewrite": rewrite,
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn prefix_len_matches_on_segment_boundary() {
+        assert_eq!(prefix_len("/api", "/api"), Some(4));
+        assert_eq!(prefix_len("/api", "/api/users"), Some(4));
+        assert_eq!(prefix_len("/api", "/apiary"), None);
+        assert_eq!(prefix_len("/api/", "/api/users"), Some(5));
+        assert_eq!(prefix_len("/api/", "/api"), None);
+        assert_eq!(prefix_len("/", "/users"), Some(1));
+    }
+
+    #[test]
+    fn rewrite_path_joins_with_one_slash() {
+        for rule_path in ["/api", "/api/"] {
+            for rewrite in ["/v2", "/v2/"] {
+                let rule = prefix_rule(rule_path, false, Some(rewrite));
+                assert_eq!(
+                    rewrite_path(&rule, "/api/users"),
+                    Some(Some("/v2/users".to_string())),
+                    "{} rewritten to {}",
+                    rule_path,
+                    rewrite
+                );
+            }
+        }
+        let rule = prefix_rule("/api", false, Some("/v2"));
+        assert_eq!(rewrite_path(&rule, "/api"), Some(Some("/v2".to_string())));
+        assert_eq!(rewrite_path(&rule, "/api/"), Some(Some("/v2/".to_string())));
+        let rule = prefix_rule("/api", false, Some("/"));
+        assert_eq!(
+            rewrite_path(&rule, "/api/users"),
+            Some(Some("/users".to_string()))
+        );
+        assert_eq!(rewrite_path(&rule, "/api"), Some(Some("/".to_string())));
+    }
+
+    #[test]
+    fn rewrite_path_strips_prefix() {
+        for rule_path in ["/api", "/api/"] {
+            let rule = prefix_rule(rule_path, true, None);
+            assert_eq!(
+                rewrite_path(&rule, "/api/users"),
+                Some(Some("/users".to_string()))
+            );
+        }
+        let rule = prefix_rule("/api", true, None);
+        assert_eq!(rewrite_path(&rule, "/api"), Some(Some("/".to_string())));
+        assert_eq!(rewrite_path(&rule, "/apiary"), None);
+        let rule = prefix_rule("/api", false, None);
+        assert_eq!(rewrite_path(&rule, "/api/users"), Some(None));
+    }
+}
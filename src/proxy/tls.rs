@@ -0,0 +1,183 @@
+// src/proxy/tls.rs
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::BufReader,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+};
+
+use anyhow::{Result, anyhow};
+use async_trait::async_trait;
+use pingora::{apps::ServerApp, protocols::Stream, server::ShutdownWatch};
+use pingora_proxy::Session;
+use rustls::RootCertStore;
+use tokio::net::TcpStream;
+use tokio_rustls::TlsAcceptor;
+use x509_parser::prelude::FromDer;
+
+use crate::cert::resolver::SniCertResolver;
+
+/// Details about a TLS connection that are lost once the stream is decrypted
+#[derive(Debug, Clone)]
+pub struct TlsConnectionInfo {
+    pub client_addr: Option<SocketAddr>,
+    pub server_name: Option<String>,
+    /// Subject of the client certificate, only set once it was verified
+    pub client_cert: Option<String>,
+}
+
+// Loopback connection a decrypted stream is forwarded over, as `(from, to)`
+type ForwardedConnection = (SocketAddr, SocketAddr);
+
+/// Registry of terminated TLS connections, keyed by the loopback connection the
+/// decrypted stream is forwarded over
+#[derive(Clone, Default)]
+pub struct TlsConnections {
+    inner: Arc<Mutex<HashMap<ForwardedConnection, TlsConnectionInfo>>>,
+}
+
+impl TlsConnections {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn register(&self, connection: ForwardedConnection, info: TlsConnectionInfo) {
+        if let Ok(mut connections) = self.inner.lock() {
+            connections.insert(connection, info);
+        }
+    }
+
+    fn unregister(&self, connection: &ForwardedConnection) {
+        if let Ok(mut connections) = self.inner.lock() {
+            connections.remove(connection);
+        }
+    }
+
+    /// Find the original TLS connection behind a proxied session
+    pub fn lookup(&self, session: &Session) -> Option<TlsConnectionInfo> {
+        let from = *session.client_addr()?.as_inet()?;
+        let to = *session.server_addr()?.as_inet()?;
+        self.inner.lock().ok()?.get(&(from, to)).cloned()
+    }
+
+    /// Address of the client behind a session. Decrypted TLS traffic arrives
+    /// from loopback, so only the registry knows its real peer; everything
+    /// acting on client addresses goes through here
+    pub fn client_addr(&self, session: &Session) -> Option<SocketAddr> {
+        match self.lookup(session) {
+            Some(info) => info.client_addr,
+            None => session.client_addr()?.as_inet().copied(),
+        }
+    }
+}
+
+/// Terminates TLS using the shared SNI resolver and forwards the decrypted
+/// stream to a plaintext pingora listener
+pub struct TlsTerminator {
+    acceptor: TlsAcceptor,
+    upstream: String,
+    connections: TlsConnections,
+}
+
+impl TlsTerminator {
+    pub fn new(
+        resolver: &Arc<SniCertResolver>,
+        upstream: &str,
+        connections: TlsConnections,
+    ) -> Result<Self> {
+        let config = resolver.server_config()?;
+
+        Ok(Self {
+            acceptor: TlsAcceptor::from(Arc::new(config)),
+            upstream: upstream.to_string(),
+            connections,
+        })
+    }
+
+    /// Like [`TlsTerminator::new`], also verifying client certificates issued
+    /// by the CAs in the PEM file `client_ca`; clients without one are still accepted
+    pub fn with_client_auth(
+        resolver: &Arc<SniCertResolver>,
+        client_ca: &str,
+        upstream: &str,
+        connections: TlsConnections,
+    ) -> Result<Self> {
+        let mut roots = RootCertStore::empty();
+        let mut reader = BufReader::new(File::open(client_ca)?);
+        for cert in rustls_pemfile::certs(&mut reader) {
+            roots.add(cert?)?;
+        }
+        if roots.is_empty() {
+            return Err(anyhow!("No CA certificates in {}", client_ca));
+        }
+        let config = resolver.server_config_with_client_auth(roots)?;
+
+        Ok(Self {
+            acceptor: TlsAcceptor::from(Arc::new(config)),
+            upstream: upstream.to_string(),
+            connections,
+        })
+    }
+}
+
+// Subject of the certificate a client authenticated with
+fn client_cert_subject(conn: &rustls::ServerConnection) -> Option<String> {
+    let leaf = conn.peer_certificates()?.first()?;
+    let (_, cert) = x509_parser::certificate::X509Certificate::from_der(leaf.as_ref()).ok()?;
+    Some(cert.subject().to_string())
+}
+
+#[async_trait]
+impl ServerApp for TlsTerminator {
+    async fn process_new(
+        self: &Arc<Self>,
+        session: Stream,
+        _shutdown: &ShutdownWatch,
+    ) -> Option<Stream> {
+        let client_addr = session
+            .get_socket_digest()
+            .and_then(|digest| digest.peer_addr().and_then(|addr| addr.as_inet()).copied());
+
+        let mut tls_stream = match self.acceptor.accept(session).await {
+            Ok(stream) => stream,
+            Err(e) => {
+                println!("TLS handshake failed for {:?}: {}", client_addr, e);
+                return None;
+            }
+        };
+        let server_name = tls_stream.get_ref().1.server_name().map(|s| s.to_string());
+        let client_cert = client_cert_subject(tls_stream.get_ref().1);
+
+        let mut upstream = match TcpStream::connect(&self.upstream).await {
+            Ok(stream) => stream,
+            Err(e) => {
+                println!("Error connecting to TLS upstream {}: {}", self.upstream, e);
+                return None;
+            }
+        };
+
+        let forwarded = upstream.local_addr().ok().zip(upstream.peer_addr().ok());
+        if let Some(connection) = forwarded {
+            self.connections.register(
+                connection,
+                TlsConnectionInfo {
+                    client_addr,
+                    server_name,
+                    client_cert,
+                },
+            );
+        }
+
+        if let Err(e) = tokio::io::copy_bidirectional(&mut tls_stream, &mut upstream).await {
+            println!("TLS connection from {:?} closed: {}", client_addr, e);
+        }
+
+        if let Some(connection) = &forwarded {
+            self.connections.unregister(connection);
+        }
+
+        // The stream was fully consumed by the forwarding loop
+        None
+    }
+}
@@ -1,22 +1,57 @@
 use regex::Regex;
+use ring::rand::{SecureRandom, SystemRandom};
 
 /// Extract hostname from HTTP request header
 pub fn extract_hostname(request_line: &str) -> Option<String> {
     // Regular expression to extract Host header
     let re = Regex::new(r"Host:\s*([^\s,]+)").unwrap();
 
-    if let Some(captures) = re.captures(request_line) {
-        if let Some(hostname) = captures.get(1) {
-            return Some(hostname.as_str().to_string());
+    re.captures(request_line)
+        .and_then(|captures| captures.get(1))
+        .map(|hostname| hostname.as_str().to_string())
+}
+
+/// Random id identifying a request in logs and error pages
+pub fn generate_request_id() -> String {
+    let mut bytes = [0u8; 8];
+    let _ = SystemRandom::new().fill(&mut bytes);
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Decode `%XX` escapes of a URL path segment, `None` when they are malformed
+pub fn percent_decode(segment: &str) -> Option<String> {
+    let bytes = segment.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let hex = std::str::from_utf8(bytes.get(i + 1..i + 3)?).ok()?;
+            decoded.push(u8::from_str_radix(hex, 16).ok()?);
+            i += 3;
+        } else {
+            decoded.push(bytes[i]);
+            i += 1;
         }
     }
+    String::from_utf8(decoded).ok()
+}
 
-    None
+/// Escape a value for use as a URL path segment
+pub fn percent_encode(segment: &str) -> String {
+    segment
+        .bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' | b'*' => {
+                (b as char).to_string()
+            }
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
 }
 
 pub fn clean_backend_address(address: &str) -> String {
     // Remove any trailing commas or whitespace
-    let cleaned = address.trim_end_matches(|c| c == ',' || c == ' ' || c == ';');
+    let cleaned = address.trim_end_matches([',', ' ', ';']);
 
     // Ensure the address has a proper port format
     if !cleaned.contains(':') {
@@ -39,6 +74,11 @@ pub fn parse_swarm_target(target: &str) -> (String, u16, Option<String>) {
         80
     };
 
+    // IP addresses contain dots too but are never service names
+    if parts[0].parse::<std::net::IpAddr>().is_ok() {
+        return (parts[0].to_string(), port, None);
+    }
+
     let dns_parts: Vec<&str> = parts[0].split('.').collect();
 
     if dns_parts.len() >= 3 {
@@ -0,0 +1,287 @@
+// src/services/cert_renewal.rs
+use std::{
+    collections::{BTreeSet, HashMap},
+    net::IpAddr,
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime},
+};
+
+use async_trait::async_trait;
+use pingora::{
+    server::{ListenFds, ShutdownWatch},
+    services::Service,
+};
+use serde::{Serialize, Serializer};
+
+use crate::cert::inspect::format_rfc3339;
+use crate::cert::issuer::{CertificateIssuer, CertificateRequest};
+use crate::cert::store::CertStore;
+use crate::config::audit::{AuditEntry, AuditLog, AuditOrigin};
+use crate::config::model::ConfigStore;
+
+/// Delay before retrying a failed renewal, doubled after every further failure
+const RETRY_BASE_DELAY: Duration = Duration::from_secs(5 * 60);
+const RETRY_MAX_DELAY: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Renewal state per domain, shared with the manager API
+pub type RenewalState = Arc<Mutex<HashMap<String, RenewalStatus>>>;
+
+/// Renewal progress of a single domain
+#[derive(Debug, Clone, Serialize)]
+pub struct RenewalStatus {
+    pub domain: String,
+    /// One of `valid`, `covered`, `missing`, `issuing`, `renewing` or `failed`
+    pub state: String,
+    #[serde(serialize_with = "serialize_time")]
+    pub expiry: Option<SystemTime>,
+    #[serde(serialize_with = "serialize_time")]
+    pub last_attempt: Option<SystemTime>,
+    #[serde(serialize_with = "serialize_time")]
+    pub last_success: Option<SystemTime>,
+    #[serde(serialize_with = "serialize_time")]
+    pub next_attempt: Option<SystemTime>,
+    pub failures: u32,
+    pub error: Option<String>,
+}
+
+impl RenewalStatus {
+    fn new(domain: &str) -> Self {
+        Self {
+            domain: domain.to_string(),
+            state: "missing".to_string(),
+            expiry: None,
+            last_attempt: None,
+            last_success: None,
+            next_attempt: None,
+            failures: 0,
+            error: None,
+        }
+    }
+}
+
+fn serialize_time<S: Serializer>(
+    time: &Option<SystemTime>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    match time {
+        Some(time) => serializer.serialize_str(&format_rfc3339(*time)),
+        None => serializer.serialize_none(),
+    }
+}
+
+// Only public DNS names can get a certificate through HTTP-01, not wildcard or regex hosts
+fn is_certificate_domain(domain: &str) -> bool {
+    domain.contains('.')
+        && !domain.contains(['*', '~', ':', '/'])
+        && domain.parse::<IpAddr>().is_err()
+        && !domain.ends_with(".localhost")
+}
+
+/// Background service that renews expiring certificates and requests
+/// certificates for mapped domains that do not have one yet
+pub struct CertRenewalService {
+    pub config_store: Arc<Mutex<ConfigStore>>,
+    pub issuer: Arc<CertificateIssuer>,
+    pub cert_store: Arc<CertStore>,
+    pub state: RenewalState,
+    pub check_interval: Duration,
+    pub renew_before: Duration,
+    pub on_demand: bool,
+    pub audit: AuditLog,
+}
+
+impl CertRenewalService {
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        config_store: Arc<Mutex<ConfigStore>>,
+        issuer: Arc<CertificateIssuer>,
+        cert_store: Arc<CertStore>,
+        state: RenewalState,
+        check_interval: u64,
+        renew_before_days: u64,
+        on_demand: bool,
+        audit: AuditLog,
+    ) -> Self {
+        Self {
+            config_store,
+            issuer,
+            cert_store,
+            state,
+            check_interval: Duration::from_secs(check_interval),
+            renew_before: Duration::from_secs(renew_before_days * 24 * 60 * 60),
+            on_demand,
+            audit,
+        }
+    }
+
+    fn update_state(&self, domain: &str, update: impl FnOnce(&mut RenewalStatus)) {
+        match self.state.lock() {
+            Ok(mut state) => update(
+                state
+                    .entry(domain.to_string())
+                    .or_insert_with(|| RenewalStatus::new(domain)),
+            ),
+            Err(e) => println!("Error locking renewal state: {:?}", e),
+        }
+    }
+
+    async fn check_certificates(&self) {
+        let mut domains: BTreeSet<String> = match self.config_store.lock() {
+            Ok(store) => store
+                .keys()
+                .filter(|domain| is_certificate_domain(domain))
+                .cloned()
+                .collect(),
+            Err(e) => {
+                println!("Error locking config store: {:?}", e);
+                return;
+            }
+        };
+
+        // Issued certificates are renewed even when no mapping uses their exact name,
+        // e.g. wildcard certificates
+        domains.extend(self.issuer.issued_domains());
+
+        // Forget domains whose mapping was removed
+        if let Ok(mut state) = self.state.lock() {
+            state.retain(|domain, _| domains.contains(domain));
+        }
+
+        for domain in domains {
+            self.check_domain(&domain).await;
+        }
+    }
+
+    async fn check_domain(&self, domain: &str) {
+        let now = SystemTime::now();
+
+        // Still backing off after a failed attempt
+        let next_attempt = self
+            .state
+            .lock()
+            .ok()
+            .and_then(|state| state.get(domain).and_then(|s| s.next_attempt));
+        if next_attempt.is_some_and(|next| next > now) {
+            return;
+        }
+
+        let info = self.issuer.certificate_info(domain);
+        let expiry = info.as_ref().map(|info| info.expiry);
+        let action = match expiry {
+            Some(expiry) if expiry > now + self.renew_before => {
+                self.update_state(domain, |s| {
+                    s.state = "valid".to_string();
+                    s.expiry = Some(expiry);
+                    s.next_attempt = None;
+                    s.error = None;
+                });
+                return;
+            }
+            Some(_) => "renewing",
+            // Served by another certificate through its SANs, e.g. a wildcard
+            None if self.cert_store.covers(domain) => {
+                self.update_state(domain, |s| {
+                    s.state = "covered".to_string();
+                    s.expiry = None;
+                    s.next_attempt = None;
+                    s.error = None;
+                });
+                return;
+            }
+            None if self.on_demand => "issuing",
+            None => {
+                self.update_state(domain, |s| {
+                    s.state = "missing".to_string();
+                    s.expiry = None;
+                });
+                return;
+            }
+        };
+
+        println!("Certificate for {} needs attention: {}", domain, action);
+        self.update_state(domain, |s| {
+            s.state = action.to_string();
+            s.expiry = expiry;
+            s.last_attempt = Some(now);
+        });
+
+        let request = CertificateRequest {
+            domain: domain.to_string(),
+            email: self.issuer.acme.email.clone().unwrap_or_default(),
+            staging: None,
+            force_renew: Some(true),
+            challenge: None,
+            // Renewals keep every DNS name of the current certificate, ACME
+            // cannot validate IP addresses
+            sans: info.as_ref().map(|info| {
+                info.sans
+                    .iter()
+                    .filter(|name| name.parse::<IpAddr>().is_err())
+                    .map(|name| name.to_lowercase())
+                    .collect()
+            }),
+        };
+        let status = self.issuer.process_request(request).await;
+
+        match status.error {
+            None => {
+                println!("Certificate for {} {}", domain, status.status);
+                self.cert_store.reload();
+
+                let renewed = self.issuer.certificate_info(domain);
+                let change = match action {
+                    "issuing" => "certificate.issue",
+                    _ => "certificate.renew",
+                };
+                self.audit.record(
+                    AuditEntry::new(AuditOrigin::CertRenewal, change, domain)
+                        .change(info.as_ref(), renewed.as_ref()),
+                );
+
+                let expiry = self.issuer.certificate_expiry(domain);
+                self.update_state(domain, |s| {
+                    s.state = "valid".to_string();
+                    s.expiry = expiry;
+                    s.last_success = Some(SystemTime::now());
+                    s.next_attempt = None;
+                    s.failures = 0;
+                    s.error = None;
+                });
+            }
+            Some(error) => {
+                println!("Certificate {} failed for {}: {}", action, domain, error);
+                self.update_state(domain, |s| {
+                    s.failures += 1;
+                    let delay = RETRY_BASE_DELAY
+                        .saturating_mul(2u32.saturating_pow(s.failures - 1))
+                        .min(RETRY_MAX_DELAY);
+                    s.state = "failed".to_string();
+                    s.next_attempt = Some(SystemTime::now() + delay);
+                    s.error = Some(error);
+                });
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl Service for CertRenewalService {
+    async fn start_service(&mut self, _fds: Option<ListenFds>, _shutdown: ShutdownWatch) {
+        println!("Starting certificate renewal service");
+
+        let mut interval = tokio::time::interval(self.check_interval);
+
+        loop {
+            interval.tick().await;
+            self.check_certificates().await;
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        "cert_renewal_service"
+    }
+
+    fn threads(&self) -> Option<usize> {
+        Some(1)
+    }
+}
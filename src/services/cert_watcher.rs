@@ -0,0 +1,51 @@
+// src/services/cert_watcher.rs
+use std::{sync::Arc, time::Duration};
+
+use async_trait::async_trait;
+use pingora::{
+    server::{ListenFds, ShutdownWatch},
+    services::Service,
+};
+use tokio::time;
+
+use crate::cert::store::CertStore;
+
+/// Background service that reloads certificates when they change on disk
+pub struct CertWatcherService {
+    pub cert_store: Arc<CertStore>,
+    pub check_interval: Duration,
+}
+
+impl CertWatcherService {
+    pub fn new(cert_store: Arc<CertStore>, check_interval: u64) -> Self {
+        Self {
+            cert_store,
+            check_interval: Duration::from_secs(check_interval),
+        }
+    }
+}
+
+#[async_trait]
+impl Service for CertWatcherService {
+    async fn start_service(&mut self, _fds: Option<ListenFds>, _shutdown: ShutdownWatch) {
+        println!("Starting certificate watcher service");
+
+        let mut interval = time::interval(self.check_interval);
+
+        loop {
+            interval.tick().await;
+
+            if self.cert_store.reload() {
+                println!("TLS certificates reloaded");
+            }
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        "cert_watcher_service"
+    }
+
+    fn threads(&self) -> Option<usize> {
+        Some(1)
+    }
+}
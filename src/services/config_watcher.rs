@@ -0,0 +1,177 @@
+// src/services/config_watcher.rs
+use std::{
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime},
+};
+
+use async_trait::async_trait;
+use pingora::{
+    server::{ListenFds, ShutdownWatch},
+    services::Service,
+};
+use tokio::{
+    signal::unix::{SignalKind, signal},
+    time,
+};
+
+use crate::config::{
+    audit::{AuditEntry, AuditLog, AuditOrigin},
+    file_manager::{adopt_version, config_modified, config_version, read_config},
+    model::ConfigStore,
+};
+use crate::proxy::maintenance::MaintenanceState;
+
+/// Background service that applies changes made to the config file on disk,
+/// checked every interval and on SIGHUP
+pub struct ConfigWatcherService {
+    pub config_store: Arc<Mutex<ConfigStore>>,
+    pub maintenance: MaintenanceState,
+    pub check_interval: Duration,
+    pub audit: AuditLog,
+    /// Modification time of the config file when it was last checked
+    modified: Option<SystemTime>,
+}
+
+impl ConfigWatcherService {
+    pub fn new(
+        config_store: Arc<Mutex<ConfigStore>>,
+        maintenance: MaintenanceState,
+        check_interval: u64,
+        audit: AuditLog,
+    ) -> Self {
+        Self {
+            config_store,
+            maintenance,
+            check_interval: Duration::from_secs(check_interval.max(1)),
+            audit,
+            modified: config_modified(),
+        }
+    }
+
+    // Whether the file was written since the last check
+    fn file_changed(&mut self) -> bool {
+        let modified = config_modified();
+        let changed = modified != self.modified;
+        self.modified = modified;
+        changed
+    }
+
+    /// Switch routing over to the config file in one step. A file that cannot
+    /// be used is logged and the configuration in effect stays
+    fn reload(&self) {
+        let Ok(mut store) = self.config_store.lock() else {
+            println!("Error locking config store for reload");
+            return;
+        };
+
+        // Read under the lock, as API writes save the file while holding it
+        let config = match read_config() {
+            Ok(config) => config,
+            Err(e) => {
+                println!("{}, keeping the configuration in effect", e);
+                return;
+            }
+        };
+        let Ok(mut maintenance) = self.maintenance.lock() else {
+            println!("Error locking maintenance state for reload");
+            return;
+        };
+
+        let servers = config.to_hashmap();
+        let same = |a, b| serde_json::to_value(a).ok() == serde_json::to_value(b).ok();
+        let mut changes = Vec::new();
+        for (host, old) in store.iter() {
+            let (action, new) = match servers.get(host) {
+                None => ("route.delete", None),
+                Some(new) if !same(old, new) => ("route.update", Some(new)),
+                Some(_) => continue,
+            };
+            changes.push(
+                AuditEntry::new(AuditOrigin::FileReload, action, host).change(Some(old), new),
+            );
+        }
+        for (host, new) in servers
+            .iter()
+            .filter(|(host, _)| !store.contains_key(*host))
+        {
+            changes.push(
+                AuditEntry::new(AuditOrigin::FileReload, "route.create", host)
+                    .change(None, Some(new)),
+            );
+        }
+        if serde_json::to_value(&*maintenance).ok()
+            != serde_json::to_value(&config.maintenance).ok()
+        {
+            changes.push(
+                AuditEntry::new(AuditOrigin::FileReload, "maintenance.update", "*")
+                    .change(maintenance.as_ref(), config.maintenance.as_ref()),
+            );
+        }
+
+        // Saves of our own end up here, the file already matches what is in effect
+        if changes.is_empty() {
+            return;
+        }
+
+        adopt_version(&config);
+        *store = servers;
+        *maintenance = config.maintenance;
+        drop(maintenance);
+        drop(store);
+
+        println!(
+            "Reloaded config file with {} change(s), now version {}",
+            changes.len(),
+            config_version()
+        );
+        for entry in changes {
+            self.audit.record(entry);
+        }
+    }
+}
+
+#[async_trait]
+impl Service for ConfigWatcherService {
+    async fn start_service(&mut self, _fds: Option<ListenFds>, _shutdown: ShutdownWatch) {
+        println!("Starting config watcher service");
+
+        let mut interval = time::interval(self.check_interval);
+        let mut hangup = match signal(SignalKind::hangup()) {
+            Ok(hangup) => Some(hangup),
+            Err(e) => {
+                println!("Error listening for SIGHUP, only watching the file: {}", e);
+                None
+            }
+        };
+
+        loop {
+            let signaled = async {
+                match &mut hangup {
+                    Some(hangup) => hangup.recv().await,
+                    None => std::future::pending().await,
+                }
+            };
+            tokio::select! {
+                _ = interval.tick() => {
+                    if !self.file_changed() {
+                        continue;
+                    }
+                }
+                _ = signaled => {
+                    println!("SIGHUP received, reloading config file");
+                    self.file_changed();
+                }
+            }
+
+            self.reload();
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        "config_watcher_service"
+    }
+
+    fn threads(&self) -> Option<usize> {
+        Some(1)
+    }
+}
@@ -14,21 +14,33 @@ use pingora::{
 };
 use tokio::time;
 
-use crate::{config::file_manager::update_config, config::model::ServerMapping};
+use crate::config::{
+    audit::{AuditEntry, AuditLog, AuditOrigin},
+    file_manager::{create_mappings_from_store, update_config},
+    model::{
+        Algorithm, ConfigStore, LoadBalancing, MaintenanceConfig, PoolConfig, ServerMapping,
+        Upstream,
+    },
+};
+use crate::proxy::auth::Organizations;
 
 pub struct SwarmDiscoveryService {
-    pub config_store: Arc<Mutex<HashMap<String, String>>>,
+    pub config_store: Arc<Mutex<ConfigStore>>,
     pub docker_client: Docker,
     pub networks: Vec<String>,
     pub check_interval: Duration,
+    pub audit: AuditLog,
+    pub organizations: Organizations,
 }
 
 impl SwarmDiscoveryService {
     pub fn new(
-        config_store: Arc<Mutex<HashMap<String, String>>>,
+        config_store: Arc<Mutex<ConfigStore>>,
         endpoint: &str,
         networks: Vec<String>,
         check_interval: u64,
+        audit: AuditLog,
+        organizations: Organizations,
     ) -> Result<Self> {
         let docker_client = if endpoint.starts_with("unix://") {
             Docker::connect_with_unix(endpoint, 120, API_DEFAULT_VERSION)?
@@ -41,6 +53,8 @@ impl SwarmDiscoveryService {
             docker_client,
             networks,
             check_interval: Duration::from_secs(check_interval),
+            audit,
+            organizations,
         })
     }
 
@@ -60,6 +74,9 @@ impl SwarmDiscoveryService {
             .await?;
 
         let mut new_mappings = HashMap::new();
+        let mut maintenance = HashMap::new();
+        let mut service_orgs = HashMap::new();
+        let mut domain_orgs: HashMap<String, Option<String>> = HashMap::new();
 
         for service in services {
             let service_spec = match service.spec {
@@ -91,6 +108,22 @@ impl SwarmDiscoveryService {
             // Get service name as provided by Docker Swarm
             let service_name = service_spec.name.unwrap_or_default();
 
+            // A domain belongs to an organization when all of its services do
+            if let Some(org) = &org_id {
+                service_orgs.insert(
+                    format!("{}.{}", service_name, self.networks[0]),
+                    org.clone(),
+                );
+            }
+            domain_orgs
+                .entry(domain.clone())
+                .and_modify(|current| {
+                    if *current != org_id {
+                        *current = None;
+                    }
+                })
+                .or_insert_with(|| org_id.clone());
+
             // Create target using Docker Swarm DNS-based service discovery
             // Format: service_name.network_name:port
             let target = if let Some(org) = org_id {
@@ -101,28 +134,100 @@ impl SwarmDiscoveryService {
                 format!("{}.{}:{}", service_name, self.networks[0], port)
             };
 
+            // Services sharing a domain are balanced as one pool
+            let weight = labels
+                .get("com.koompi.proxy.weight")
+                .and_then(|w| w.parse::<usize>().ok())
+                .filter(|w| *w > 0)
+                .unwrap_or(1);
+            let load_balancing = labels
+                .get("com.koompi.proxy.lb")
+                .and_then(|algorithm| {
+                    serde_json::from_value::<Algorithm>(serde_json::json!(algorithm))
+                        .inspect_err(|e| println!("Invalid load balancing algorithm: {}", e))
+                        .ok()
+                })
+                .map(|algorithm| LoadBalancing {
+                    algorithm,
+                    hash_on: labels.get("com.koompi.proxy.lb.hash_on").cloned(),
+                });
+
+            // Any service of a domain can put it in maintenance
+            if let Some(enabled) = labels
+                .get("com.koompi.proxy.maintenance")
+                .and_then(|value| value.parse::<bool>().ok())
+            {
+                let entry = maintenance.entry(domain.clone()).or_insert(false);
+                *entry |= enabled;
+            }
+
             println!("Discovered service mapping: {} -> {}", domain, target);
-            new_mappings.insert(domain, target);
+            let pool: &mut PoolConfig = new_mappings.entry(domain).or_default();
+            pool.upstreams.push(Upstream {
+                addr: target,
+                weight,
+            });
+            if load_balancing.is_some() {
+                pool.load_balancing = load_balancing;
+            }
         }
 
+        self.organizations.update(
+            service_orgs,
+            domain_orgs
+                .into_iter()
+                .filter_map(|(domain, org)| Some((domain, org?)))
+                .collect(),
+        );
+
         // Update config store with new mappings
-        if !new_mappings.is_empty() {
-            if let Ok(mut store) = self.config_store.lock() {
-                // Merge new mappings with existing ones
-                for (domain, target) in new_mappings {
-                    store.insert(domain, target);
+        if !new_mappings.is_empty()
+            && let Ok(mut store) = self.config_store.lock()
+        {
+            // Merge new mappings with existing ones, keeping their path rules
+            for (domain, mut pool) in new_mappings {
+                // A single service is a plain backend, several form a pool
+                let to = pool.upstreams[0].addr.clone();
+                if pool.upstreams.len() == 1 && pool.load_balancing.is_none() {
+                    pool.upstreams.clear();
+                }
+
+                let old = store.get(&domain).cloned();
+                let mapping = store
+                    .entry(domain.clone())
+                    .or_insert_with(|| ServerMapping::new(&domain, &to));
+                mapping.to = to;
+                // Health checks and circuit breakers set through the API stay
+                mapping.pool.upstreams = pool.upstreams;
+                mapping.pool.load_balancing = pool.load_balancing;
+
+                // Toggled by the label, keeping the allow-list and bypass set through the API
+                if let Some(enabled) = maintenance.get(&domain).copied() {
+                    match &mut mapping.maintenance {
+                        Some(current) => current.enabled = enabled,
+                        None if enabled => mapping.maintenance = Some(MaintenanceConfig::default()),
+                        None => {}
+                    }
                 }
 
-                // Update config file
-                let server_mappings = store
-                    .iter()
-                    .map(|(from, to)| ServerMapping {
-                        from: from.clone(),
-                        to: to.clone(),
-                    })
-                    .collect();
+                // Only actual changes are audited, not every discovery run
+                let changed = old.as_ref().map(serde_json::to_value).and_then(Result::ok)
+                    != serde_json::to_value(&*mapping).ok();
+                if changed {
+                    let action = match old {
+                        Some(_) => "route.update",
+                        None => "route.create",
+                    };
+                    self.audit.record(
+                        AuditEntry::new(AuditOrigin::SwarmDiscovery, action, &domain)
+                            .change(old.as_ref(), Some(&*mapping)),
+                    );
+                }
+            }
 
-                update_config(server_mappings);
+            // Update config file
+            if let Err(e) = update_config(create_mappings_from_store(&store)) {
+                println!("Error updating config file: {}", e);
             }
         }
 
@@ -0,0 +1,92 @@
+// src/services/health_check.rs
+use std::{
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use async_trait::async_trait;
+use futures::future::join_all;
+use pingora::{
+    server::{ListenFds, ShutdownWatch},
+    services::Service,
+};
+
+use crate::config::model::ConfigStore;
+use crate::proxy::balancer::{UpstreamPools, configured_pools};
+
+/// How often pools are looked at, each pool is checked at its own interval
+const TICK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Background service that runs the active health checks of every configured
+/// pool and looks their upstreams up again
+pub struct HealthCheckService {
+    pub config_store: Arc<Mutex<ConfigStore>>,
+    pub pools: UpstreamPools,
+}
+
+impl HealthCheckService {
+    pub fn new(config_store: Arc<Mutex<ConfigStore>>, pools: UpstreamPools) -> Self {
+        Self {
+            config_store,
+            pools,
+        }
+    }
+
+    async fn check_pools(&self) {
+        let configs = match self.config_store.lock() {
+            Ok(store) => configured_pools(&store),
+            Err(e) => {
+                println!("Error locking config store: {:?}", e);
+                return;
+            }
+        };
+
+        let mut pools = Vec::new();
+        for (domain, _, config) in &configs {
+            // Built ahead of the first request so it never reaches an unchecked upstream
+            let pool = match config.health_check {
+                Some(_) => self
+                    .pools
+                    .get_or_build(config)
+                    .await
+                    .inspect_err(|e| println!("Error building upstream pool for {}: {}", domain, e))
+                    .ok(),
+                None => self.pools.peek(config),
+            };
+            pools.extend(pool);
+        }
+
+        // Pools are resolved and checked concurrently so a slow one does not
+        // delay the others
+        join_all(pools.iter().map(|pool| async {
+            pool.refresh().await;
+            pool.run_health_check().await;
+        }))
+        .await;
+
+        let configs: Vec<_> = configs.into_iter().map(|(_, _, config)| config).collect();
+        self.pools.prune(&configs);
+    }
+}
+
+#[async_trait]
+impl Service for HealthCheckService {
+    async fn start_service(&mut self, _fds: Option<ListenFds>, _shutdown: ShutdownWatch) {
+        println!("Starting upstream health check service");
+
+        let mut interval = tokio::time::interval(TICK_INTERVAL);
+
+        loop {
+            interval.tick().await;
+            self.check_pools().await;
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        "health_check_service"
+    }
+
+    fn threads(&self) -> Option<usize> {
+        Some(1)
+    }
+}
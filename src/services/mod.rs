@@ -1,2 +1,6 @@
 // pub mod service;
+pub mod cert_renewal;
+pub mod cert_watcher;
+pub mod config_watcher;
 pub mod docker_swarm;
+pub mod health_check;